@@ -0,0 +1,493 @@
+/*
+ * Filename: admin.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Routes of the admin interface, served apart from the Docker plugin API
+//! for operators and monitoring.
+
+use crate::api;
+use crate::error::Error;
+use crate::fault::Faults;
+use crate::health;
+use crate::inject::InjectConfig;
+use crate::logging::LogFilter;
+use crate::manager::NetworkManager;
+use crate::monitor::{FrameBatch, FrameFilter};
+use crate::recorder::RecordConfig;
+use crate::replay::ReplayConfig;
+use bollard::Docker;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use warp::{http, Filter};
+
+/// Default path of the admin socket. It must not be in
+/// `/run/docker/plugins`, where Docker would take it for a plugin.
+pub const ADMIN_SOCKET: &str = "/run/rustyvxcan/admin.sock";
+
+/// Environment variable overriding [`ADMIN_SOCKET`].
+pub const ADMIN_SOCKET_ENV: &str = "RUSTYVXCAN_ADMIN_SOCKET";
+
+/// Environment variable holding an optional `address:port` on which
+/// `GET /metrics` is served over TCP as well.
+pub const METRICS_ADDR_ENV: &str = "RUSTYVXCAN_METRICS_ADDR";
+
+/// `GET /metrics`: all metrics in the Prometheus text format.
+pub fn metrics_route(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(with_mgr(mgr))
+        .then(|mgr: NetworkManager| async move {
+            warp::reply::with_header(
+                mgr.metrics_export().await,
+                "Content-Type",
+                "text/plain; version=0.0.4",
+            )
+        })
+}
+
+/// `GET /health`: results of the liveness checks, with status 503 if any
+/// failed.
+pub fn health_route(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("health"))
+        .and(warp::path::end())
+        .and(with_mgr(mgr))
+        .then(|mgr: NetworkManager| async move {
+            let health = health::check(&mgr).await;
+            let status = match health.healthy {
+                true => http::StatusCode::OK,
+                false => http::StatusCode::SERVICE_UNAVAILABLE,
+            };
+            warp::reply::with_status(warp::reply::json(&health), status)
+        })
+}
+
+/// `GET /ready`: results of the readiness checks, with status 503 if any
+/// failed. `storage` are the directories the plugin has to be able to
+/// write to.
+pub fn ready_route(
+    mgr: NetworkManager,
+    docker: Option<Docker>,
+    storage: Vec<PathBuf>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("ready"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || (mgr.clone(), docker.clone(), storage.clone())))
+        .then(
+            |(mgr, docker, storage): (NetworkManager, Option<Docker>, Vec<PathBuf>)| async move {
+                let ready = health::ready(&mgr, docker.as_ref(), &storage).await;
+                let status = match ready.ready {
+                    true => http::StatusCode::OK,
+                    false => http::StatusCode::SERVICE_UNAVAILABLE,
+                };
+                warp::reply::with_status(warp::reply::json(&ready), status)
+            },
+        )
+}
+
+/// Network inspection and statistics:
+///
+/// - `GET /networks`: IDs of all networks,
+/// - `GET /networks/{id}`: endpoints and forwarding rules of a network,
+/// - `GET /networks/{id}/stats`: can-gw frame counters of every route of a
+///   network, summed per endpoint and for the network,
+/// - `GET /networks/{id}/endpoints/{id}/stats`: the same for one endpoint.
+pub fn network_routes(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path("networks"))
+        .and(warp::path::end())
+        .and(with_mgr(mgr.clone()))
+        .map(|mgr: NetworkManager| warp::reply::json(&mgr.networks()));
+
+    let inspect = warp::get()
+        .and(warp::path!("networks" / String))
+        .and(with_mgr(mgr.clone()))
+        .then(|nid: String, mgr: NetworkManager| async move {
+            json_reply(mgr.network_inspect(nid).await)
+        });
+
+    let stats = warp::get()
+        .and(warp::path!("networks" / String / "stats"))
+        .and(with_mgr(mgr.clone()))
+        .then(|nid: String, mgr: NetworkManager| async move {
+            json_reply(mgr.network_stats(nid).await)
+        });
+
+    let endpoint_stats = warp::get()
+        .and(warp::path!(
+            "networks" / String / "endpoints" / String / "stats"
+        ))
+        .and(with_mgr(mgr))
+        .then(
+            |nid: String, epid: String, mgr: NetworkManager| async move {
+                json_reply(mgr.endpoint_stats(nid, epid).await)
+            },
+        );
+
+    list.or(inspect).or(stats).or(endpoint_stats)
+}
+
+/// Traffic recording:
+///
+/// - `POST /networks/{id}/record`: start recording to the directory and in
+///   the format given by a [`RecordConfig`] body,
+/// - `GET /networks/{id}/record`: state of the recording, or `null`,
+/// - `DELETE /networks/{id}/record`: stop recording and return its final
+///   state, or `null` if there was none.
+pub fn record_routes(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    // Match the path first, so that other paths are not found rather than
+    // not allowed
+    let start = warp::path!("networks" / String / "record")
+        .and(warp::post())
+        .and(api::process_body())
+        .and(with_mgr(mgr.clone()))
+        .then(
+            |nid: String, body: bytes::Bytes, mgr: NetworkManager| async move {
+                match parse_body::<RecordConfig>(&body) {
+                    Ok(config) => json_reply(mgr.record_start(nid, config).await),
+                    Err(e) => json_reply::<()>(Err(e)),
+                }
+            },
+        );
+
+    let status = warp::path!("networks" / String / "record")
+        .and(warp::get())
+        .and(with_mgr(mgr.clone()))
+        .then(|nid: String, mgr: NetworkManager| async move {
+            json_reply(mgr.network_inspect(nid).await.map(|info| info.recording))
+        });
+
+    let stop =
+        warp::path!("networks" / String / "record")
+            .and(warp::delete())
+            .and(with_mgr(mgr))
+            .then(|nid: String, mgr: NetworkManager| async move {
+                json_reply(mgr.record_stop(nid).await)
+            });
+
+    start.or(status).or(stop)
+}
+
+/// Replay of recorded traffic:
+///
+/// - `POST /networks/{id}/replay`: start replaying the candump log given by
+///   a [`ReplayConfig`] body,
+/// - `GET /networks/{id}/replay`: progress of the replay, or `null`,
+/// - `DELETE /networks/{id}/replay`: stop replaying and return the final
+///   progress, or `null` if there was no replay.
+pub fn replay_routes(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let start = warp::path!("networks" / String / "replay")
+        .and(warp::post())
+        .and(api::process_body())
+        .and(with_mgr(mgr.clone()))
+        .then(
+            |nid: String, body: bytes::Bytes, mgr: NetworkManager| async move {
+                match parse_body::<ReplayConfig>(&body) {
+                    Ok(config) => json_reply(mgr.replay_start(nid, config).await),
+                    Err(e) => json_reply::<()>(Err(e)),
+                }
+            },
+        );
+
+    let status = warp::path!("networks" / String / "replay")
+        .and(warp::get())
+        .and(with_mgr(mgr.clone()))
+        .then(|nid: String, mgr: NetworkManager| async move {
+            json_reply(mgr.network_inspect(nid).await.map(|info| info.replay))
+        });
+
+    let stop =
+        warp::path!("networks" / String / "replay")
+            .and(warp::delete())
+            .and(with_mgr(mgr))
+            .then(|nid: String, mgr: NetworkManager| async move {
+                json_reply(mgr.replay_stop(nid).await)
+            });
+
+    start.or(status).or(stop)
+}
+
+/// Error frame injection:
+///
+/// - `POST /networks/{id}/errors`: start sending the error frames of an
+///   [`InjectConfig`] body to the network or one endpoint,
+/// - `GET /networks/{id}/errors`: progress of the injection, or `null`,
+/// - `DELETE /networks/{id}/errors`: stop injecting and return the final
+///   progress, or `null` if there was no injection.
+pub fn inject_routes(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let start = warp::path!("networks" / String / "errors")
+        .and(warp::post())
+        .and(api::process_body())
+        .and(with_mgr(mgr.clone()))
+        .then(
+            |nid: String, body: bytes::Bytes, mgr: NetworkManager| async move {
+                match parse_body::<InjectConfig>(&body) {
+                    Ok(config) => json_reply(mgr.inject_start(nid, config).await),
+                    Err(e) => json_reply::<()>(Err(e)),
+                }
+            },
+        );
+
+    let status = warp::path!("networks" / String / "errors")
+        .and(warp::get())
+        .and(with_mgr(mgr.clone()))
+        .then(|nid: String, mgr: NetworkManager| async move {
+            json_reply(mgr.network_inspect(nid).await.map(|info| info.injection))
+        });
+
+    let stop =
+        warp::path!("networks" / String / "errors")
+            .and(warp::delete())
+            .and(with_mgr(mgr))
+            .then(|nid: String, mgr: NetworkManager| async move {
+                json_reply(mgr.inject_stop(nid).await)
+            });
+
+    start.or(status).or(stop)
+}
+
+/// Frames exchanged with a network from the host:
+///
+/// - `POST /networks/{id}/frames`: send a frame, or an array of frames, in
+///   `cansend` notation onto the network's host device,
+/// - `GET /networks/{id}/frames?filter=<id>:<mask>,...`: stream the frames
+///   on the host device as newline-delimited JSON until the client
+///   disconnects.
+pub fn frame_routes(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let send = warp::path!("networks" / String / "frames")
+        .and(warp::post())
+        .and(api::process_body())
+        .and(with_mgr(mgr.clone()))
+        .then(
+            |nid: String, body: bytes::Bytes, mgr: NetworkManager| async move {
+                let frames = parse_body::<FrameBatch>(&body).and_then(|batch| batch.parse());
+                let res = match frames {
+                    Ok(frames) => mgr.frames_send(nid, frames).await,
+                    Err(e) => Err(e),
+                };
+                json_reply(res.map(|sent| serde_json::json!({ "sent": sent })))
+            },
+        );
+
+    let subscribe = warp::path!("networks" / String / "frames")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_mgr(mgr))
+        .then(
+            |nid: String, query: HashMap<String, String>, mgr: NetworkManager| async move {
+                let filter = query.get("filter").map_or("", String::as_str);
+                let rx = match FrameFilter::parse(filter) {
+                    Ok(filter) => mgr.frames_subscribe(nid, filter).await,
+                    Err(e) => Err(e),
+                };
+                let rx = match rx {
+                    Ok(rx) => rx,
+                    Err(e) => return json_reply::<()>(Err(e)),
+                };
+                let lines = ReceiverStream::new(rx).map(|record| {
+                    let mut line = serde_json::to_string(&record).unwrap_or_default();
+                    line.push('\n');
+                    Ok::<_, std::convert::Infallible>(line)
+                });
+                let mut rsp = warp::reply::Response::new(warp::hyper::Body::wrap_stream(lines));
+                rsp.headers_mut().insert(
+                    http::header::CONTENT_TYPE,
+                    http::HeaderValue::from_static("application/x-ndjson"),
+                );
+                rsp
+            },
+        );
+
+    send.or(subscribe)
+}
+
+/// Fault injection, for networks forwarding frames in userspace:
+///
+/// - `PUT /networks/{id}/endpoints/{id}/faults`: replace the faults
+///   injected into the endpoint's frames with a [`Faults`] body; fields left
+///   out are 0,
+/// - `GET /networks/{id}/endpoints/{id}/faults`: the faults in effect,
+/// - `DELETE /networks/{id}/endpoints/{id}/faults`: stop injecting faults.
+pub fn fault_routes(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let set = warp::path!("networks" / String / "endpoints" / String / "faults")
+        .and(warp::put())
+        .and(api::process_body())
+        .and(with_mgr(mgr.clone()))
+        .then(
+            |nid: String, epid: String, body: bytes::Bytes, mgr: NetworkManager| async move {
+                match parse_body::<Faults>(&body) {
+                    Ok(faults) => json_reply(mgr.endpoint_faults(nid, epid, faults).await),
+                    Err(e) => json_reply::<()>(Err(e)),
+                }
+            },
+        );
+
+    let get = warp::path!("networks" / String / "endpoints" / String / "faults")
+        .and(warp::get())
+        .and(with_mgr(mgr.clone()))
+        .then(
+            |nid: String, epid: String, mgr: NetworkManager| async move {
+                let res = mgr.network_inspect(nid).await.and_then(|info| {
+                    info.endpoints
+                        .into_iter()
+                        .find(|ep| ep.id == epid)
+                        .map(|ep| ep.faults.unwrap_or_default())
+                        .ok_or(Error::EndpointNotFound(epid))
+                });
+                json_reply(res)
+            },
+        );
+
+    let clear = warp::path!("networks" / String / "endpoints" / String / "faults")
+        .and(warp::delete())
+        .and(with_mgr(mgr))
+        .then(
+            |nid: String, epid: String, mgr: NetworkManager| async move {
+                json_reply(mgr.endpoint_faults(nid, epid, Faults::default()).await)
+            },
+        );
+
+    set.or(get).or(clear)
+}
+
+/// Log verbosity, if the logger was installed by
+/// [`logging::init`](crate::logging::init):
+///
+/// - `GET /log`: the log filter in effect, as `{"filter": "..."}`,
+/// - `PUT /log`: replace the filter with the one of a `{"filter": "..."}`
+///   body, in the syntax of [`LOG_ENV`](crate::logging::LOG_ENV).
+pub fn log_routes(
+    log: Option<LogFilter>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let with_log = warp::any().map(move || {
+        log.clone()
+            .ok_or_else(|| Error::Unsupported(String::from("the log filter cannot be changed")))
+    });
+
+    let get = warp::path!("log")
+        .and(warp::get())
+        .and(with_log.clone())
+        .map(|log: Result<LogFilter, Error>| {
+            json_reply(log.and_then(|l| l.get()).map(|filter| LogLevel { filter }))
+        });
+
+    let set = warp::path!("log")
+        .and(warp::put())
+        .and(api::process_body())
+        .and(with_log)
+        .map(|body: bytes::Bytes, log: Result<LogFilter, Error>| {
+            let res = parse_body::<LogLevel>(&body).and_then(|level| {
+                let log = log?;
+                log.set(&level.filter)?;
+                log.get().map(|filter| LogLevel { filter })
+            });
+            json_reply(res)
+        });
+
+    get.or(set)
+}
+
+/// Body of the log routes.
+#[derive(Serialize, Deserialize)]
+struct LogLevel {
+    filter: String,
+}
+
+/// All routes of the admin interface, served by `mgr`. `docker` is the
+/// daemon the plugin serves, if it could connect to it, `storage` the
+/// directories it writes to, like the one of the admin socket, and `log` the
+/// filter of its logger.
+pub fn routes(
+    mgr: NetworkManager,
+    docker: Option<Docker>,
+    storage: Vec<PathBuf>,
+    log: Option<LogFilter>,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    metrics_route(mgr.clone())
+        .or(log_routes(log))
+        .or(health_route(mgr.clone()))
+        .or(ready_route(mgr.clone(), docker, storage))
+        .or(network_routes(mgr.clone()))
+        .or(record_routes(mgr.clone()))
+        .or(replay_routes(mgr.clone()))
+        .or(inject_routes(mgr.clone()))
+        .or(frame_routes(mgr.clone()))
+        .or(fault_routes(mgr))
+}
+
+fn with_mgr(
+    mgr: NetworkManager,
+) -> impl Filter<Extract = (NetworkManager,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || mgr.clone())
+}
+
+/// Read a JSON request body.
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body)
+        .map_err(|e| Error::InvalidOption(format!("invalid request body: {e}")))
+}
+
+/// `value` as JSON, or the error as `{"Err": "..."}` with a matching status.
+fn json_reply<T: Serialize>(res: Result<T, Error>) -> warp::reply::Response {
+    use warp::Reply;
+
+    match res {
+        Ok(value) => warp::reply::json(&value).into_response(),
+        Err(e) => {
+            let status = match e {
+                Error::NetworkNotFound(_) | Error::EndpointNotFound(_) => {
+                    http::StatusCode::NOT_FOUND
+                }
+                Error::InvalidOption(_) | Error::DeviceInUse(_) => http::StatusCode::BAD_REQUEST,
+                Error::Busy(_) => http::StatusCode::CONFLICT,
+                _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            let body = warp::reply::json(&serde_json::json!({ "Err": e.to_string() }));
+            warp::reply::with_status(body, status).into_response()
+        }
+    }
+}
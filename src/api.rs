@@ -0,0 +1,753 @@
+/*
+ * Filename: api.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::error;
+use crate::manager::NetworkManager;
+use crate::network;
+use crate::stats;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::vec::Vec;
+use tracing::{debug, info, warn, Instrument, Span};
+use warp::{http, Filter};
+
+/// Environment variable selecting the [`Scope`] reported to Docker.
+pub const SCOPE_ENV: &str = "RUSTYVXCAN_SCOPE";
+
+/// How far the plugin's networks reach, as reported to Docker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// Networks only connect containers on this host.
+    #[default]
+    Local,
+    /// Networks may span Docker hosts, e.g. those of a swarm, by tunneling
+    /// to the nodes Docker reports.
+    Global,
+}
+
+impl Scope {
+    pub fn parse(s: &str) -> Result<Self, error::Error> {
+        match s {
+            "local" => Ok(Scope::Local),
+            "global" => Ok(Scope::Global),
+            _ => Err(error::Error::InvalidOption(format!(
+                "unknown scope '{s}', expected 'local' or 'global'"
+            ))),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Local => "local",
+            Scope::Global => "global",
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct ErrorResponse {
+    Err: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct HandshakeResponse {
+    Implements: Vec<String>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct SetCapabilityResponse {
+    Scope: String,
+    ConnectivityScope: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct JoinResponse {
+    InterfaceName: network::JoinResponse,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize, Clone)]
+struct EndpointInfoResponse {
+    Value: stats::EndpointStats,
+}
+
+/// Body of `DiscoverNew` and `DiscoverDelete`.
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+struct DiscoveryRequest {
+    DiscoveryType: u32,
+    #[serde(default)]
+    DiscoveryData: serde_json::Value,
+}
+
+/// `DiscoveryData` of a [`NODE_DISCOVERY`] notification.
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+struct NodeDiscoveryData {
+    Address: String,
+    /// Whether the node is this host.
+    #[serde(default, rename = "Self")]
+    IsSelf: bool,
+}
+
+/// `DiscoveryType` of notifications about other Docker nodes.
+const NODE_DISCOVERY: u32 = 1;
+
+/// IDs most requests carry, attached to the span of the request.
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize, Default)]
+struct RequestIds {
+    NetworkID: Option<String>,
+    EndpointID: Option<String>,
+}
+
+async fn api_plugin_activate(payload: bytes::Bytes) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+    let rsp = HandshakeResponse {
+        Implements: vec![String::from("NetworkDriver")],
+    };
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let jrsp = match serde_json::to_string(&rsp) {
+        Ok(jrsp) => jrsp,
+        Err(_) => {
+            status = http::StatusCode::BAD_REQUEST;
+            String::from(r#"{"Err":"Serializing response to Plugin.Activate"}"#)
+        }
+    };
+    log_reply(status, &jrsp);
+    Ok(warp::reply::with_status(jrsp, status))
+}
+
+async fn api_get_capabilities(
+    payload: bytes::Bytes,
+    scope: Scope,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+    // Every host keeps its own state, so Docker needs no shared store
+    let rsp = SetCapabilityResponse {
+        Scope: String::from("local"),
+        ConnectivityScope: String::from(scope.as_str()),
+    };
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let jrsp = match serde_json::to_string(&rsp) {
+        Ok(jrsp) => jrsp,
+        Err(_) => {
+            status = http::StatusCode::BAD_REQUEST;
+            String::from(r#"{"Err":"Serializing response to NetworkDriver.GetCapabilities"}"#)
+        }
+    };
+
+    log_reply(status, &jrsp);
+    Ok(warp::reply::with_status(jrsp, status))
+}
+
+async fn api_network_create(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => {
+            let mut error = false;
+            let uid = match v["NetworkID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["NetworkID"], "invalid network ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            if !error {
+                match mgr
+                    .network_create(uid, &v["Options"]["com.docker.network.generic"])
+                    .await
+                {
+                    Ok(()) => String::from("{}"),
+                    Err(e) => {
+                        status = http::StatusCode::BAD_REQUEST;
+                        error_reply(&mgr, &e)
+                    }
+                }
+            } else {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid network ID"}"#)
+            }
+        }
+        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+async fn api_network_delete(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => {
+            let mut error = false;
+            let uid = match v["NetworkID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["NetworkID"], "invalid network ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            if !error {
+                match mgr.network_delete(uid).await {
+                    Ok(()) => String::from("{}"),
+                    Err(e) => error_reply(&mgr, &e),
+                }
+            } else {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid network ID"}"#)
+            }
+        }
+        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+async fn api_endpoint_create(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => {
+            let mut error = false;
+            let nuid = match v["NetworkID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["NetworkID"], "invalid network ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            let epuid = match v["EndpointID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["EndpointID"], "invalid endpoint ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            if !error {
+                match mgr.endpoint_create(nuid, epuid).await {
+                    Ok(()) => String::from("{}"),
+                    Err(e) => error_reply(&mgr, &e),
+                }
+            } else {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid network ID or endpoint ID"}"#)
+            }
+        }
+        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+async fn api_endpoint_delete(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => {
+            let mut error = false;
+            let nuid = match v["NetworkID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["NetworkID"], "invalid network ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            let epuid = match v["EndpointID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["EndpointID"], "invalid endpoint ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            if !error {
+                match mgr.endpoint_delete(nuid, epuid).await {
+                    Ok(()) => String::from("{}"),
+                    Err(e) => error_reply(&mgr, &e),
+                }
+            } else {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid network ID or endpoint ID"}"#)
+            }
+        }
+        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+async fn api_endpoint_info(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => {
+            let mut error = false;
+            let nuid = match v["NetworkID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["NetworkID"], "invalid network ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            let epuid = match v["EndpointID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["EndpointID"], "invalid endpoint ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            if !error {
+                match mgr.endpoint_stats(nuid, epuid).await {
+                    Ok(stats) => {
+                        let rsp = EndpointInfoResponse { Value: stats };
+                        match serde_json::to_string(&rsp) {
+                            Ok(jrsp) => jrsp,
+                            Err(_) => String::from(
+                                r#"{"Err":"Serializing response to NetworkDriver.EndpointOperInfo"}"#,
+                            ),
+                        }
+                    }
+                    Err(e) => error_reply(&mgr, &e),
+                }
+            } else {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid network ID or endpoint ID"}"#)
+            }
+        }
+        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+async fn api_network_join(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => {
+            let mut error = false;
+            let nuid = match v["NetworkID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["NetworkID"], "invalid network ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            let epuid = match v["EndpointID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["EndpointID"], "invalid endpoint ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            let sbox = match v["SandboxKey"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["SandboxKey"], "invalid sandbox key");
+                    error = true;
+                    String::new()
+                }
+            };
+            let opt = match v["Options"].as_str() {
+                Some(o) => o.to_string(),
+                None => v["Options"].to_string(),
+            };
+            if !error {
+                match mgr.endpoint_attach(nuid, epuid, sbox, opt).await {
+                    Ok(joinrsp) => {
+                        let rsp = JoinResponse {
+                            InterfaceName: joinrsp,
+                        };
+                        match serde_json::to_string(&rsp) {
+                            Ok(jrsp) => jrsp,
+                            Err(_) => String::from(
+                                r#"{"Err":"Serializing response to NetworkDriver.Join"}"#,
+                            ),
+                        }
+                    }
+                    Err(e) => error_reply(&mgr, &e),
+                }
+            } else {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid network ID, endpoint ID, or sandbox key"}"#)
+            }
+        }
+        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+async fn api_network_leave(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
+        Ok(v) => {
+            let mut error = false;
+            let nuid = match v["NetworkID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["NetworkID"], "invalid network ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            let epuid = match v["EndpointID"].as_str() {
+                Some(u) => u.to_string(),
+                None => {
+                    warn!(value = %v["EndpointID"], "invalid endpoint ID");
+                    error = true;
+                    String::new()
+                }
+            };
+            if !error {
+                match mgr.endpoint_detach(nuid, epuid).await {
+                    Ok(()) => String::from("{}"),
+                    Err(e) => error_reply(&mgr, &e),
+                }
+            } else {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid network ID or endpoint ID"}"#)
+            }
+        }
+        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+async fn api_discover_new(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    api_discover(payload, mgr, true).await
+}
+
+async fn api_discover_delete(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    api_discover(payload, mgr, false).await
+}
+
+/// Learn about (`added`) or forget another Docker node. Other discovery
+/// notifications, e.g. of encryption keys, do not concern the plugin.
+async fn api_discover(
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+    added: bool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    log_body(&payload);
+
+    let mut status: http::StatusCode = http::StatusCode::OK;
+    let reply = match serde_json::from_slice::<DiscoveryRequest>(&payload) {
+        Ok(req) if req.DiscoveryType != NODE_DISCOVERY => {
+            debug!(kind = req.DiscoveryType, "ignoring discovery");
+            String::from("{}")
+        }
+        Ok(req) => match serde_json::from_value::<NodeDiscoveryData>(req.DiscoveryData) {
+            Ok(node) if node.IsSelf => String::from("{}"),
+            Ok(node) => match node.Address.parse::<IpAddr>() {
+                Ok(addr) => {
+                    if added {
+                        mgr.node_add(addr).await;
+                    } else {
+                        mgr.node_remove(addr).await;
+                    }
+                    String::from("{}")
+                }
+                Err(_) => {
+                    warn!(address = %node.Address, "invalid node address");
+                    status = http::StatusCode::BAD_REQUEST;
+                    String::from(r#"{"Err":"Invalid node address"}"#)
+                }
+            },
+            Err(_) => {
+                status = http::StatusCode::BAD_REQUEST;
+                String::from(r#"{"Err":"Invalid node discovery data"}"#)
+            }
+        },
+        Err(_) => {
+            status = http::StatusCode::BAD_REQUEST;
+            String::from(r#"{"Err":"Unable to parse JSON payload"}"#)
+        }
+    };
+
+    log_reply(status, &reply);
+    Ok(warp::reply::with_status(reply, status))
+}
+
+fn error_reply(mgr: &NetworkManager, e: &error::Error) -> String {
+    warn!(error = %e, "request failed");
+    mgr.metrics().error_inc(e);
+    let rsp = ErrorResponse { Err: e.to_string() };
+    match serde_json::to_string(&rsp) {
+        Ok(jrsp) => jrsp,
+        Err(_) => String::from(r#"{"Err":"Serializing error response"}"#),
+    }
+}
+
+fn log_body(payload: &bytes::Bytes) {
+    debug!(body = %String::from_utf8_lossy(payload), "request");
+}
+
+fn log_reply(status: http::StatusCode, reply: &str) {
+    info!(status = status.as_u16(), "request handled");
+    debug!(body = reply, "response");
+}
+
+/// Span covering the handling of one Docker request, including the work
+/// done for it by the network's task.
+fn request_span(method: &'static str, payload: &bytes::Bytes) -> Span {
+    let ids: RequestIds = serde_json::from_slice(payload).unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        method,
+        network = tracing::field::Empty,
+        endpoint = tracing::field::Empty
+    );
+    if let Some(nid) = ids.NetworkID {
+        span.record("network", nid.as_str());
+    }
+    if let Some(epid) = ids.EndpointID {
+        span.record("endpoint", epid.as_str());
+    }
+    span
+}
+
+/// Run `handler` for a request to `method` within the request's span,
+/// recording how long it took.
+async fn traced<F, Fut>(
+    method: &'static str,
+    payload: bytes::Bytes,
+    mgr: NetworkManager,
+    handler: F,
+) -> Fut::Output
+where
+    F: FnOnce(bytes::Bytes, NetworkManager) -> Fut,
+    Fut: std::future::Future,
+{
+    let span = request_span(method, &payload);
+    let start = std::time::Instant::now();
+    let res = handler(payload, mgr.clone()).instrument(span).await;
+    mgr.metrics().request_observe(method, start.elapsed());
+    res
+}
+
+/// Request body of at most 16 KiB.
+pub(crate) fn process_body(
+) -> impl Filter<Extract = (bytes::Bytes,), Error = warp::Rejection> + Copy {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::bytes())
+}
+
+/// All routes of the Docker network plugin protocol, served by `mgr` and
+/// reporting networks of `scope`.
+pub fn routes(
+    mgr: NetworkManager,
+    scope: Scope,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let filter = warp::any().map(move || mgr.clone());
+
+    let payload = warp::post()
+        .and(warp::path("Plugin.Activate"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| {
+            traced("Plugin.Activate", body, mgr, |body, _| {
+                api_plugin_activate(body)
+            })
+        });
+
+    let get_cap = warp::post()
+        .and(warp::path("NetworkDriver.GetCapabilities"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(move |body, mgr| {
+            traced(
+                "NetworkDriver.GetCapabilities",
+                body,
+                mgr,
+                move |body, _| api_get_capabilities(body, scope),
+            )
+        });
+
+    let nw_create = warp::post()
+        .and(warp::path("NetworkDriver.CreateNetwork"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| traced("NetworkDriver.CreateNetwork", body, mgr, api_network_create));
+
+    let nw_del = warp::post()
+        .and(warp::path("NetworkDriver.DeleteNetwork"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| traced("NetworkDriver.DeleteNetwork", body, mgr, api_network_delete));
+
+    let endp_create = warp::post()
+        .and(warp::path("NetworkDriver.CreateEndpoint"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| {
+            traced(
+                "NetworkDriver.CreateEndpoint",
+                body,
+                mgr,
+                api_endpoint_create,
+            )
+        });
+
+    let endp_del = warp::post()
+        .and(warp::path("NetworkDriver.DeleteEndpoint"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| {
+            traced(
+                "NetworkDriver.DeleteEndpoint",
+                body,
+                mgr,
+                api_endpoint_delete,
+            )
+        });
+
+    let endp_info = warp::post()
+        .and(warp::path("NetworkDriver.EndpointOperInfo"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| {
+            traced(
+                "NetworkDriver.EndpointOperInfo",
+                body,
+                mgr,
+                api_endpoint_info,
+            )
+        });
+
+    let nw_join = warp::post()
+        .and(warp::path("NetworkDriver.Join"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| traced("NetworkDriver.Join", body, mgr, api_network_join));
+
+    let nw_leave = warp::post()
+        .and(warp::path("NetworkDriver.Leave"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| traced("NetworkDriver.Leave", body, mgr, api_network_leave));
+
+    let dsc_new = warp::post()
+        .and(warp::path("NetworkDriver.DiscoverNew"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| traced("NetworkDriver.DiscoverNew", body, mgr, api_discover_new));
+
+    let dsc_del = warp::post()
+        .and(warp::path("NetworkDriver.DiscoverDelete"))
+        .and(warp::path::end())
+        .and(process_body())
+        .and(filter.clone())
+        .and_then(|body, mgr| {
+            traced(
+                "NetworkDriver.DiscoverDelete",
+                body,
+                mgr,
+                api_discover_delete,
+            )
+        });
+
+    payload
+        .or(get_cap)
+        .or(nw_create)
+        .or(nw_del)
+        .or(endp_create)
+        .or(endp_del)
+        .or(endp_info)
+        .or(nw_join)
+        .or(nw_leave)
+        .or(dsc_new)
+        .or(dsc_del)
+}
@@ -0,0 +1,296 @@
+/*
+ * Filename: backend.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::bus::Bus;
+use crate::error::Error;
+use crate::fault::Faults;
+use crate::frame::Frame;
+use crate::metrics::Metrics;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+pub mod cli;
+pub mod fake;
+pub mod raw;
+pub mod timed;
+pub mod userspace;
+
+/// Traffic counters of an interface, as kept by the kernel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub rx_frames: u64,
+    pub tx_frames: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+/// Frame counters of one forwarding rule.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleStats {
+    pub src: String,
+    pub dst: String,
+    /// Whether the rule forwards CAN FD rather than classic frames.
+    pub fd: bool,
+    /// Frames forwarded.
+    pub handled: u64,
+    /// Frames that could not be forwarded.
+    pub dropped: u64,
+    /// Frames discarded by a frame modification check.
+    pub deleted: u64,
+}
+
+/// Creates and removes the network interfaces the plugin manages.
+#[async_trait]
+pub trait LinkBackend: Send + Sync {
+    /// Fail unless vcan and vxcan devices can be created.
+    async fn supported(&self) -> Result<(), Error>;
+
+    /// Whether an interface called `name` exists.
+    async fn link_exists(&self, name: &str) -> Result<bool, Error>;
+
+    /// Create a virtual CAN device and bring it up.
+    async fn vcan_add(&self, name: &str) -> Result<(), Error>;
+
+    /// Create a VXCAN tunnel and bring up its host side, `name`.
+    async fn vxcan_add(&self, name: &str, peer: &str) -> Result<(), Error>;
+
+    /// Move `name` into the network namespace mounted at `netns`, rename it
+    /// to `ifname` there and bring it up.
+    async fn link_move(&self, name: &str, netns: &str, ifname: &str) -> Result<(), Error>;
+
+    /// Bring an interface down and delete it. An interface that is already
+    /// gone counts as deleted.
+    async fn link_del(&self, name: &str) -> Result<(), Error>;
+
+    /// Traffic counters of the interface `name`.
+    async fn link_stats(&self, name: &str) -> Result<LinkStats, Error>;
+}
+
+/// Installs and removes the rules forwarding frames between interfaces.
+#[async_trait]
+pub trait GatewayBackend: Send + Sync {
+    /// Fail unless forwarding rules can be installed.
+    async fn supported(&self) -> Result<(), Error>;
+
+    /// Forward every classic and FD frame received on `src` to `dst`.
+    async fn rule_add(&self, src: &str, dst: &str) -> Result<(), Error>;
+
+    /// Remove a rule added by `rule_add`. A rule that is already gone counts
+    /// as removed.
+    async fn rule_del(&self, src: &str, dst: &str) -> Result<(), Error>;
+
+    /// Counters of every rule installed in the kernel, including rules the
+    /// plugin did not add.
+    async fn rule_stats(&self) -> Result<Vec<RuleStats>, Error>;
+
+    /// Inject `faults` into the frames the rule from `src` to `dst`
+    /// forwards, replacing any set before. Only gateways forwarding in
+    /// userspace support this.
+    async fn rule_faults(&self, src: &str, dst: &str, faults: &Faults) -> Result<(), Error> {
+        let _ = (src, dst, faults);
+        Err(Error::Unsupported(String::from(
+            "faults can only be injected into frames forwarded in userspace",
+        )))
+    }
+
+    /// Forward every frame received on `src` to `hub`, and on to every
+    /// interface `hub` has a rule to except `src` itself, so that the
+    /// interfaces around a hub exchange frames without receiving their own.
+    /// Removed with `rule_del`. Only gateways forwarding in userspace
+    /// support this.
+    async fn hub_rule_add(&self, src: &str, hub: &str) -> Result<(), Error> {
+        let _ = (src, hub);
+        Err(Error::Unsupported(String::from(
+            "frames can only be forwarded through a hub in userspace",
+        )))
+    }
+
+    /// Keep the frames a hub passes on from `ifc` to interfaces in the same
+    /// `group`, and those from other groups away from `ifc`, or stop with
+    /// `None`. Interfaces without a group form one of their own. Only
+    /// gateways forwarding in userspace support this.
+    async fn group_set(&self, ifc: &str, group: Option<&str>) -> Result<(), Error> {
+        let _ = (ifc, group);
+        Err(Error::Unsupported(String::from(
+            "frames can only be kept within groups around a hub in userspace",
+        )))
+    }
+
+    /// Pass every frame a hub passes on to `ifc`, whatever group it comes
+    /// from, or keep to the group of `ifc` again with `false`. Only gateways
+    /// forwarding in userspace support this.
+    async fn tap_set(&self, ifc: &str, tap: bool) -> Result<(), Error> {
+        let _ = (ifc, tap);
+        Err(Error::Unsupported(String::from(
+            "frames can only be passed on to taps around a hub in userspace",
+        )))
+    }
+
+    /// Pass the frames received on `ifc` through the emulated `bus` before
+    /// forwarding them, or stop doing so with `None`. Only gateways
+    /// forwarding in userspace support this.
+    async fn bus_set(&self, ifc: &str, bus: Option<Arc<Bus>>) -> Result<(), Error> {
+        let _ = (ifc, bus);
+        Err(Error::Unsupported(String::from(
+            "bus timing can only be emulated for frames forwarded in userspace",
+        )))
+    }
+}
+
+/// A raw socket on one interface, sending frames onto it and receiving
+/// every frame on it.
+#[async_trait]
+pub trait FrameSocket: Send + Sync {
+    /// Wait for the next frame on the interface, with the time it arrived.
+    /// Frames sent by other sockets and error frames are received as well,
+    /// frames sent through this socket are not.
+    async fn recv(&self) -> Result<(Frame, SystemTime), Error>;
+
+    /// Send `frame` onto the interface.
+    async fn send(&self, frame: &Frame) -> Result<(), Error>;
+}
+
+/// Opens sockets to exchange frames with the interfaces the plugin manages.
+#[async_trait]
+pub trait FrameBackend: Send + Sync {
+    /// Fail unless raw CAN sockets can be opened.
+    async fn supported(&self) -> Result<(), Error>;
+
+    /// Open a socket on the interface `ifc`.
+    async fn open(&self, ifc: &str) -> Result<Arc<dyn FrameSocket>, Error>;
+}
+
+/// What forwards frames between the interfaces of a network
+/// (`vxcan.gateway`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GatewayMode {
+    /// can-gw rules in the kernel.
+    Kernel,
+    /// The plugin itself, over raw sockets (see [`userspace`]).
+    Userspace,
+    /// The kernel if it supports can-gw, the plugin otherwise.
+    #[default]
+    Auto,
+}
+
+impl GatewayMode {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "kernel" => Ok(GatewayMode::Kernel),
+            "userspace" => Ok(GatewayMode::Userspace),
+            "auto" => Ok(GatewayMode::Auto),
+            _ => Err(Error::InvalidOption(format!(
+                "unknown gateway '{s}', expected 'kernel', 'userspace' or 'auto'"
+            ))),
+        }
+    }
+}
+
+/// How the rules of a network connect its endpoints (`vxcan.topology`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    /// Rules between the host device and every endpoint, and between every
+    /// two endpoints.
+    Mesh,
+    /// Rules between the host device and every endpoint only, the device
+    /// passing each endpoint's frames on to the others (see
+    /// [`GatewayBackend::hub_rule_add`]).
+    Hub,
+    /// A hub if the network forwards frames in userspace, a mesh otherwise.
+    #[default]
+    Auto,
+}
+
+impl Topology {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "mesh" => Ok(Topology::Mesh),
+            "hub" => Ok(Topology::Hub),
+            "auto" => Ok(Topology::Auto),
+            _ => Err(Error::InvalidOption(format!(
+                "unknown topology '{s}', expected 'mesh', 'hub' or 'auto'"
+            ))),
+        }
+    }
+
+    /// The topology a network forwarding frames with `gateway` uses.
+    pub fn resolve(self, gateway: GatewayMode) -> Self {
+        match (self, gateway) {
+            (Topology::Auto, GatewayMode::Userspace) => Topology::Hub,
+            (Topology::Auto, _) => Topology::Mesh,
+            (topology, _) => topology,
+        }
+    }
+}
+
+/// The link, gateway and frame implementations used by the networks of a
+/// manager.
+#[derive(Clone)]
+pub struct Backend {
+    pub links: Arc<dyn LinkBackend>,
+    pub gateway: Arc<dyn GatewayBackend>,
+    pub frames: Arc<dyn FrameBackend>,
+}
+
+impl Backend {
+    pub fn new(
+        links: Arc<dyn LinkBackend>,
+        gateway: Arc<dyn GatewayBackend>,
+        frames: Arc<dyn FrameBackend>,
+    ) -> Self {
+        Backend {
+            links,
+            gateway,
+            frames,
+        }
+    }
+
+    /// The production backend, driving the kernel through `ip` and `cangw`
+    /// and exchanging frames over raw SocketCAN sockets.
+    pub fn cli() -> Self {
+        let cli = Arc::new(cli::CliBackend);
+        Backend::new(cli.clone(), cli, Arc::new(raw::RawBackend))
+    }
+
+    /// The same backend, recording the duration of every link and gateway
+    /// operation in `metrics`.
+    pub fn timed(&self, metrics: Arc<Metrics>) -> Self {
+        let timed = Arc::new(timed::TimedBackend::new(
+            self.links.clone(),
+            self.gateway.clone(),
+            metrics,
+        ));
+        Backend::new(timed.clone(), timed, self.frames.clone())
+    }
+}
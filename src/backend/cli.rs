@@ -0,0 +1,179 @@
+/*
+ * Filename: cli.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::{GatewayBackend, LinkBackend, LinkStats, RuleStats};
+use crate::error::Error;
+use crate::kernel;
+use async_trait::async_trait;
+
+/// Drives the kernel through the `ip` and `cangw` command line tools.
+pub struct CliBackend;
+
+#[async_trait]
+impl LinkBackend for CliBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        kernel::installed("ip", "iproute2").await?;
+        kernel::module_available("vcan", "CONFIG_CAN_VCAN").await?;
+        kernel::module_available("vxcan", "CONFIG_CAN_VXCAN").await
+    }
+
+    async fn link_exists(&self, name: &str) -> Result<bool, Error> {
+        let name = name.to_string();
+        let ifcs = tokio::task::spawn_blocking(interfaces::Interface::get_all)
+            .await
+            .map_err(|e| kernel::error("list interfaces", e.to_string()))?
+            .map_err(|e| kernel::error("list interfaces", e.to_string()))?;
+        Ok(ifcs.iter().any(|i| i.name.eq(&name)))
+    }
+
+    async fn vcan_add(&self, name: &str) -> Result<(), Error> {
+        kernel::run("ip", &["link", "add", "dev", name, "type", "vcan"]).await?;
+        kernel::run("ip", &["link", "set", "up", name]).await
+    }
+
+    async fn vxcan_add(&self, name: &str, peer: &str) -> Result<(), Error> {
+        kernel::run(
+            "ip",
+            &[
+                "link", "add", "dev", name, "type", "vxcan", "peer", "name", peer,
+            ],
+        )
+        .await?;
+        kernel::run("ip", &["link", "set", "up", name]).await
+    }
+
+    async fn link_move(&self, name: &str, netns: &str, ifname: &str) -> Result<(), Error> {
+        kernel::run("ip", &["link", "set", "dev", name, "netns", netns]).await?;
+        let net = format!("--net={netns}");
+        kernel::run(
+            "nsenter",
+            &[&net, "ip", "link", "set", "dev", name, "name", ifname],
+        )
+        .await?;
+        kernel::run("nsenter", &[&net, "ip", "link", "set", "up", ifname]).await
+    }
+
+    async fn link_del(&self, name: &str) -> Result<(), Error> {
+        kernel::remove("ip", &["link", "set", "down", name]).await?;
+        kernel::remove("ip", &["link", "del", "dev", name]).await
+    }
+
+    async fn link_stats(&self, name: &str) -> Result<LinkStats, Error> {
+        let dir = format!("/sys/class/net/{name}/statistics");
+        let read = |counter: &'static str| {
+            let path = format!("{dir}/{counter}");
+            async move {
+                let value = tokio::fs::read_to_string(&path)
+                    .await
+                    .map_err(|e| kernel::error(&format!("read {path}"), e.to_string()))?;
+                value
+                    .trim()
+                    .parse::<u64>()
+                    .map_err(|e| kernel::error(&format!("read {path}"), e.to_string()))
+            }
+        };
+        Ok(LinkStats {
+            rx_frames: read("rx_packets").await?,
+            tx_frames: read("tx_packets").await?,
+            rx_bytes: read("rx_bytes").await?,
+            tx_bytes: read("tx_bytes").await?,
+            rx_errors: read("rx_errors").await?,
+            tx_errors: read("tx_errors").await?,
+            rx_dropped: read("rx_dropped").await?,
+            tx_dropped: read("tx_dropped").await?,
+        })
+    }
+}
+
+#[async_trait]
+impl GatewayBackend for CliBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        kernel::installed("cangw", "can-utils").await?;
+        kernel::module_available("can-gw", "CONFIG_CAN_GW").await
+    }
+
+    async fn rule_add(&self, src: &str, dst: &str) -> Result<(), Error> {
+        kernel::run("cangw", &["-A", "-s", src, "-d", dst, "-e"]).await?;
+        kernel::run("cangw", &["-A", "-s", src, "-d", dst, "-eX"]).await
+    }
+
+    async fn rule_del(&self, src: &str, dst: &str) -> Result<(), Error> {
+        kernel::remove("cangw", &["-D", "-s", src, "-d", dst, "-e"]).await?;
+        kernel::remove("cangw", &["-D", "-s", src, "-d", dst, "-eX"]).await
+    }
+
+    async fn rule_stats(&self) -> Result<Vec<RuleStats>, Error> {
+        let list = kernel::output("cangw", &["-L"]).await?;
+        Ok(parse_rules(&list))
+    }
+}
+
+/// Parse the output of `cangw -L`, which prints every rule as the command
+/// that would add it, followed by its counters:
+///
+/// ```text
+/// cangw -A -s vcan0 -d vxcan0a1b2c3d -e -X # 12 handled 0 dropped 0 deleted
+/// ```
+fn parse_rules(list: &str) -> Vec<RuleStats> {
+    let mut rules = Vec::new();
+    for line in list.lines() {
+        let (command, counters) = match line.split_once('#') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        let mut rule = RuleStats::default();
+        let mut args = command.split_whitespace();
+        while let Some(arg) = args.next() {
+            match arg {
+                "-s" => rule.src = args.next().unwrap_or_default().to_string(),
+                "-d" => rule.dst = args.next().unwrap_or_default().to_string(),
+                "-X" => rule.fd = true,
+                _ => {}
+            }
+        }
+        if rule.src.is_empty() || rule.dst.is_empty() {
+            continue;
+        }
+
+        let words: Vec<&str> = counters.split_whitespace().collect();
+        for pair in words.chunks(2) {
+            if let [count, name] = pair {
+                let count = count.parse().unwrap_or_default();
+                match *name {
+                    "handled" => rule.handled = count,
+                    "dropped" => rule.dropped = count,
+                    "deleted" => rule.deleted = count,
+                    _ => {}
+                }
+            }
+        }
+        rules.push(rule);
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests;
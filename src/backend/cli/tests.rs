@@ -0,0 +1,43 @@
+use super::parse_rules;
+use crate::backend::RuleStats;
+
+#[test]
+fn rule_list() {
+    let list = "\
+cangw -A -s vcan0 -d vxcan0a1b2c3d -e # 12 handled 1 dropped 0 deleted
+cangw -A -s vcan0 -d vxcan0a1b2c3d -e -X # 3 handled 0 dropped 2 deleted
+cangw -A -s can0 -d can1 -m SET:IL:333.4.1122334455667788 # 0 handled 0 dropped 0 deleted
+";
+    assert_eq!(
+        parse_rules(list),
+        [
+            RuleStats {
+                src: "vcan0".into(),
+                dst: "vxcan0a1b2c3d".into(),
+                fd: false,
+                handled: 12,
+                dropped: 1,
+                deleted: 0,
+            },
+            RuleStats {
+                src: "vcan0".into(),
+                dst: "vxcan0a1b2c3d".into(),
+                fd: true,
+                handled: 3,
+                dropped: 0,
+                deleted: 2,
+            },
+            RuleStats {
+                src: "can0".into(),
+                dst: "can1".into(),
+                ..Default::default()
+            },
+        ]
+    );
+}
+
+#[test]
+fn empty_rule_list() {
+    assert!(parse_rules("").is_empty());
+    assert!(parse_rules("garbage without counters\n").is_empty());
+}
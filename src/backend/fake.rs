@@ -0,0 +1,401 @@
+/*
+ * Filename: fake.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::{
+    FrameBackend, FrameSocket, GatewayBackend, LinkBackend, LinkStats, RuleStats,
+};
+use crate::error::Error;
+use crate::frame::Frame;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// Frames buffered for each socket before the oldest are lost.
+const SOCKET_QUEUE: usize = 1024;
+
+/// Kind of an interface known to the [`FakeBackend`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FakeLink {
+    /// Added with [`FakeBackend::link_insert`], e.g. a physical CAN device.
+    Existing,
+    Vcan,
+    /// One side of a VXCAN tunnel, naming the other side.
+    Vxcan {
+        peer: String,
+    },
+}
+
+#[derive(Default)]
+struct FakeState {
+    links: BTreeMap<String, FakeLink>,
+    rules: BTreeSet<(String, String)>,
+    /// Links moved out of the host namespace, by `(netns, name)`, naming
+    /// the host side of their tunnel.
+    moved: BTreeMap<(String, String), String>,
+    link_stats: HashMap<String, LinkStats>,
+    rule_stats: HashMap<(String, String), RuleStats>,
+    failing: HashSet<String>,
+    /// Frames on each interface a socket was opened on, tagged with the
+    /// socket that sent them (0 for none).
+    buses: HashMap<String, broadcast::Sender<(u64, Frame)>>,
+    sockets: u64,
+}
+
+impl FakeState {
+    /// Send `frame` on `ifc`: other sockets on it receive it, as does the
+    /// other side of a tunnel.
+    fn transmit(&self, ifc: &str, origin: u64, frame: &Frame, hops: u32) {
+        self.receive(ifc, origin, frame, hops);
+        if let Some(FakeLink::Vxcan { peer }) = self.links.get(ifc) {
+            self.receive(peer, 0, frame, hops);
+        }
+    }
+
+    /// Deliver `frame` to the sockets on `ifc` and, like can-gw with its
+    /// default hop limit of one, forward it along the rules from `ifc`
+    /// unless it was forwarded already or is an error frame.
+    fn receive(&self, ifc: &str, origin: u64, frame: &Frame, hops: u32) {
+        if let Some(bus) = self.buses.get(ifc) {
+            let _ = bus.send((origin, frame.clone()));
+        }
+        if hops == 0 && !frame.is_error() {
+            for (_, dst) in self.rules.iter().filter(|(src, _)| src == ifc) {
+                self.transmit(dst, 0, frame, hops + 1);
+            }
+        }
+    }
+}
+
+/// In-memory stand-in for the kernel that records links and rules, so that
+/// topology logic can be exercised without root, vcan or can-gw.
+///
+/// Frames sent through its sockets travel like on the real interfaces: to
+/// the other sockets on the interface, through VXCAN tunnels and along the
+/// forwarding rules.
+#[derive(Default)]
+pub struct FakeBackend {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pretend an interface the plugin did not create is present.
+    pub fn link_insert(&self, name: &str) {
+        self.state
+            .lock()
+            .links
+            .insert(name.to_string(), FakeLink::Existing);
+    }
+
+    /// Make every operation touching `name` fail until [`Self::recover`].
+    /// Failing `vcan`, `vxcan`, `can-gw` or `can-raw` reports the feature as
+    /// unsupported.
+    pub fn fail(&self, name: &str) {
+        self.state.lock().failing.insert(name.to_string());
+    }
+
+    pub fn recover(&self, name: &str) {
+        self.state.lock().failing.remove(name);
+    }
+
+    /// Current interfaces, by name.
+    pub fn links(&self) -> BTreeMap<String, FakeLink> {
+        self.state.lock().links.clone()
+    }
+
+    /// Interfaces moved into other namespaces, by `(netns, name)`, naming
+    /// the host side of their tunnel.
+    pub fn moved_links(&self) -> BTreeMap<(String, String), String> {
+        self.state.lock().moved.clone()
+    }
+
+    /// Set the traffic counters reported for the interface `name`; other
+    /// interfaces report zero.
+    pub fn set_link_stats(&self, name: &str, stats: LinkStats) {
+        self.state.lock().link_stats.insert(name.to_string(), stats);
+    }
+
+    /// Set the counters reported for the rule from `stats.src` to
+    /// `stats.dst` while it exists; other rules report zero.
+    pub fn set_rule_stats(&self, stats: RuleStats) {
+        let key = (stats.src.clone(), stats.dst.clone());
+        self.state.lock().rule_stats.insert(key, stats);
+    }
+
+    /// Current forwarding rules as `(src, dst)` pairs.
+    pub fn rules(&self) -> BTreeSet<(String, String)> {
+        self.state.lock().rules.clone()
+    }
+
+    fn check(state: &FakeState, op: &str, names: &[&str]) -> Result<(), Error> {
+        match names.iter().find(|n| state.failing.contains(**n)) {
+            Some(n) => Err(Error::Kernel {
+                command: format!("{op} {}", names.join(" ")),
+                reason: format!("injected failure on {n}"),
+                transient: false,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail if support for any of `features` was removed with
+    /// [`Self::fail`].
+    fn supports(state: &FakeState, features: &[&str]) -> Result<(), Error> {
+        match features.iter().find(|f| state.failing.contains(**f)) {
+            Some(f) => Err(Error::Unsupported(format!("{f} is not supported"))),
+            None => Ok(()),
+        }
+    }
+
+    fn missing(op: &str, name: &str) -> Error {
+        Error::Kernel {
+            command: format!("{op} {name}"),
+            reason: format!("Cannot find device \"{name}\""),
+            transient: false,
+        }
+    }
+
+    fn exists(op: &str, name: &str) -> Error {
+        Error::Kernel {
+            command: format!("{op} {name}"),
+            reason: String::from("RTNETLINK answers: File exists"),
+            transient: false,
+        }
+    }
+}
+
+#[async_trait]
+impl LinkBackend for FakeBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        Self::supports(&self.state.lock(), &["vcan", "vxcan"])
+    }
+
+    async fn link_exists(&self, name: &str) -> Result<bool, Error> {
+        let state = self.state.lock();
+        Self::check(&state, "link_exists", &[name])?;
+        Ok(state.links.contains_key(name))
+    }
+
+    async fn vcan_add(&self, name: &str) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        Self::check(&state, "vcan_add", &[name])?;
+        if state.links.contains_key(name) {
+            return Err(Self::exists("vcan_add", name));
+        }
+        state.links.insert(name.to_string(), FakeLink::Vcan);
+        Ok(())
+    }
+
+    async fn vxcan_add(&self, name: &str, peer: &str) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        Self::check(&state, "vxcan_add", &[name, peer])?;
+        if state.links.contains_key(name) || state.links.contains_key(peer) {
+            return Err(Self::exists("vxcan_add", name));
+        }
+        state.links.insert(
+            name.to_string(),
+            FakeLink::Vxcan {
+                peer: peer.to_string(),
+            },
+        );
+        state.links.insert(
+            peer.to_string(),
+            FakeLink::Vxcan {
+                peer: name.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn link_move(&self, name: &str, netns: &str, ifname: &str) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        Self::check(&state, "link_move", &[name, netns])?;
+        let key = (netns.to_string(), ifname.to_string());
+        if state.moved.contains_key(&key) {
+            return Err(Self::exists("link_move", ifname));
+        }
+        match state.links.remove(name) {
+            Some(FakeLink::Vxcan { peer }) => {
+                state.moved.insert(key, peer);
+                Ok(())
+            }
+            Some(link) => {
+                // Only tunnel ends are ever moved by the plugin
+                state.links.insert(name.to_string(), link);
+                Err(Self::missing("link_move", name))
+            }
+            None => Err(Self::missing("link_move", name)),
+        }
+    }
+
+    async fn link_del(&self, name: &str) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        Self::check(&state, "link_del", &[name])?;
+        // Like the kernel, deleting either side of a tunnel removes both and
+        // any rule using a removed interface goes with it
+        let mut gone = vec![name.to_string()];
+        if let Some(FakeLink::Vxcan { peer }) = state.links.remove(name) {
+            state.links.remove(&peer);
+            gone.push(peer);
+        }
+        state.moved.retain(|_, host| host != name);
+        state
+            .rules
+            .retain(|(src, dst)| !gone.contains(src) && !gone.contains(dst));
+        // Sockets on a removed interface see it go away
+        state.buses.retain(|ifc, _| !gone.contains(ifc));
+        Ok(())
+    }
+
+    async fn link_stats(&self, name: &str) -> Result<LinkStats, Error> {
+        let state = self.state.lock();
+        Self::check(&state, "link_stats", &[name])?;
+        if !state.links.contains_key(name) {
+            return Err(Self::missing("link_stats", name));
+        }
+        Ok(state.link_stats.get(name).copied().unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl GatewayBackend for FakeBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        Self::supports(&self.state.lock(), &["can-gw"])
+    }
+
+    async fn rule_add(&self, src: &str, dst: &str) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        Self::check(&state, "rule_add", &[src, dst])?;
+        for name in [src, dst] {
+            if !state.links.contains_key(name) {
+                return Err(Self::missing("rule_add", name));
+            }
+        }
+        state.rules.insert((src.to_string(), dst.to_string()));
+        Ok(())
+    }
+
+    async fn rule_del(&self, src: &str, dst: &str) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        Self::check(&state, "rule_del", &[src, dst])?;
+        state.rules.remove(&(src.to_string(), dst.to_string()));
+        Ok(())
+    }
+
+    async fn rule_stats(&self) -> Result<Vec<RuleStats>, Error> {
+        let state = self.state.lock();
+        Self::check(&state, "rule_stats", &["can-gw"])?;
+        Ok(state
+            .rules
+            .iter()
+            .map(
+                |(src, dst)| match state.rule_stats.get(&(src.clone(), dst.clone())) {
+                    Some(stats) => stats.clone(),
+                    None => RuleStats {
+                        src: src.clone(),
+                        dst: dst.clone(),
+                        ..Default::default()
+                    },
+                },
+            )
+            .collect())
+    }
+}
+
+#[async_trait]
+impl FrameBackend for FakeBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        Self::supports(&self.state.lock(), &["can-raw"])
+    }
+
+    async fn open(&self, ifc: &str) -> Result<Arc<dyn FrameSocket>, Error> {
+        let mut state = self.state.lock();
+        Self::check(&state, "open", &[ifc])?;
+        if !state.links.contains_key(ifc) {
+            return Err(Self::missing("open", ifc));
+        }
+        state.sockets += 1;
+        let id = state.sockets;
+        let rx = state
+            .buses
+            .entry(ifc.to_string())
+            .or_insert_with(|| broadcast::channel(SOCKET_QUEUE).0)
+            .subscribe();
+        Ok(Arc::new(FakeSocket {
+            ifc: ifc.to_string(),
+            id,
+            state: self.state.clone(),
+            rx: tokio::sync::Mutex::new(rx),
+        }))
+    }
+}
+
+/// Socket opened on a [`FakeBackend`] interface.
+struct FakeSocket {
+    ifc: String,
+    id: u64,
+    state: Arc<Mutex<FakeState>>,
+    rx: tokio::sync::Mutex<broadcast::Receiver<(u64, Frame)>>,
+}
+
+#[async_trait]
+impl FrameSocket for FakeSocket {
+    async fn recv(&self) -> Result<(Frame, SystemTime), Error> {
+        let mut rx = self.rx.lock().await;
+        loop {
+            match rx.recv().await {
+                Ok((origin, frame)) if origin != self.id => return Ok((frame, SystemTime::now())),
+                Ok(_) => continue,
+                // Like a socket whose receive queue overflowed
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(FakeBackend::missing("recv", &self.ifc))
+                }
+            }
+        }
+    }
+
+    async fn send(&self, frame: &Frame) -> Result<(), Error> {
+        let state = self.state.lock();
+        FakeBackend::check(&state, "send", &[&self.ifc])?;
+        if !state.buses.contains_key(&self.ifc) {
+            return Err(FakeBackend::missing("send", &self.ifc));
+        }
+        frame.validate().map_err(|e| Error::Kernel {
+            command: format!("send {}", self.ifc),
+            reason: e.to_string(),
+            transient: false,
+        })?;
+        state.transmit(&self.ifc, self.id, frame, 0);
+        Ok(())
+    }
+}
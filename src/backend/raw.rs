@@ -0,0 +1,253 @@
+/*
+ * Filename: raw.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::{FrameBackend, FrameSocket};
+use crate::error::Error;
+use crate::frame::{Frame, CANFD_MAX_LEN, CAN_MAX_LEN, RTR_FLAG};
+use crate::kernel;
+use async_trait::async_trait;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::unix::AsyncFd;
+
+/// Size of `struct can_frame`.
+const CAN_MTU: usize = 16;
+/// Size of `struct canfd_frame`.
+const CANFD_MTU: usize = 72;
+
+/// Exchanges frames through raw SocketCAN sockets.
+pub struct RawBackend;
+
+#[async_trait]
+impl FrameBackend for RawBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        kernel::module_available("can-raw", "CONFIG_CAN_RAW").await
+    }
+
+    async fn open(&self, ifc: &str) -> Result<Arc<dyn FrameSocket>, Error> {
+        let socket = RawSocket::open(ifc).map_err(|e| error("open", ifc, e))?;
+        Ok(Arc::new(socket))
+    }
+}
+
+/// A non-blocking `CAN_RAW` socket bound to one interface, receiving CAN FD
+/// and error frames as well.
+pub struct RawSocket {
+    ifc: String,
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl RawSocket {
+    pub fn open(ifc: &str) -> io::Result<Self> {
+        let name = CString::new(ifc)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+        // SAFETY: `name` is a valid NUL-terminated string
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: plain system call; the descriptor is owned right away
+        let fd = unsafe {
+            libc::socket(
+                libc::PF_CAN,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::CAN_RAW,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a freshly created descriptor nobody else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        setsockopt(&fd, libc::CAN_RAW_FD_FRAMES, 1 as libc::c_int)?;
+        setsockopt(&fd, libc::CAN_RAW_ERR_FILTER, libc::CAN_ERR_MASK)?;
+
+        // SAFETY: an all-zero sockaddr_can is valid
+        let mut addr: libc::sockaddr_can = unsafe { mem::zeroed() };
+        addr.can_family = libc::AF_CAN as libc::sa_family_t;
+        addr.can_ifindex = index as libc::c_int;
+        // SAFETY: `addr` is a sockaddr_can of the given size
+        let res = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_can as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_can>() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(RawSocket {
+            ifc: ifc.to_string(),
+            fd: AsyncFd::new(fd)?,
+        })
+    }
+}
+
+#[async_trait]
+impl FrameSocket for RawSocket {
+    async fn recv(&self) -> Result<(Frame, SystemTime), Error> {
+        let mut buf = [0u8; CANFD_MTU];
+        loop {
+            let mut guard = self
+                .fd
+                .readable()
+                .await
+                .map_err(|e| error("recv", &self.ifc, e))?;
+            let res = guard.try_io(|fd| {
+                // SAFETY: `buf` is writable for its whole length
+                let n = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                match n {
+                    n if n < 0 => Err(io::Error::last_os_error()),
+                    n => Ok(n as usize),
+                }
+            });
+            match res {
+                Ok(Ok(n)) => return Ok((decode(&buf[..n], &self.ifc)?, SystemTime::now())),
+                Ok(Err(e)) => return Err(error("recv", &self.ifc, e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    async fn send(&self, frame: &Frame) -> Result<(), Error> {
+        frame.validate().map_err(|e| {
+            error(
+                "send",
+                &self.ifc,
+                io::Error::new(io::ErrorKind::InvalidInput, e),
+            )
+        })?;
+        let buf = encode(frame);
+        loop {
+            let mut guard = self
+                .fd
+                .writable()
+                .await
+                .map_err(|e| error("send", &self.ifc, e))?;
+            let res = guard.try_io(|fd| {
+                // SAFETY: `buf` is readable for its whole length
+                let n = unsafe {
+                    libc::write(
+                        fd.as_raw_fd(),
+                        buf.as_ptr() as *const libc::c_void,
+                        buf.len(),
+                    )
+                };
+                match n {
+                    n if n < 0 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            });
+            match res {
+                Ok(res) => return res.map_err(|e| error("send", &self.ifc, e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+fn setsockopt<T>(fd: &OwnedFd, name: libc::c_int, value: T) -> io::Result<()> {
+    // SAFETY: `value` lives for the duration of the call and has the given size
+    let res = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_CAN_RAW,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Read a `struct can_frame` or `struct canfd_frame`.
+fn decode(buf: &[u8], ifc: &str) -> Result<Frame, Error> {
+    let (fd, max) = match buf.len() {
+        CAN_MTU => (false, CAN_MAX_LEN),
+        CANFD_MTU => (true, CANFD_MAX_LEN),
+        n => {
+            return Err(error(
+                "recv",
+                ifc,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected frame size {n}"),
+                ),
+            ))
+        }
+    };
+    let id = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let len = (buf[4] as usize).min(max);
+    let data = match id & RTR_FLAG {
+        0 => buf[8..8 + len].to_vec(),
+        _ => vec![0; len],
+    };
+    Ok(Frame {
+        id,
+        data,
+        fd,
+        flags: if fd { buf[5] } else { 0 },
+    })
+}
+
+/// Lay out `frame` as a `struct can_frame` or `struct canfd_frame`.
+fn encode(frame: &Frame) -> Vec<u8> {
+    let mut buf = vec![0u8; if frame.fd { CANFD_MTU } else { CAN_MTU }];
+    buf[..4].copy_from_slice(&frame.id.to_ne_bytes());
+    buf[4] = frame.data.len() as u8;
+    if frame.fd {
+        buf[5] = frame.flags;
+    }
+    if !frame.is_remote() {
+        buf[8..8 + frame.data.len()].copy_from_slice(&frame.data);
+    }
+    buf
+}
+
+fn error(op: &str, ifc: &str, e: io::Error) -> Error {
+    Error::Kernel {
+        command: format!("{op} CAN frames on {ifc}"),
+        transient: e.raw_os_error() == Some(libc::ENOBUFS),
+        reason: e.to_string(),
+    }
+}
@@ -0,0 +1,136 @@
+/*
+ * Filename: timed.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::{GatewayBackend, LinkBackend, LinkStats, RuleStats};
+use crate::bus::Bus;
+use crate::error::Error;
+use crate::fault::Faults;
+use crate::metrics::Metrics;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Wraps the backend of a manager to record how long each operation takes.
+pub struct TimedBackend {
+    links: Arc<dyn LinkBackend>,
+    gateway: Arc<dyn GatewayBackend>,
+    metrics: Arc<Metrics>,
+}
+
+impl TimedBackend {
+    pub fn new(
+        links: Arc<dyn LinkBackend>,
+        gateway: Arc<dyn GatewayBackend>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        TimedBackend {
+            links,
+            gateway,
+            metrics,
+        }
+    }
+
+    async fn time<T>(&self, op: &'static str, f: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let res = f.await;
+        self.metrics.kernel_observe(op, start.elapsed());
+        res
+    }
+}
+
+#[async_trait]
+impl LinkBackend for TimedBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        self.links.supported().await
+    }
+
+    async fn link_exists(&self, name: &str) -> Result<bool, Error> {
+        self.time("link_exists", self.links.link_exists(name)).await
+    }
+
+    async fn vcan_add(&self, name: &str) -> Result<(), Error> {
+        self.time("vcan_add", self.links.vcan_add(name)).await
+    }
+
+    async fn vxcan_add(&self, name: &str, peer: &str) -> Result<(), Error> {
+        self.time("vxcan_add", self.links.vxcan_add(name, peer))
+            .await
+    }
+
+    async fn link_move(&self, name: &str, netns: &str, ifname: &str) -> Result<(), Error> {
+        self.time("link_move", self.links.link_move(name, netns, ifname))
+            .await
+    }
+
+    async fn link_del(&self, name: &str) -> Result<(), Error> {
+        self.time("link_del", self.links.link_del(name)).await
+    }
+
+    async fn link_stats(&self, name: &str) -> Result<LinkStats, Error> {
+        self.time("link_stats", self.links.link_stats(name)).await
+    }
+}
+
+#[async_trait]
+impl GatewayBackend for TimedBackend {
+    async fn supported(&self) -> Result<(), Error> {
+        self.gateway.supported().await
+    }
+
+    async fn rule_add(&self, src: &str, dst: &str) -> Result<(), Error> {
+        self.time("rule_add", self.gateway.rule_add(src, dst)).await
+    }
+
+    async fn rule_del(&self, src: &str, dst: &str) -> Result<(), Error> {
+        self.time("rule_del", self.gateway.rule_del(src, dst)).await
+    }
+
+    async fn rule_stats(&self) -> Result<Vec<RuleStats>, Error> {
+        self.time("rule_stats", self.gateway.rule_stats()).await
+    }
+
+    async fn rule_faults(&self, src: &str, dst: &str, faults: &Faults) -> Result<(), Error> {
+        self.gateway.rule_faults(src, dst, faults).await
+    }
+
+    async fn bus_set(&self, ifc: &str, bus: Option<Arc<Bus>>) -> Result<(), Error> {
+        self.gateway.bus_set(ifc, bus).await
+    }
+
+    async fn hub_rule_add(&self, src: &str, hub: &str) -> Result<(), Error> {
+        self.time("rule_add", self.gateway.hub_rule_add(src, hub))
+            .await
+    }
+
+    async fn group_set(&self, ifc: &str, group: Option<&str>) -> Result<(), Error> {
+        self.gateway.group_set(ifc, group).await
+    }
+
+    async fn tap_set(&self, ifc: &str, tap: bool) -> Result<(), Error> {
+        self.gateway.tap_set(ifc, tap).await
+    }
+}
@@ -0,0 +1,439 @@
+/*
+ * Filename: userspace.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::{FrameBackend, FrameSocket, GatewayBackend, RuleStats};
+use crate::bus::Bus;
+use crate::error::Error;
+use crate::fault::Faults;
+use crate::frame::Frame;
+use async_trait::async_trait;
+use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Longest a frame to be reordered waits for the frame to swap places with.
+const REORDER_WAIT: Duration = Duration::from_millis(10);
+
+/// Forwards frames between interfaces in the plugin itself, for kernels
+/// without can-gw.
+///
+/// Each interface with a rule gets one raw socket, used both to receive the
+/// frames to forward and to send frames forwarded to it. A socket does not
+/// receive its own frames, so, like can-gw with its default hop limit, a
+/// frame is forwarded at most once: a frame from one endpoint reaches
+/// another endpoint through their direct rule only, not again through the
+/// network device. Error frames are not forwarded, as can-gw does not
+/// receive them.
+///
+/// A route to a hub (see [`GatewayBackend::hub_rule_add`]) passes each
+/// frame on along the routes from the hub itself, skipping the interface the
+/// frame came from and the interfaces in other groups except taps, which
+/// can-gw has no way to do. The frame suffers the
+/// faults of both routes, and is paced by the bus of the interface it came
+/// from only.
+///
+/// Unlike can-gw, routes can degrade the frames they forward (see
+/// [`Faults`]), and the interfaces of a network can share an emulated
+/// [`Bus`] that paces the frames they forward. Frames lost that way count as
+/// dropped.
+pub struct UserspaceGateway {
+    frames: Arc<dyn FrameBackend>,
+    ports: Mutex<HashMap<String, Port>>,
+    /// Emulated buses by interface, kept while the interfaces have no port.
+    buses: RwLock<HashMap<String, Arc<Bus>>>,
+    /// Groups of the interfaces around hubs, by interface.
+    groups: Arc<RwLock<HashMap<String, String>>>,
+    /// Interfaces around hubs that receive frames from every group.
+    taps: Arc<RwLock<HashSet<String>>>,
+}
+
+/// The socket on one interface and the rules forwarding frames from it.
+struct Port {
+    socket: Arc<dyn FrameSocket>,
+    routes: Arc<RwLock<Vec<Route>>>,
+    bus: Arc<RwLock<Option<Arc<Bus>>>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Clone)]
+struct Route {
+    dst: String,
+    socket: Arc<dyn FrameSocket>,
+    counters: Arc<Counters>,
+    /// Set while faults are injected into the route's frames.
+    faults: Option<Arc<FaultLine>>,
+    /// Set if `dst` is a hub, to pass the frames on around it.
+    hub: Option<Hub>,
+}
+
+/// The routes from a hub, and the groups of the interfaces they go to.
+#[derive(Clone)]
+struct Hub {
+    routes: Arc<RwLock<Vec<Route>>>,
+    groups: Arc<RwLock<HashMap<String, String>>>,
+    taps: Arc<RwLock<HashSet<String>>>,
+}
+
+impl Route {
+    async fn send(&self, src: &str, frame: &Frame) {
+        send(src, &self.dst, &self.socket, &self.counters, frame).await;
+        let Some(hub) = &self.hub else {
+            return;
+        };
+        // Around the hub, within the group the frame came from or to taps,
+        // and except back to where it came from
+        let routes: Vec<Route> = {
+            let (groups, taps) = (hub.groups.read(), hub.taps.read());
+            let group = groups.get(src);
+            hub.routes
+                .read()
+                .iter()
+                .filter(|r| r.dst != src)
+                .filter(|r| taps.contains(&r.dst) || groups.get(&r.dst) == group)
+                .cloned()
+                .collect()
+        };
+        for route in routes.iter() {
+            match &route.faults {
+                Some(line) => line.push(frame.clone()),
+                None => send(&self.dst, &route.dst, &route.socket, &route.counters, frame).await,
+            }
+        }
+    }
+}
+
+/// Delivers the frames of a route as its faults dictate, holding them back
+/// in a task of its own so that other routes are not delayed.
+struct FaultLine {
+    faults: Faults,
+    tx: mpsc::UnboundedSender<Delivery>,
+    counters: Arc<Counters>,
+    task: JoinHandle<()>,
+}
+
+struct Delivery {
+    frame: Frame,
+    due: Instant,
+    reorder: bool,
+}
+
+impl Drop for FaultLine {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl FaultLine {
+    fn start(src: &str, route: &Route, faults: Faults) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(deliver(src.to_string(), route.clone(), rx));
+        FaultLine {
+            faults,
+            tx,
+            counters: route.counters.clone(),
+            task,
+        }
+    }
+
+    fn push(&self, mut frame: Frame) {
+        let fate = self.faults.apply(&mut frame, &mut rand::thread_rng());
+        if fate.copies == 0 {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let due = Instant::now() + fate.delay;
+        for _ in 0..fate.copies {
+            let _ = self.tx.send(Delivery {
+                frame: frame.clone(),
+                due,
+                reorder: fate.reorder,
+            });
+        }
+    }
+}
+
+/// Send the frames queued on a route when they are due. Frames leave in the
+/// order they were queued; a frame to be reordered is sent after the next
+/// one, or on its own if none follows soon.
+async fn deliver(src: String, route: Route, mut rx: mpsc::UnboundedReceiver<Delivery>) {
+    let mut held: Option<Frame> = None;
+    loop {
+        let next = match &held {
+            Some(frame) => match tokio::time::timeout(REORDER_WAIT, rx.recv()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    route.send(&src, frame).await;
+                    held = None;
+                    continue;
+                }
+            },
+            None => rx.recv().await,
+        };
+        let Some(delivery) = next else {
+            return;
+        };
+        tokio::time::sleep_until(delivery.due).await;
+        if delivery.reorder && held.is_none() {
+            held = Some(delivery.frame);
+            continue;
+        }
+        route.send(&src, &delivery.frame).await;
+        if let Some(frame) = held.take() {
+            route.send(&src, &frame).await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    handled: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl UserspaceGateway {
+    pub fn new(frames: Arc<dyn FrameBackend>) -> Self {
+        UserspaceGateway {
+            frames,
+            ports: Mutex::new(HashMap::new()),
+            buses: RwLock::new(HashMap::new()),
+            groups: Arc::default(),
+            taps: Arc::default(),
+        }
+    }
+
+    /// Open a socket on `name` unless there is one, and forward the frames
+    /// it receives along its routes.
+    async fn port_open(&self, ports: &mut HashMap<String, Port>, name: &str) -> Result<(), Error> {
+        if ports.contains_key(name) {
+            return Ok(());
+        }
+        let socket = self.frames.open(name).await?;
+        let routes = Arc::new(RwLock::new(Vec::new()));
+        let bus = Arc::new(RwLock::new(self.buses.read().get(name).cloned()));
+        let task = tokio::spawn(forward(
+            name.to_string(),
+            socket.clone(),
+            routes.clone(),
+            bus.clone(),
+        ));
+        ports.insert(
+            name.to_string(),
+            Port {
+                socket,
+                routes,
+                bus,
+                task,
+            },
+        );
+        Ok(())
+    }
+
+    /// Add a route from `src` to `dst` unless there is one, passing its
+    /// frames on along the routes from `dst` with `hub` set.
+    async fn route_add(&self, src: &str, dst: &str, hub: bool) -> Result<(), Error> {
+        let mut ports = self.ports.lock().await;
+        self.port_open(&mut ports, src).await?;
+        self.port_open(&mut ports, dst).await?;
+
+        let socket = ports[dst].socket.clone();
+        let hub = hub.then(|| Hub {
+            routes: ports[dst].routes.clone(),
+            groups: self.groups.clone(),
+            taps: self.taps.clone(),
+        });
+        let mut routes = ports[src].routes.write();
+        match routes.iter_mut().find(|r| r.dst == dst) {
+            Some(route) => route.hub = hub,
+            None => routes.push(Route {
+                dst: dst.to_string(),
+                socket,
+                counters: Arc::default(),
+                faults: None,
+                hub,
+            }),
+        }
+        Ok(())
+    }
+}
+
+async fn forward(
+    src: String,
+    socket: Arc<dyn FrameSocket>,
+    routes: Arc<RwLock<Vec<Route>>>,
+    bus: Arc<RwLock<Option<Arc<Bus>>>>,
+) {
+    loop {
+        let frame = match socket.recv().await {
+            Ok((frame, _)) => frame,
+            Err(e) => {
+                warn!(interface = %src, error = %e, "stopped forwarding frames");
+                return;
+            }
+        };
+        if frame.is_error() {
+            continue;
+        }
+        // Hold the frame until the emulated bus would have carried it
+        let emulated = bus.read().clone();
+        if let Some(bus) = emulated {
+            bus.transmit(&frame).await;
+        }
+        let routes = routes.read().clone();
+        for route in routes {
+            match &route.faults {
+                Some(line) => line.push(frame.clone()),
+                None => route.send(&src, &frame).await,
+            }
+        }
+    }
+}
+
+async fn send(
+    src: &str,
+    dst: &str,
+    socket: &Arc<dyn FrameSocket>,
+    counters: &Counters,
+    frame: &Frame,
+) {
+    match socket.send(frame).await {
+        Ok(()) => counters.handled.fetch_add(1, Ordering::Relaxed),
+        Err(e) => {
+            debug!(%src, %dst, error = %e, "unable to forward frame");
+            counters.dropped.fetch_add(1, Ordering::Relaxed)
+        }
+    };
+}
+
+#[async_trait]
+impl GatewayBackend for UserspaceGateway {
+    async fn supported(&self) -> Result<(), Error> {
+        self.frames.supported().await
+    }
+
+    async fn rule_add(&self, src: &str, dst: &str) -> Result<(), Error> {
+        self.route_add(src, dst, false).await
+    }
+
+    async fn hub_rule_add(&self, src: &str, hub: &str) -> Result<(), Error> {
+        self.route_add(src, hub, true).await
+    }
+
+    async fn rule_del(&self, src: &str, dst: &str) -> Result<(), Error> {
+        let mut ports = self.ports.lock().await;
+        if let Some(port) = ports.get(src) {
+            port.routes.write().retain(|r| r.dst != dst);
+        }
+
+        // Close the sockets no rule uses any more
+        for name in [src, dst] {
+            let used = ports.get(name).is_some_and(|p| !p.routes.read().is_empty())
+                || ports
+                    .values()
+                    .any(|p| p.routes.read().iter().any(|r| r.dst == name));
+            if !used {
+                ports.remove(name);
+            }
+        }
+        Ok(())
+    }
+
+    async fn rule_faults(&self, src: &str, dst: &str, faults: &Faults) -> Result<(), Error> {
+        let ports = self.ports.lock().await;
+        let Some(port) = ports.get(src) else {
+            return Ok(());
+        };
+        let mut routes = port.routes.write();
+        if let Some(route) = routes.iter_mut().find(|r| r.dst == dst) {
+            // Frames still held back by the previous faults are lost
+            route.faults = None;
+            if !faults.is_none() {
+                route.faults = Some(Arc::new(FaultLine::start(src, route, *faults)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn bus_set(&self, ifc: &str, bus: Option<Arc<Bus>>) -> Result<(), Error> {
+        let ports = self.ports.lock().await;
+        match &bus {
+            Some(bus) => self.buses.write().insert(ifc.to_string(), bus.clone()),
+            None => self.buses.write().remove(ifc),
+        };
+        if let Some(port) = ports.get(ifc) {
+            *port.bus.write() = bus;
+        }
+        Ok(())
+    }
+
+    async fn group_set(&self, ifc: &str, group: Option<&str>) -> Result<(), Error> {
+        let mut groups = self.groups.write();
+        match group {
+            Some(group) => groups.insert(ifc.to_string(), group.to_string()),
+            None => groups.remove(ifc),
+        };
+        Ok(())
+    }
+
+    async fn tap_set(&self, ifc: &str, tap: bool) -> Result<(), Error> {
+        let mut taps = self.taps.write();
+        if tap {
+            taps.insert(ifc.to_string());
+        } else {
+            taps.remove(ifc);
+        }
+        Ok(())
+    }
+
+    async fn rule_stats(&self) -> Result<Vec<RuleStats>, Error> {
+        let ports = self.ports.lock().await;
+        let mut stats = Vec::new();
+        for (src, port) in ports.iter() {
+            for route in port.routes.read().iter() {
+                stats.push(RuleStats {
+                    src: src.clone(),
+                    dst: route.dst.clone(),
+                    fd: false,
+                    handled: route.counters.handled.load(Ordering::Relaxed),
+                    dropped: route.counters.dropped.load(Ordering::Relaxed),
+                    deleted: 0,
+                });
+            }
+        }
+        Ok(stats)
+    }
+}
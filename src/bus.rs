@@ -0,0 +1,303 @@
+/*
+ * Filename: bus.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Emulation of the timing of a real CAN bus, whose bandwidth a vcan lacks.
+
+use crate::frame::{self, Frame, EFF_MASK};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Period over which the bus load is measured.
+const LOAD_WINDOW: Duration = Duration::from_secs(1);
+
+/// Bits of a classic frame outside the data field: SOF, identifier, RTR,
+/// IDE, r0, DLC, CRC, delimiters, ACK, EOF and interframe space.
+const CLASSIC_OVERHEAD: u64 = 47;
+/// Further bits of an extended identifier: SRR, IDE and 18 identifier bits.
+const EXTENDED_OVERHEAD: u64 = 20;
+/// Bits of a classic frame exposed to bit stuffing outside the data field:
+/// SOF up to the end of the CRC.
+const CLASSIC_STUFFED: u64 = 34;
+/// Bits of a CAN FD frame sent at the nominal bitrate: SOF, identifier,
+/// RRS, IDE, FDF, res and BRS, then CRC delimiter, ACK, EOF and interframe
+/// space.
+const FD_NOMINAL: u64 = 17 + 13;
+/// Bits of a CAN FD frame sent at the data bitrate outside the data field
+/// and CRC: ESI, DLC and the stuff count.
+const FD_DATA: u64 = 9;
+
+/// The bitrates of an emulated bus (`vxcan.emulate-bitrate` and
+/// `vxcan.emulate-data-bitrate`), in bit/s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct BusConfig {
+    /// Bitrate of classic frames and of the arbitration phase of CAN FD
+    /// frames.
+    pub bitrate: u32,
+    /// Bitrate of the data phase of CAN FD frames that switch bitrate.
+    pub data_bitrate: u32,
+}
+
+impl BusConfig {
+    /// A bus at `bitrate`, switching to no higher bitrate for CAN FD data.
+    pub fn new(bitrate: u32) -> Self {
+        BusConfig {
+            bitrate,
+            data_bitrate: bitrate,
+        }
+    }
+
+    /// How long `frame` occupies the bus, including interframe space and an
+    /// estimate of stuff bits.
+    pub fn duration(&self, frame: &Frame) -> Duration {
+        let (nominal, data) = bits(frame);
+        let data_bitrate = match frame.flags & frame::FD_BRS {
+            0 => self.bitrate,
+            _ => self.data_bitrate,
+        };
+        Duration::from_secs_f64(
+            nominal as f64 / self.bitrate as f64 + data as f64 / data_bitrate as f64,
+        )
+    }
+}
+
+/// Bits of `frame` sent at the nominal and at the data bitrate. Stuff bits
+/// are counted as in the worst case, one for every four bits after the
+/// first, which errs on the side of a busier bus.
+pub fn bits(frame: &Frame) -> (u64, u64) {
+    let ext = if frame.is_extended() {
+        EXTENDED_OVERHEAD
+    } else {
+        0
+    };
+    let payload = if frame.is_remote() {
+        0
+    } else {
+        8 * frame.data.len() as u64
+    };
+    let stuff = |bits: u64| bits.saturating_sub(1) / 4;
+
+    if !frame.fd {
+        let exposed = CLASSIC_STUFFED + ext + payload;
+        return (CLASSIC_OVERHEAD + ext + payload + stuff(exposed), 0);
+    }
+    // The CRC is longer above 16 bytes, and carries a fixed stuff bit every
+    // four bits
+    let crc = if frame.data.len() > 16 { 21 } else { 17 };
+    let nominal = FD_NOMINAL + ext + stuff(17 + ext);
+    let data = FD_DATA + payload + stuff(FD_DATA + payload) + crc + crc.div_ceil(4);
+    (nominal, data)
+}
+
+/// Priority of `frame` in arbitration: the lowest value wins. The bits are
+/// ordered as they go on the wire, dominant bits being zeros: the 11-bit
+/// base identifier, RTR or SRR, IDE, the 18-bit identifier extension and
+/// the RTR bit of extended frames.
+pub fn priority(frame: &Frame) -> u64 {
+    let rtr = u64::from(frame.is_remote());
+    if frame.is_extended() {
+        let id = u64::from(frame.id & EFF_MASK);
+        let base = id >> 18;
+        let extension = id & 0x3ffff;
+        base << 21 | 1 << 20 | 1 << 19 | extension << 1 | rtr
+    } else {
+        u64::from(frame.raw_id()) << 21 | rtr << 20
+    }
+}
+
+/// Load of an emulated bus, as reported by
+/// [`NetworkManager::network_inspect`](crate::NetworkManager::network_inspect).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct BusStatus {
+    #[serde(flatten)]
+    pub config: BusConfig,
+    /// Percentage of the last second the bus was busy.
+    pub load: f64,
+    /// Frames sent over the bus.
+    pub frames: u64,
+}
+
+/// A frame waiting for the bus.
+struct Pending {
+    priority: u64,
+    /// Order of arrival, breaking ties between equal identifiers.
+    seq: u64,
+    duration: Duration,
+    sent: oneshot::Sender<()>,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.priority, self.seq).cmp(&(other.priority, other.seq))
+    }
+}
+
+/// Transmissions over the last [`LOAD_WINDOW`].
+#[derive(Default)]
+struct Usage {
+    /// Start and end of recent transmissions, oldest first.
+    busy: VecDeque<(Instant, Instant)>,
+    frames: u64,
+}
+
+impl Usage {
+    fn load(&mut self, now: Instant) -> f64 {
+        let since = now.checked_sub(LOAD_WINDOW).unwrap_or(now);
+        while self.busy.front().is_some_and(|(_, end)| *end <= since) {
+            self.busy.pop_front();
+        }
+        let busy: Duration = self
+            .busy
+            .iter()
+            .map(|(start, end)| {
+                (*end)
+                    .min(now)
+                    .saturating_duration_since((*start).max(since))
+            })
+            .sum();
+        100.0 * busy.as_secs_f64() / LOAD_WINDOW.as_secs_f64()
+    }
+}
+
+/// An emulated CAN bus shared by the interfaces of a network.
+///
+/// Each interface hands the frames it forwards to [`Bus::transmit`], which
+/// returns once the frame would have been sent on a real bus at the
+/// configured bitrate. Frames waiting at the same time are sent in order of
+/// their identifiers, as arbitration does.
+pub struct Bus {
+    config: BusConfig,
+    tx: mpsc::UnboundedSender<Pending>,
+    usage: Arc<Mutex<Usage>>,
+    seq: Mutex<u64>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Bus {
+    pub fn new(config: BusConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let usage = Arc::new(Mutex::new(Usage::default()));
+        let task = tokio::spawn(arbitrate(rx, usage.clone()));
+        Bus {
+            config,
+            tx,
+            usage,
+            seq: Mutex::new(0),
+            task,
+        }
+    }
+
+    /// Wait until `frame` has been sent over the bus.
+    pub async fn transmit(&self, frame: &Frame) {
+        let (sent, done) = oneshot::channel();
+        let seq = {
+            let mut seq = self.seq.lock();
+            *seq += 1;
+            *seq
+        };
+        let pending = Pending {
+            priority: priority(frame),
+            seq,
+            duration: self.config.duration(frame),
+            sent,
+        };
+        if self.tx.send(pending).is_ok() {
+            let _ = done.await;
+        }
+    }
+
+    pub fn status(&self) -> BusStatus {
+        let mut usage = self.usage.lock();
+        BusStatus {
+            config: self.config,
+            load: usage.load(Instant::now()),
+            frames: usage.frames,
+        }
+    }
+}
+
+/// Send the waiting frames one at a time, the highest priority first.
+///
+/// Timers are too coarse to wait for single frames, so the bus keeps its own
+/// clock: while frames are waiting, each starts when the one before ends,
+/// however late the task wakes up.
+async fn arbitrate(mut rx: mpsc::UnboundedReceiver<Pending>, usage: Arc<Mutex<Usage>>) {
+    let mut waiting = BinaryHeap::new();
+    let mut free = Instant::now();
+    loop {
+        if waiting.is_empty() {
+            match rx.recv().await {
+                Some(p) => waiting.push(Reverse(p)),
+                None => return,
+            }
+            free = free.max(Instant::now());
+        }
+        while let Ok(p) = rx.try_recv() {
+            waiting.push(Reverse(p));
+        }
+        let Some(Reverse(next)) = waiting.pop() else {
+            continue;
+        };
+
+        let start = free;
+        free += next.duration;
+        {
+            let mut usage = usage.lock();
+            usage.busy.push_back((start, free));
+            usage.frames += 1;
+        }
+        if free > Instant::now() {
+            tokio::time::sleep_until(free).await;
+        }
+        let _ = next.sent.send(());
+    }
+}
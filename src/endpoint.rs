@@ -24,90 +24,97 @@
  * SOFTWARE.
  */
 
+use crate::backend::LinkBackend;
+use crate::error::Error;
+use crate::fault::Faults;
+use crate::kernel;
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
 use truncrate::*;
 
-#[derive(Clone)]
+/// A container's connection to a network: one VXCAN tunnel whose host side
+/// is bridged to the network by forwarding rules.
 pub struct Endpoint {
     pub uid: String,
+    /// Host side of the tunnel.
     pub device: String,
+    /// Container side of the tunnel, as named when it was created.
     pub peer: String,
+    /// Network namespace the endpoint is attached to, if any.
+    pub namespace: Option<String>,
+    /// Faults injected into the frames to and from the endpoint.
+    pub faults: Faults,
+    /// Whether the endpoint only receives the network's frames, none of its
+    /// own being forwarded.
+    pub tap: bool,
+    /// Group of the endpoints it exchanges frames with, if any.
+    pub group: Option<String>,
     created: bool,
+    links: Arc<dyn LinkBackend>,
 }
 
 impl Endpoint {
-    pub fn new(uid: String) -> Self {
-        println!("Creating a new endpoint: {uid}");
-        let ifcs = interfaces::Interface::get_all().unwrap();
+    /// Create the tunnel for endpoint `uid`, reusing an existing interface of
+    /// the same name.
+    pub async fn create(uid: String, links: Arc<dyn LinkBackend>) -> Result<Self, Error> {
+        debug!(endpoint = %uid, "creating endpoint");
 
-        let mut exists: bool = false;
         let newifc = format!("vxcan{}", uid.truncate_to_byte_offset(8));
         let peerifc = format!("{newifc}p");
-
-        for i in ifcs.into_iter() {
-            if i.name.eq(&newifc) {
-                exists = true;
-            }
-        }
+        let exists = links.link_exists(&newifc).await?;
 
         if !exists {
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("add")
-                .arg("dev")
-                .arg(&newifc)
-                .arg("type")
-                .arg("vxcan")
-                .arg("peer")
-                .arg("name")
-                .arg(&peerifc)
-                .output()
-                .expect("failed to add VXCAN device");
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("set")
-                .arg("up")
-                .arg(&newifc)
-                .output()
-                .expect("failed to start VXCAN device");
+            links.vxcan_add(&newifc, &peerifc).await?;
         }
-        println!(
-            "Creating VXCAN tunnel with settings: device='{}', peer='{}'",
-            newifc, peerifc
-        );
-        Endpoint {
-            uid: uid,
+        info!(device = %newifc, peer = %peerifc, existing = exists, "created VXCAN tunnel");
+        Ok(Endpoint {
+            uid,
             device: newifc,
             peer: peerifc,
+            namespace: None,
+            faults: Faults::default(),
+            tap: false,
+            group: None,
             created: !exists,
+            links,
+        })
+    }
+
+    /// Remove the VXCAN tunnel if this endpoint created it.
+    pub async fn destroy(&mut self) -> Result<(), Error> {
+        if self.created {
+            let links = &self.links;
+            let dev = self.device.as_str();
+            kernel::retry(|| links.link_del(dev)).await?;
+            self.created = false;
+
+            info!(device = %self.device, peer = %self.peer, "destroyed VXCAN tunnel");
         }
+        Ok(())
     }
 }
 
 impl Drop for Endpoint {
     fn drop(&mut self) {
         if self.created {
-            // Actually delete the network interface
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("set")
-                .arg("down")
-                .arg(&self.device)
-                .output()
-                .expect("failed to start VCAN device");
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("del")
-                .arg("dev")
-                .arg(&self.device)
-                .arg("type")
-                .arg("vxcan")
-                .output()
-                .expect("failed to remove VCAN device");
-
-            println!(
-                "Dropping Endpoint object with {}, {}",
-                self.device, self.peer,
+            // Only reached if destroy() was never called or failed
+            warn!(
+                endpoint = %self.uid,
+                device = %self.device,
+                "endpoint dropped without being destroyed, removing its tunnel"
             );
+            match tokio::runtime::Handle::try_current() {
+                Ok(rt) => {
+                    let links = self.links.clone();
+                    let dev = self.device.clone();
+                    rt.spawn(async move {
+                        if let Err(e) = links.link_del(&dev).await {
+                            error!(error = %e, "unable to remove {dev}");
+                        }
+                    });
+                }
+                Err(_) => error!(device = %self.device, "no runtime left to remove the tunnel"),
+            }
         }
     }
 }
@@ -0,0 +1,92 @@
+/*
+ * Filename: error.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::fmt;
+
+/// Errors reported back to Docker in the `Err` field of a plugin response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A network or endpoint option was unknown, malformed or out of range.
+    InvalidOption(String),
+    /// The host CAN device already backs another network.
+    DeviceInUse(String),
+    /// A kernel operation (`ip`, `cangw`) failed.
+    Kernel {
+        command: String,
+        reason: String,
+        /// Whether the operation may succeed if attempted again.
+        transient: bool,
+    },
+    /// No network with the given ID is known to the plugin.
+    NetworkNotFound(String),
+    /// The task serving the network on the given device has stopped.
+    NetworkStopped(String),
+    /// No endpoint with the given ID exists on the network.
+    EndpointNotFound(String),
+    /// The kernel or the host lacks a feature the plugin needs.
+    Unsupported(String),
+    /// The network is already doing what was asked for, e.g. recording.
+    Busy(String),
+    /// Reading or writing a file failed.
+    Io(String),
+}
+
+impl Error {
+    /// Short, stable name of the kind of error, e.g. for metric labels.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::InvalidOption(_) => "invalid_option",
+            Error::DeviceInUse(_) => "device_in_use",
+            Error::Kernel { .. } => "kernel",
+            Error::NetworkNotFound(_) => "network_not_found",
+            Error::NetworkStopped(_) => "network_stopped",
+            Error::EndpointNotFound(_) => "endpoint_not_found",
+            Error::Unsupported(_) => "unsupported",
+            Error::Busy(_) => "busy",
+            Error::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidOption(msg) => write!(f, "invalid option: {msg}"),
+            Error::DeviceInUse(msg) => write!(f, "{msg}"),
+            Error::Kernel {
+                command, reason, ..
+            } => write!(f, "'{command}' failed: {reason}"),
+            Error::NetworkNotFound(uid) => write!(f, "network {uid} not found"),
+            Error::NetworkStopped(ifc) => write!(f, "network on {ifc} is shutting down"),
+            Error::EndpointNotFound(uid) => write!(f, "endpoint {uid} not found"),
+            Error::Unsupported(msg) => write!(f, "{msg}"),
+            Error::Busy(msg) => write!(f, "{msg}"),
+            Error::Io(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
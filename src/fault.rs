@@ -0,0 +1,147 @@
+/*
+ * Filename: fault.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Degradation of the frames forwarded to and from an endpoint, for testing
+//! how software copes with a poor bus.
+
+use crate::error::Error;
+use crate::frame::Frame;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Largest delay and jitter in milliseconds.
+pub const DELAY_MAX: u64 = 60_000;
+
+/// Faults injected into the frames of a route, as set with the
+/// `vxcan.drop`, `vxcan.delay`, `vxcan.jitter`, `vxcan.corrupt`,
+/// `vxcan.duplicate` and `vxcan.reorder` endpoint options. Percentages are
+/// chances per frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Faults {
+    /// Percentage of frames lost.
+    pub drop: f64,
+    /// Milliseconds every frame is held back.
+    pub delay: u64,
+    /// Further milliseconds, up to this many, a frame is held back at
+    /// random. Frames still leave in the order they arrived, as on a bus.
+    pub jitter: u64,
+    /// Percentage of frames with one bit of their payload flipped.
+    pub corrupt: f64,
+    /// Percentage of frames delivered twice.
+    pub duplicate: f64,
+    /// Percentage of frames delivered after the frame following them.
+    pub reorder: f64,
+}
+
+/// What becomes of one frame, decided by [`Faults::apply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fate {
+    /// How often the frame is delivered: 0 if it is lost, 2 if duplicated.
+    pub copies: usize,
+    /// How long the frame is held back.
+    pub delay: Duration,
+    /// Whether the frame swaps places with the next one.
+    pub reorder: bool,
+}
+
+impl Faults {
+    /// Check that percentages lie within 0-100 and delays within
+    /// [`DELAY_MAX`].
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, value) in [
+            ("drop", self.drop),
+            ("corrupt", self.corrupt),
+            ("duplicate", self.duplicate),
+            ("reorder", self.reorder),
+        ] {
+            if !(0.0..=100.0).contains(&value) {
+                return Err(Error::InvalidOption(format!(
+                    "{name} must be a percentage between 0 and 100, not {value}"
+                )));
+            }
+        }
+        for (name, value) in [("delay", self.delay), ("jitter", self.jitter)] {
+            if value > DELAY_MAX {
+                return Err(Error::InvalidOption(format!(
+                    "{name} must be at most {DELAY_MAX} ms, not {value}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether frames pass untouched.
+    pub fn is_none(&self) -> bool {
+        *self == Faults::default()
+    }
+
+    /// The faults of a frame going through `self` and then `next`, e.g.
+    /// from one faulty endpoint to another.
+    pub fn then(&self, next: &Faults) -> Faults {
+        // Chance that either of two independent events happens
+        let either = |a: f64, b: f64| 100.0 - (100.0 - a) * (100.0 - b) / 100.0;
+        Faults {
+            drop: either(self.drop, next.drop),
+            delay: self.delay + next.delay,
+            jitter: self.jitter + next.jitter,
+            corrupt: either(self.corrupt, next.corrupt),
+            duplicate: either(self.duplicate, next.duplicate),
+            reorder: either(self.reorder, next.reorder),
+        }
+    }
+
+    /// Decide what becomes of `frame`, corrupting it if that is its fate.
+    pub fn apply(&self, frame: &mut Frame, rng: &mut impl Rng) -> Fate {
+        if chance(rng, self.drop) {
+            return Fate {
+                copies: 0,
+                delay: Duration::ZERO,
+                reorder: false,
+            };
+        }
+        // Frames without payload, such as remote requests, stay intact
+        if !frame.data.is_empty() && chance(rng, self.corrupt) {
+            let bit = rng.gen_range(0..frame.data.len() * 8);
+            frame.data[bit / 8] ^= 1 << (bit % 8);
+        }
+        let jitter = match self.jitter {
+            0 => 0,
+            j => rng.gen_range(0..=j),
+        };
+        Fate {
+            copies: if chance(rng, self.duplicate) { 2 } else { 1 },
+            delay: Duration::from_millis(self.delay + jitter),
+            reorder: chance(rng, self.reorder),
+        }
+    }
+}
+
+/// Whether an event with a chance of `percent` happens.
+fn chance(rng: &mut impl Rng, percent: f64) -> bool {
+    rng.gen_bool((percent / 100.0).clamp(0.0, 1.0))
+}
@@ -0,0 +1,273 @@
+/*
+ * Filename: frame.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! CAN frames as SocketCAN represents them, and their candump text form.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Identifier flag of frames with a 29-bit identifier.
+pub const EFF_FLAG: u32 = 0x8000_0000;
+/// Identifier flag of remote transmission requests.
+pub const RTR_FLAG: u32 = 0x4000_0000;
+/// Identifier flag of error frames.
+pub const ERR_FLAG: u32 = 0x2000_0000;
+/// Bits of a 11-bit identifier.
+pub const SFF_MASK: u32 = 0x0000_07ff;
+/// Bits of a 29-bit identifier.
+pub const EFF_MASK: u32 = 0x1fff_ffff;
+/// Bits of the error class of an error frame.
+pub const ERR_MASK: u32 = 0x1fff_ffff;
+
+/// Bit rate switch flag of a CAN FD frame.
+pub const FD_BRS: u8 = 0x01;
+/// Error state indicator flag of a CAN FD frame.
+pub const FD_ESI: u8 = 0x02;
+
+/// Largest payload of a classic frame.
+pub const CAN_MAX_LEN: usize = 8;
+/// Largest payload of a CAN FD frame.
+pub const CANFD_MAX_LEN: usize = 64;
+
+/// A classic CAN, CAN FD or error frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Identifier, including the [`EFF_FLAG`], [`RTR_FLAG`] and [`ERR_FLAG`]
+    /// bits.
+    pub id: u32,
+    /// Payload. For a remote transmission request only its length, the
+    /// requested DLC, is meaningful.
+    pub data: Vec<u8>,
+    /// Whether this is a CAN FD frame.
+    pub fd: bool,
+    /// [`FD_BRS`] and [`FD_ESI`] of a CAN FD frame.
+    pub flags: u8,
+}
+
+/// A frame that could not be parsed or does not fit on the bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameError(pub String);
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl Frame {
+    /// Identifier without flags: 11 or 29 bits, or the error class.
+    pub fn raw_id(&self) -> u32 {
+        if self.is_error() {
+            self.id & ERR_MASK
+        } else if self.is_extended() {
+            self.id & EFF_MASK
+        } else {
+            self.id & SFF_MASK
+        }
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.id & EFF_FLAG != 0
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.id & RTR_FLAG != 0
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.id & ERR_FLAG != 0
+    }
+
+    /// Check that the payload fits the kind of frame and, for CAN FD, is
+    /// one of the lengths a DLC can express.
+    pub fn validate(&self) -> Result<(), FrameError> {
+        let len = self.data.len();
+        if self.fd {
+            if self.is_remote() || self.is_error() {
+                return Err(FrameError(String::from(
+                    "CAN FD frames cannot be remote or error frames",
+                )));
+            }
+            if len > CANFD_MAX_LEN || fd_len(dlc(len)) != len {
+                return Err(FrameError(format!("{len} is not a valid CAN FD length")));
+            }
+        } else if len > CAN_MAX_LEN {
+            return Err(FrameError(format!(
+                "classic frames carry at most {CAN_MAX_LEN} bytes, not {len}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// The frame as candump prints it, e.g. `123#DEADBEEF`,
+    /// `12345678#R`, or `123##1A0` for CAN FD.
+    pub fn to_candump(&self) -> String {
+        let mut s = if self.is_error() {
+            format!("{:08X}", self.id & (ERR_MASK | ERR_FLAG))
+        } else if self.is_extended() {
+            format!("{:08X}", self.raw_id())
+        } else {
+            format!("{:03X}", self.raw_id())
+        };
+        s.push('#');
+        if self.fd {
+            s.push_str(&format!("#{:X}", self.flags & 0x0f));
+        } else if self.is_remote() {
+            s.push('R');
+            if !self.data.is_empty() {
+                s.push_str(&format!("{}", self.data.len()));
+            }
+            return s;
+        }
+        for b in &self.data {
+            s.push_str(&format!("{b:02X}"));
+        }
+        s
+    }
+
+    /// Parse a frame in the form written by [`Self::to_candump`]. Three
+    /// identifier digits make an 11-bit frame, eight a 29-bit or error
+    /// frame; data bytes may be separated by dots.
+    pub fn parse_candump(s: &str) -> Result<Self, FrameError> {
+        let bad = |why: &str| FrameError(format!("'{s}' is not a CAN frame: {why}"));
+
+        let (id, rest) = s.split_once('#').ok_or_else(|| bad("missing '#'"))?;
+        let raw = u32::from_str_radix(id, 16).map_err(|_| bad("invalid identifier"))?;
+        let id = match id.len() {
+            3 if raw <= SFF_MASK => raw,
+            8 if raw & ERR_FLAG != 0 => raw & (ERR_MASK | ERR_FLAG),
+            8 if raw <= EFF_MASK => raw | EFF_FLAG,
+            _ => return Err(bad("identifier must have 3 or 8 hex digits")),
+        };
+
+        let mut frame = Frame {
+            id,
+            data: Vec::new(),
+            fd: false,
+            flags: 0,
+        };
+        let data = if let Some(fd) = rest.strip_prefix('#') {
+            let mut chars = fd.chars();
+            let flags = chars
+                .next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| bad("missing CAN FD flags"))?;
+            frame.fd = true;
+            frame.flags = flags as u8;
+            chars.as_str()
+        } else if let Some(rtr) = rest.strip_prefix(['R', 'r']) {
+            frame.id |= RTR_FLAG;
+            let len = match rtr {
+                "" => 0,
+                n => n
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n <= CAN_MAX_LEN)
+                    .ok_or_else(|| bad("invalid remote request length"))?,
+            };
+            frame.data = vec![0; len];
+            return Ok(frame);
+        } else {
+            // A trailing `_` and DLC only matter for lengths above 8
+            rest.split_once('_').map_or(rest, |(data, _)| data)
+        };
+
+        let digits: Vec<u8> = data.bytes().filter(|b| *b != b'.').collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err(bad("odd number of data digits"));
+        }
+        for pair in digits.chunks(2) {
+            let hex = std::str::from_utf8(pair).map_err(|_| bad("invalid data"))?;
+            frame
+                .data
+                .push(u8::from_str_radix(hex, 16).map_err(|_| bad("invalid data"))?);
+        }
+        frame.validate().map_err(|e| bad(&e.0))?;
+        Ok(frame)
+    }
+}
+
+/// Smallest DLC describing a payload of `len` bytes.
+pub fn dlc(len: usize) -> usize {
+    match len {
+        0..=8 => len,
+        9..=12 => 9,
+        13..=16 => 10,
+        17..=20 => 11,
+        21..=24 => 12,
+        25..=32 => 13,
+        33..=48 => 14,
+        _ => 15,
+    }
+}
+
+/// Payload length of a CAN FD frame with the given DLC.
+pub fn fd_len(dlc: usize) -> usize {
+    const LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+    LENGTHS[dlc.min(15)]
+}
+
+/// One line of a `candump -l` log: `(seconds.micros) interface frame`.
+pub fn candump_line(time: SystemTime, ifc: &str, frame: &Frame) -> String {
+    let t = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "({}.{:06}) {ifc} {}",
+        t.as_secs(),
+        t.subsec_micros(),
+        frame.to_candump()
+    )
+}
+
+/// Parse a line of a `candump -l` log into its timestamp, interface and
+/// frame. Anything after the frame, such as a direction, is ignored.
+pub fn parse_candump_line(line: &str) -> Result<(Duration, String, Frame), FrameError> {
+    let bad = |why: &str| FrameError(format!("'{line}' is not a candump log line: {why}"));
+
+    let mut fields = line.split_whitespace();
+    let time = fields
+        .next()
+        .and_then(|t| t.strip_prefix('('))
+        .and_then(|t| t.strip_suffix(')'))
+        .ok_or_else(|| bad("missing timestamp"))?;
+    let (secs, frac) = time.split_once('.').unwrap_or((time, "0"));
+    let secs: u64 = secs.parse().map_err(|_| bad("invalid timestamp"))?;
+    // Scale the fraction to nanoseconds, whatever its number of digits
+    let digits = frac.len().min(9);
+    let frac: u64 = frac[..digits]
+        .parse()
+        .map_err(|_| bad("invalid timestamp"))?;
+    let nanos = frac * 10u64.pow((9 - digits) as u32);
+
+    let ifc = fields.next().ok_or_else(|| bad("missing interface"))?;
+    let frame = fields.next().ok_or_else(|| bad("missing frame"))?;
+    Ok((
+        Duration::new(secs, nanos as u32),
+        ifc.to_string(),
+        Frame::parse_candump(frame)?,
+    ))
+}
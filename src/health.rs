@@ -0,0 +1,146 @@
+/*
+ * Filename: health.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::manager::NetworkManager;
+use bollard::Docker;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Outcome of one self-check.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    /// Why the check failed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub problems: Vec<String>,
+}
+
+/// Result of the liveness checks; the plugin is healthy if every check
+/// passed.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Health {
+    pub healthy: bool,
+    pub checks: Vec<Check>,
+}
+
+/// Result of the readiness checks; the plugin is ready to serve Docker if
+/// every check passed.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Check {
+    fn new(name: &'static str, problems: Vec<String>) -> Self {
+        Check {
+            name,
+            ok: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+/// Run the liveness checks of the plugin, which fail if the networks it
+/// serves are broken:
+///
+/// - `kernel`: vcan and vxcan support and the `ip` tool, and a way to
+///   forward frames (can-gw and `cangw`, or raw sockets),
+/// - `state`: the interfaces and rules of every network exist in the kernel,
+///   and no stale rules use an endpoint's tunnel.
+pub async fn check(mgr: &NetworkManager) -> Health {
+    let state = match mgr.kernel_verify().await {
+        Ok(problems) => problems,
+        Err(e) => vec![format!("unable to read kernel state: {e}")],
+    };
+
+    let checks = vec![
+        Check::new("kernel", kernel(mgr).await),
+        Check::new("state", state),
+    ];
+    Health {
+        healthy: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
+
+/// Run the readiness checks of the plugin, which fail while it cannot serve
+/// new requests:
+///
+/// - `kernel`: as for [`check`],
+/// - `docker`: the Engine API answers a ping (fails without a connection),
+/// - `storage`: the plugin can write to each of `dirs`, e.g. the directory
+///   of the admin socket, and to the directory of every running recording.
+///
+/// Networks are restored from Docker, so the plugin keeps no state file of
+/// its own to check.
+pub async fn ready(mgr: &NetworkManager, docker: Option<&Docker>, dirs: &[PathBuf]) -> Readiness {
+    let docker = match docker {
+        Some(d) => match d.ping().await {
+            Ok(_) => Vec::new(),
+            Err(e) => vec![format!("docker is not reachable: {e}")],
+        },
+        None => vec![String::from("not connected to docker")],
+    };
+
+    let mut dirs = dirs.to_vec();
+    for nid in mgr.networks() {
+        let recording = match mgr.network_inspect(nid).await {
+            Ok(info) => info.recording,
+            Err(_) => None,
+        };
+        if let Some(r) = recording.filter(|r| r.error.is_none()) {
+            dirs.push(r.config.path);
+        }
+    }
+    let storage = dirs.iter().filter_map(|d| writable(d).err()).collect();
+
+    let checks = vec![
+        Check::new("kernel", kernel(mgr).await),
+        Check::new("docker", docker),
+        Check::new("storage", storage),
+    ];
+    Readiness {
+        ready: checks.iter().all(|c| c.ok),
+        checks,
+    }
+}
+
+async fn kernel(mgr: &NetworkManager) -> Vec<String> {
+    match mgr.kernel_support().await {
+        Ok(()) => Vec::new(),
+        Err(e) => vec![e.to_string()],
+    }
+}
+
+/// Check that files can be created in `dir` by creating and removing one.
+fn writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(format!(".rustyvxcan-ready-{}", std::process::id()));
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} is not writable: {e}", dir.display()))
+}
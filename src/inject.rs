@@ -0,0 +1,359 @@
+/*
+ * Filename: inject.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Injection of error frames, which virtual CAN devices never produce.
+
+use crate::backend::FrameSocket;
+use crate::error::Error;
+use crate::frame::{Frame, CAN_MAX_LEN, ERR_FLAG};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn, Instrument};
+
+/// Error classes of an error frame, the bits of its identifier as defined
+/// in `linux/can/error.h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorClass {
+    TxTimeout,
+    LostArbitration,
+    /// Controller problems, detailed in data byte 1.
+    Controller,
+    /// Protocol violations, detailed in data bytes 2 and 3.
+    Protocol,
+    /// Transceiver status, in data byte 4.
+    Transceiver,
+    Ack,
+    BusOff,
+    BusError,
+    /// The controller was restarted after bus-off.
+    Restarted,
+    /// Error counters in data bytes 6 (transmit) and 7 (receive).
+    Counters,
+}
+
+impl ErrorClass {
+    pub fn bit(&self) -> u32 {
+        match self {
+            ErrorClass::TxTimeout => 0x001,
+            ErrorClass::LostArbitration => 0x002,
+            ErrorClass::Controller => 0x004,
+            ErrorClass::Protocol => 0x008,
+            ErrorClass::Transceiver => 0x010,
+            ErrorClass::Ack => 0x020,
+            ErrorClass::BusOff => 0x040,
+            ErrorClass::BusError => 0x080,
+            ErrorClass::Restarted => 0x100,
+            ErrorClass::Counters => 0x200,
+        }
+    }
+}
+
+/// Controller states reported the way SocketCAN drivers report them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorState {
+    /// Error counters back below 96 (only reported by recent kernels).
+    ErrorActive,
+    /// Error counters reached 96.
+    ErrorWarning,
+    /// Error counters reached 128.
+    ErrorPassive,
+    /// The transmit error counter passed 255 and the controller went off
+    /// the bus.
+    BusOff,
+    /// The controller was restarted after bus-off and is active again.
+    Restarted,
+}
+
+impl ErrorState {
+    /// Error classes and data of the frame reporting the state.
+    fn frame(&self) -> (Vec<ErrorClass>, [u8; CAN_MAX_LEN]) {
+        use ErrorClass::*;
+
+        // Data byte 1 flags of controller problems
+        const RX_WARNING: u8 = 0x04;
+        const TX_WARNING: u8 = 0x08;
+        const RX_PASSIVE: u8 = 0x10;
+        const TX_PASSIVE: u8 = 0x20;
+        const ACTIVE: u8 = 0x40;
+
+        match self {
+            ErrorState::ErrorActive => (vec![Controller, Counters], [0, ACTIVE, 0, 0, 0, 0, 0, 0]),
+            ErrorState::ErrorWarning => (
+                vec![Controller, Counters],
+                [0, RX_WARNING | TX_WARNING, 0, 0, 0, 0, 96, 96],
+            ),
+            ErrorState::ErrorPassive => (
+                vec![Controller, Counters],
+                [0, RX_PASSIVE | TX_PASSIVE, 0, 0, 0, 0, 128, 128],
+            ),
+            ErrorState::BusOff => (vec![BusOff], [0; CAN_MAX_LEN]),
+            ErrorState::Restarted => (vec![Restarted, Controller], [0, ACTIVE, 0, 0, 0, 0, 0, 0]),
+        }
+    }
+}
+
+/// One error frame of a sequence, sent `repeat` times.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorStep {
+    /// Milliseconds to wait before each time the frame is sent.
+    #[serde(default)]
+    pub delay: u64,
+    /// Controller state the frame reports, setting its classes and data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<ErrorState>,
+    /// Error classes of the frame, added to those of `state`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub class: Vec<ErrorClass>,
+    /// Data bytes of the frame, replacing those of `state`; missing bytes
+    /// are 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Vec<u8>>,
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+}
+
+fn default_repeat() -> u32 {
+    1
+}
+
+impl ErrorStep {
+    /// A step reporting `state` right away.
+    pub fn state(state: ErrorState) -> Self {
+        ErrorStep {
+            delay: 0,
+            state: Some(state),
+            class: Vec::new(),
+            data: None,
+            repeat: default_repeat(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.state.is_none() && self.class.is_empty() {
+            return Err(Error::InvalidOption(String::from(
+                "an error frame needs a state or at least one error class",
+            )));
+        }
+        if self.data.as_ref().is_some_and(|d| d.len() > CAN_MAX_LEN) {
+            return Err(Error::InvalidOption(format!(
+                "error frames carry {CAN_MAX_LEN} data bytes"
+            )));
+        }
+        Ok(())
+    }
+
+    /// The error frame this step sends.
+    pub fn frame(&self) -> Frame {
+        let (mut classes, mut data) = match self.state {
+            Some(state) => state.frame(),
+            None => (Vec::new(), [0; CAN_MAX_LEN]),
+        };
+        classes.extend(&self.class);
+        if let Some(bytes) = &self.data {
+            data = [0; CAN_MAX_LEN];
+            data[..bytes.len()].copy_from_slice(bytes);
+        }
+        Frame {
+            id: ERR_FLAG | classes.iter().fold(0, |id, c| id | c.bit()),
+            data: data.to_vec(),
+            fd: false,
+            flags: 0,
+        }
+    }
+}
+
+/// Error frames to inject into a network, and where.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InjectConfig {
+    /// Endpoint whose container alone receives the frames. Without one,
+    /// they go to the host device and to every endpoint of the network when
+    /// the injection starts.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Frames to send, in order.
+    pub sequence: Vec<ErrorStep>,
+    /// Times to run through the sequence; 0 repeats it until stopped.
+    #[serde(default = "default_repeat")]
+    pub loops: u32,
+}
+
+impl InjectConfig {
+    /// Send the frames of `sequence` once to the whole network.
+    pub fn new(sequence: Vec<ErrorStep>) -> Self {
+        InjectConfig {
+            endpoint: None,
+            sequence,
+            loops: default_repeat(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.sequence.is_empty() {
+            return Err(Error::InvalidOption(String::from(
+                "the sequence of error frames is empty",
+            )));
+        }
+        if self.loops == 0 && self.sequence.iter().all(|s| s.delay == 0) {
+            return Err(Error::InvalidOption(String::from(
+                "a sequence repeated until stopped needs a delay",
+            )));
+        }
+        self.sequence.iter().try_for_each(ErrorStep::validate)
+    }
+}
+
+/// Progress of an injection.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InjectStatus {
+    #[serde(flatten)]
+    pub config: InjectConfig,
+    /// Interfaces the frames are sent on.
+    pub interfaces: Vec<String>,
+    /// Whether frames are still to be sent.
+    pub running: bool,
+    /// Error frames sent so far, counted once however many interfaces they
+    /// went to.
+    pub frames: u64,
+    /// Why the injection stopped early, if it did.
+    pub error: Option<String>,
+}
+
+/// Sends a sequence of error frames onto interfaces until done or stopped.
+pub struct Injection {
+    status: Arc<Mutex<InjectStatus>>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Injection {
+    /// Send the sequence of `config` through `sockets`, one per interface,
+    /// in a task of its own. Frames sent on the host side of a VXCAN
+    /// tunnel reach the container.
+    pub fn start(
+        sockets: Vec<(String, Arc<dyn FrameSocket>)>,
+        config: InjectConfig,
+    ) -> Result<Self, Error> {
+        config.validate()?;
+        let interfaces: Vec<String> = sockets.iter().map(|(ifc, _)| ifc.clone()).collect();
+        info!(
+            ?interfaces,
+            steps = config.sequence.len(),
+            "injecting error frames"
+        );
+
+        let status = Arc::new(Mutex::new(InjectStatus {
+            config,
+            interfaces,
+            running: true,
+            frames: 0,
+            error: None,
+        }));
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(
+            run(sockets, status.clone(), stopped).instrument(tracing::info_span!("inject")),
+        );
+        Ok(Injection { status, stop, task })
+    }
+
+    /// Whether frames are still to be sent.
+    pub fn running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn status(&self) -> InjectStatus {
+        self.status.lock().clone()
+    }
+
+    /// Stop sending frames.
+    pub async fn stop(self) -> InjectStatus {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+        let status = self.status.lock().clone();
+        info!(frames = status.frames, "stopped injecting error frames");
+        status
+    }
+}
+
+async fn run(
+    sockets: Vec<(String, Arc<dyn FrameSocket>)>,
+    status: Arc<Mutex<InjectStatus>>,
+    stopped: oneshot::Receiver<()>,
+) {
+    let res = tokio::select! {
+        // Also ends the injection if it was dropped
+        _ = stopped => Ok(()),
+        res = inject(sockets, &status) => res,
+    };
+    let mut status = status.lock();
+    status.running = false;
+    if let Err(e) = res {
+        error!(error = %e, "error frame injection failed");
+        status.error = Some(e.to_string());
+    }
+}
+
+async fn inject(
+    mut sockets: Vec<(String, Arc<dyn FrameSocket>)>,
+    status: &Mutex<InjectStatus>,
+) -> Result<(), Error> {
+    let config = status.lock().config.clone();
+    let mut pass = 0;
+    while config.loops == 0 || pass < config.loops {
+        pass += 1;
+        for step in &config.sequence {
+            let frame = step.frame();
+            for _ in 0..step.repeat {
+                tokio::time::sleep(Duration::from_millis(step.delay)).await;
+                // An endpoint may go away meanwhile; keep serving the others
+                let mut failed = None;
+                let mut alive = Vec::with_capacity(sockets.len());
+                for (ifc, socket) in sockets {
+                    match socket.send(&frame).await {
+                        Ok(()) => alive.push((ifc, socket)),
+                        Err(e) => {
+                            warn!(interface = %ifc, error = %e, "unable to inject error frame");
+                            failed = Some(e);
+                        }
+                    }
+                }
+                sockets = alive;
+                if let (true, Some(e)) = (sockets.is_empty(), failed) {
+                    return Err(e);
+                }
+                status.lock().frames += 1;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+/*
+ * Filename: kernel.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::error::Error;
+use std::future::Future;
+use std::process::Output;
+use std::time::Duration;
+
+/// How often a failed teardown step is attempted before giving up.
+const RETRY_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry; doubled for each further attempt.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Run `program` with `args` without blocking the runtime, failing if it
+/// cannot be started or exits non-zero.
+pub fn run(program: &str, args: &[&str]) -> impl Future<Output = Result<(), Error>> {
+    let op = output(program, args);
+    async move { op.await.map(|_| ()) }
+}
+
+/// Like [`run`], returning what the command printed to stdout.
+pub fn output(program: &str, args: &[&str]) -> impl Future<Output = Result<String, Error>> {
+    let command = format!("{program} {}", args.join(" "));
+    let mut cmd = tokio::process::Command::new(program);
+    cmd.args(args);
+    async move {
+        let output = cmd.output().await;
+        let output = check(command, output)?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Like [`run`] for commands removing kernel objects: an object that is
+/// already gone counts as removed.
+pub fn remove(program: &str, args: &[&str]) -> impl Future<Output = Result<(), Error>> {
+    let op = run(program, args);
+    async move { ignore_missing(op.await) }
+}
+
+/// Retry `op` with a growing delay while it fails with a transient error.
+pub async fn retry<F, Fut>(mut op: F) -> Result<(), Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(
+                e @ Error::Kernel {
+                    transient: true, ..
+                },
+            ) if attempt < RETRY_ATTEMPTS => {
+                tracing::warn!(error = %e, delay_ms = delay.as_millis() as u64, "retrying");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            r => return r,
+        }
+    }
+}
+
+fn check(command: String, output: std::io::Result<Output>) -> Result<Output, Error> {
+    let output = output.map_err(|e| error(&command, e.to_string()))?;
+    if output.status.success() {
+        return Ok(output);
+    }
+
+    let reason = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    let transient = reason.contains("Device or resource busy")
+        || reason.contains("Resource temporarily unavailable")
+        || reason.contains("No buffer space available");
+    Err(Error::Kernel {
+        command,
+        reason,
+        transient,
+    })
+}
+
+fn ignore_missing(r: Result<(), Error>) -> Result<(), Error> {
+    match r {
+        Err(Error::Kernel { reason, .. })
+            if reason.contains("Cannot find device")
+                || reason.contains("No such device")
+                || reason.contains("No such file or directory") =>
+        {
+            Ok(())
+        }
+        r => r,
+    }
+}
+
+/// Fail unless the kernel module `module` is loaded, built in or can be
+/// loaded by `modprobe`. `config` names the kernel option providing it.
+pub async fn module_available(module: &str, config: &str) -> Result<(), Error> {
+    // Loaded modules, and built-in ones with parameters, show up in sysfs
+    let sysfs = format!("/sys/module/{}", module.replace('-', "_"));
+    if tokio::fs::metadata(&sysfs).await.is_ok() {
+        return Ok(());
+    }
+
+    if let Ok(release) = tokio::fs::read_to_string("/proc/sys/kernel/osrelease").await {
+        let builtin = format!("/lib/modules/{}/modules.builtin", release.trim());
+        if let Ok(list) = tokio::fs::read_to_string(&builtin).await {
+            let ko = format!("/{module}.ko");
+            if list.lines().any(|l| l.ends_with(&ko)) {
+                return Ok(());
+            }
+        }
+    }
+
+    match run("modprobe", &["--dry-run", "--quiet", module]).await {
+        Ok(()) => Ok(()),
+        Err(_) => Err(Error::Unsupported(format!(
+            "kernel module '{module}' is not available: load it with 'modprobe {module}' or build a kernel with {config}"
+        ))),
+    }
+}
+
+/// Fail unless `program` can be started.
+pub async fn installed(program: &str, package: &str) -> Result<(), Error> {
+    // Without arguments the tools print their usage, which is all we need
+    match tokio::process::Command::new(program)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await
+    {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::Unsupported(format!(
+            "'{program}' was not found: install {package}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A non-transient kernel error for `command`.
+pub fn error(command: &str, reason: String) -> Error {
+    Error::Kernel {
+        command: command.to_string(),
+        reason,
+        transient: false,
+    }
+}
@@ -0,0 +1,90 @@
+/*
+ * Filename: lib.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! VXCAN topology engine behind the `rustyvxcan` Docker network plugin.
+//!
+//! A [`NetworkManager`] keeps a set of CAN networks, each backed by a host
+//! CAN device (a `vcan` it creates, or an existing interface). Every
+//! endpoint of a network is a VXCAN tunnel whose host side is connected to
+//! the device and to every other endpoint by can-gw rules; the other side is
+//! moved into a container's network namespace.
+//!
+//! The Docker plugin in [`api`] is one user of this API. Other programs can
+//! attach network namespaces directly:
+//!
+//! ```
+//! use rustycan4docker::backend::fake::FakeBackend;
+//! use rustycan4docker::{Backend, NetworkManager, NetworkOptions};
+//! use std::sync::Arc;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), rustycan4docker::Error> {
+//! // Use NetworkManager::new() to drive the real kernel
+//! let fake = Arc::new(FakeBackend::new());
+//! let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake));
+//!
+//! mgr.network_add("bus".into(), NetworkOptions::default()).await?;
+//! mgr.namespace_attach("bus".into(), "ecu1".into(), "/run/netns/ecu1".into(), "can0".into())
+//!     .await?;
+//!
+//! let info = mgr.network_inspect("bus".into()).await?;
+//! assert_eq!(info.interface, "vcan0");
+//! assert_eq!(info.endpoints[0].namespace.as_deref(), Some("/run/netns/ecu1"));
+//!
+//! mgr.namespace_detach("bus".into(), "ecu1".into()).await?;
+//! mgr.network_delete("bus".into()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod admin;
+pub mod api;
+pub mod backend;
+pub mod bus;
+pub mod endpoint;
+pub mod error;
+pub mod fault;
+pub mod frame;
+pub mod health;
+pub mod inject;
+mod kernel;
+pub mod logging;
+pub mod manager;
+pub mod metrics;
+pub mod monitor;
+pub mod network;
+pub mod options;
+pub mod recorder;
+pub mod replay;
+pub mod stats;
+pub mod tunnel;
+
+pub use backend::Backend;
+pub use endpoint::Endpoint;
+pub use error::Error;
+pub use manager::NetworkManager;
+pub use network::{EndpointInfo, JoinResponse, Network, NetworkHandle, NetworkInfo};
+pub use options::NetworkOptions;
@@ -0,0 +1,118 @@
+/*
+ * Filename: logging.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::error::Error;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Environment variable holding the log filter, e.g. `debug` or
+/// `info,rustycan4docker::api=debug`. Defaults to `info`.
+pub const LOG_ENV: &str = "RUSTYVXCAN_LOG";
+
+/// Environment variable selecting the log output: `text` (the default),
+/// `json` or `journald`.
+pub const LOG_FORMAT_ENV: &str = "RUSTYVXCAN_LOG_FORMAT";
+
+/// Layer filtering the events of a subscriber, whose filter a [`LogFilter`]
+/// replaces.
+pub type FilterLayer = reload::Layer<EnvFilter, Registry>;
+
+/// Handle to the filter of a running logger, to change its verbosity without
+/// restarting the plugin.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    /// A filter from `directives`, in the syntax of [`LOG_ENV`], and the
+    /// layer to install it with.
+    pub fn new(directives: &str) -> Result<(FilterLayer, LogFilter), Error> {
+        let (layer, handle) = reload::Layer::new(parse(directives)?);
+        Ok((layer, LogFilter { handle }))
+    }
+
+    /// Directives of the filter in effect.
+    pub fn get(&self) -> Result<String, Error> {
+        self.handle
+            .with_current(|f| f.to_string())
+            .map_err(|e| Error::Unsupported(format!("unable to read log filter: {e}")))
+    }
+
+    /// Replace the filter with one from `directives`.
+    pub fn set(&self, directives: &str) -> Result<(), Error> {
+        let filter = parse(directives)?;
+        self.handle
+            .reload(filter)
+            .map_err(|e| Error::Unsupported(format!("unable to change log filter: {e}")))?;
+        tracing::info!(filter = %directives, "log filter changed");
+        Ok(())
+    }
+}
+
+fn parse(directives: &str) -> Result<EnvFilter, Error> {
+    EnvFilter::try_new(directives)
+        .map_err(|e| Error::InvalidOption(format!("invalid log filter '{directives}': {e}")))
+}
+
+/// Install the global logger as configured by [`LOG_ENV`] and
+/// [`LOG_FORMAT_ENV`], returning the handle to change its filter later.
+///
+/// Request and response bodies of the plugin API are logged at `debug`
+/// level by the `rustycan4docker::api` target.
+pub fn init() -> Result<LogFilter, String> {
+    let directives = std::env::var(LOG_ENV).unwrap_or_else(|_| String::from("info"));
+    let filter = EnvFilter::try_new(&directives)
+        .map_err(|e| format!("invalid {LOG_ENV} '{directives}': {e}"))?;
+    let (layer, handle) = reload::Layer::new(filter);
+
+    let format = std::env::var(LOG_FORMAT_ENV).unwrap_or_default();
+    let registry = tracing_subscriber::registry().with(layer);
+    let res = match format.as_str() {
+        "" | "text" => registry.with(tracing_subscriber::fmt::layer()).try_init(),
+        "json" => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(true),
+            )
+            .try_init(),
+        "journald" => {
+            let journald = tracing_journald::layer()
+                .map_err(|e| format!("unable to connect to journald: {e}"))?;
+            registry.with(journald).try_init()
+        }
+        f => {
+            return Err(format!(
+                "invalid {LOG_FORMAT_ENV} '{f}', expected text, json or journald"
+            ))
+        }
+    };
+    res.map_err(|e| format!("unable to install logger: {e}"))?;
+    Ok(LogFilter { handle })
+}
@@ -24,471 +24,94 @@
  * SOFTWARE.
  */
 
-use crate::manager::NetworkManager;
-use serde::{Deserialize, Serialize};
-use std::vec::Vec;
+use bollard::Docker;
+use rustycan4docker::backend::GatewayMode;
+use rustycan4docker::{admin, api, logging, NetworkManager};
+use std::net::SocketAddr;
+use std::path::Path;
 use tokio::net::UnixListener;
 use tokio_stream::wrappers::UnixListenerStream;
-use warp::{http, Filter};
 
-pub mod endpoint;
-pub mod manager;
-pub mod network;
-
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct ErrorResponse {
-    name: String,
-    msg: String,
-}
-
-#[allow(non_snake_case)]
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct HandshakeResponse {
-    Implements: Vec<String>,
-}
-
-#[allow(non_snake_case)]
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct SetCapabilityResponse {
-    Scope: String,
-    ConnectivityScope: String,
-}
-
-#[allow(non_snake_case)]
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct JoinResponse {
-    InterfaceName: network::JoinResponse,
-}
-
-async fn api_plugin_activate(payload: bytes::Bytes) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-    let rsp = HandshakeResponse {
-        Implements: vec![String::from("NetworkDriver")],
-    };
-
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let jrsp = match serde_json::to_string(&rsp) {
-        Ok(jrsp) => jrsp,
-        Err(_) => {
-            status = http::StatusCode::BAD_REQUEST;
-            String::from(r#"{"Err":"Serializing response to Plugin.Activate"}"#)
-        }
-    };
-    println!("Plugin.Activate: {}", jrsp);
-    Ok(warp::reply::with_status(jrsp, status))
-}
-
-async fn api_get_capabilities(payload: bytes::Bytes) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-    let rsp = SetCapabilityResponse {
-        Scope: String::from("local"),
-        ConnectivityScope: String::from("local"),
-    };
-
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let jrsp = match serde_json::to_string(&rsp) {
-        Ok(jrsp) => jrsp,
-        Err(_) => {
-            status = http::StatusCode::BAD_REQUEST;
-            String::from(r#"{"Err":"Serializing response to NetworkDriver.GetCapabilities"}"#)
+#[tokio::main]
+async fn main() {
+    let log = match logging::init() {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
         }
     };
 
-    println!("NetworkDriver.GetCapabilities: {}", jrsp);
-    Ok(warp::reply::with_status(jrsp, status))
-}
-
-async fn api_network_create(
-    payload: bytes::Bytes,
-    mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
-        Ok(v) => {
-            let mut error = false;
-            let uid = match v["NetworkID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing network ID: {}", v["NetworkID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            let opt = match v["Options"]["com.docker.network.generic"].as_str() {
-                Some(o) => o.to_string(),
-                None => v["Options"]["com.docker.network.generic"].to_string(),
-            };
-            if !error {
-                mgr.network_create(uid, opt);
-                "{}"
-            } else {
-                status = http::StatusCode::BAD_REQUEST;
-                r#"{"Err":"Invalid network ID"}"#
+    // Refuse bad settings before touching any network
+    let metrics_addr = match std::env::var(admin::METRICS_ADDR_ENV) {
+        Ok(addr) => match addr.parse::<SocketAddr>() {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                eprintln!("invalid {} '{addr}': {e}", admin::METRICS_ADDR_ENV);
+                std::process::exit(1);
             }
-        }
-        Err(_) => r#"{"Err":"Unable to parse JSON payload"}"#,
+        },
+        Err(_) => None,
     };
-
-    println!("NetworkDriver.CreateNetwork: {}", reply);
-    Ok(warp::reply::with_status(reply, status))
-}
-
-async fn api_network_delete(
-    payload: bytes::Bytes,
-    mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
-        Ok(v) => {
-            let mut error = false;
-            let uid = match v["NetworkID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing network ID: {}", v["NetworkID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            if !error {
-                mgr.network_delete(uid);
-                "{}"
-            } else {
-                status = http::StatusCode::BAD_REQUEST;
-                r#"{"Err":"Invalid network ID"}"#
+    let scope = match std::env::var(api::SCOPE_ENV) {
+        Ok(scope) => match api::Scope::parse(&scope) {
+            Ok(scope) => scope,
+            Err(e) => {
+                eprintln!("invalid {}: {e}", api::SCOPE_ENV);
+                std::process::exit(1);
             }
-        }
-        Err(_) => r#"{"Err":"Unable to parse JSON payload"}"#,
+        },
+        Err(_) => api::Scope::default(),
     };
 
-    println!("NetworkDriver.DeleteNetwork: {}", reply);
-    Ok(warp::reply::with_status(reply, status))
-}
-
-async fn api_endpoint_create(
-    payload: bytes::Bytes,
-    mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
+    let mgr = NetworkManager::new();
 
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
-        Ok(v) => {
-            let mut error = false;
-            let nuid = match v["NetworkID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing network ID: {}", v["NetworkID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            let epuid = match v["EndpointID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing endpoint ID: {}", v["EndpointID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            if !error {
-                mgr.endpoint_create(nuid, epuid);
-                "{}"
-            } else {
-                status = http::StatusCode::BAD_REQUEST;
-                r#"{"Err":"Invalid network ID or endpoint ID"}"#
-            }
+    // Without kernel support every Docker request would fail later on
+    if let Err(e) = mgr.kernel_support().await {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    if let Ok(GatewayMode::Userspace) = mgr.gateway_resolve(GatewayMode::Auto).await {
+        tracing::warn!("can-gw is not available, networks forward frames in userspace");
+    }
+
+    // DOCKER_HOST=unix:///path/to/docker.sock selects a non-default daemon
+    let docker = match Docker::connect_with_unix_defaults() {
+        Ok(docker) => {
+            mgr.network_load(&docker).await;
+            let watcher = mgr.clone();
+            let events = docker.clone();
+            tokio::spawn(async move { watcher.network_watch(&events).await });
+            Some(docker)
         }
-        Err(_) => r#"{"Err":"Unable to parse JSON payload"}"#,
-    };
-
-    println!("NetworkDriver.CreateEndpoint: {}", reply);
-    Ok(warp::reply::with_status(reply, status))
-}
-
-async fn api_endpoint_delete(
-    payload: bytes::Bytes,
-    mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
-        Ok(v) => {
-            let mut error = false;
-            let nuid = match v["NetworkID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing network ID: {}", v["NetworkID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            let epuid = match v["EndpointID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing endpoint ID: {}", v["EndpointID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            if !error {
-                mgr.endpoint_delete(nuid, epuid);
-                "{}"
-            } else {
-                status = http::StatusCode::BAD_REQUEST;
-                r#"{"Err":"Invalid network ID or endpoint ID"}"#
-            }
+        Err(e) => {
+            tracing::error!(error = %e, "unable to connect to docker");
+            None
         }
-        Err(_) => r#"{"Err":"Unable to parse JSON payload"}"#,
     };
 
-    println!("NetworkDriver.DeleteEndpoint: {}", reply);
-    Ok(warp::reply::with_status(reply, status))
-}
-
-async fn api_endpoint_info(
-    payload: bytes::Bytes,
-    _mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    // ! TODO Add error handling
-    Ok(warp::reply::with_status("{}", http::StatusCode::OK))
-}
-
-async fn api_network_join(
-    payload: bytes::Bytes,
-    mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
-        Ok(v) => {
-            let mut error = false;
-            let nuid = match v["NetworkID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing network ID: {}", v["NetworkID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            let epuid = match v["EndpointID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing endpoint ID: {}", v["EndpointID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            let sbox = match v["SandboxKey"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing sandbox key: {}", v["SandboxKey"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            let opt = match v["Options"].as_str() {
-                Some(o) => o.to_string(),
-                None => v["Options"].to_string(),
-            };
-            if !error {
-                match mgr.endpoint_attach(nuid, epuid, sbox, opt) {
-                    Ok(joinrsp) => {
-                        let rsp = JoinResponse {
-                            InterfaceName: joinrsp,
-                        };
-                        match serde_json::to_string(&rsp) {
-                            Ok(jrsp) => jrsp,
-                            Err(_) => String::from(
-                                r#"{"Err":"Serializing response to NetworkDriver.Join"}"#,
-                            ),
-                        }
-                    }
-                    Err(_) => String::from(r#"{"Err":"Error attaching endpoint to network"}"#),
-                }
-            } else {
-                status = http::StatusCode::BAD_REQUEST;
-                String::from(r#"{"Err":"Invalid network ID, endpoint ID, or sandbox key"}"#)
-            }
+    let admin_sock =
+        std::env::var(admin::ADMIN_SOCKET_ENV).unwrap_or_else(|_| admin::ADMIN_SOCKET.to_string());
+    match admin_listener(Path::new(&admin_sock)) {
+        Ok(listener) => {
+            let incoming = UnixListenerStream::new(listener);
+            let storage = Path::new(&admin_sock).parent().map(Path::to_path_buf);
+            let storage = storage.into_iter().collect();
+            let routes = admin::routes(mgr.clone(), docker, storage, Some(log));
+            tokio::spawn(warp::serve(routes).run_incoming(incoming));
         }
-        Err(_) => String::from(r#"{"Err":"Unable to parse JSON payload"}"#),
-    };
-
-    println!("NetworkDriver.Join: {}", reply);
-    Ok(warp::reply::with_status(reply, status))
-}
-
-async fn api_network_leave(
-    payload: bytes::Bytes,
-    mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    let mut status: http::StatusCode = http::StatusCode::OK;
-    let reply = match serde_json::from_slice::<serde_json::Value>(&payload) {
-        Ok(v) => {
-            let mut error = false;
-            let nuid = match v["NetworkID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing network ID: {}", v["NetworkID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            let epuid = match v["EndpointID"].as_str() {
-                Some(u) => u.to_string(),
-                None => {
-                    println!("Error parsing endpoint ID: {}", v["EndpointID"]);
-                    error = true;
-                    String::new()
-                }
-            };
-            if !error {
-                mgr.endpoint_detach(nuid, epuid);
-                "{}"
-            } else {
-                status = http::StatusCode::BAD_REQUEST;
-                r#"{"Err":"Invalid network ID or endpoint ID"}"#
+        Err(e) => tracing::error!(path = %admin_sock, error = %e, "unable to open admin socket"),
+    }
+    if let Some(addr) = metrics_addr {
+        match warp::serve(admin::metrics_route(mgr.clone())).try_bind_ephemeral(addr) {
+            Ok((_, server)) => {
+                tokio::spawn(server);
             }
+            Err(e) => tracing::error!(%addr, error = %e, "unable to serve metrics"),
         }
-        Err(_) => r#"{"Err":"Unable to parse JSON payload"}"#,
-    };
-
-    println!("NetworkDriver.Leave: {}", reply);
-    Ok(warp::reply::with_status(reply, status))
-}
-
-async fn api_discover_new(
-    payload: bytes::Bytes,
-    _mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    // ! TODO Add error handling
-    Ok(warp::reply::with_status("{}", http::StatusCode::OK))
-}
-
-async fn api_discover_delete(
-    payload: bytes::Bytes,
-    _mgr: NetworkManager,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    log_body(&payload);
-
-    // ! TODO Add error handling
-    Ok(warp::reply::with_status("{}", http::StatusCode::OK))
-}
-
-fn log_body(_payload: &bytes::Bytes) {
-    // println!(
-    //     "Request body: {}",
-    //     std::str::from_utf8(&payload).expect("error converting bytes to &str")
-    // );
-}
-
-fn process_body() -> impl Filter<Extract = (bytes::Bytes,), Error = warp::Rejection> + Copy {
-    warp::body::content_length_limit(1024 * 16).and(warp::body::bytes())
-}
-
-#[tokio::main]
-async fn main() {
-    let mgr = NetworkManager::new();
-    mgr.network_load().await;
-    let filter = warp::any().map(move || mgr.clone());
-
-    let payload = warp::post()
-        .and(warp::path("Plugin.Activate"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and_then(api_plugin_activate);
-
-    let get_cap = warp::post()
-        .and(warp::path("NetworkDriver.GetCapabilities"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and_then(api_get_capabilities);
-
-    let nw_create = warp::post()
-        .and(warp::path("NetworkDriver.CreateNetwork"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_network_create);
+    }
 
-    let nw_del = warp::post()
-        .and(warp::path("NetworkDriver.DeleteNetwork"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_network_delete);
-
-    let endp_create = warp::post()
-        .and(warp::path("NetworkDriver.CreateEndpoint"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_endpoint_create);
-
-    let endp_del = warp::post()
-        .and(warp::path("NetworkDriver.DeleteEndpoint"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_endpoint_delete);
-
-    let endp_info = warp::post()
-        .and(warp::path("NetworkDriver.EndpointOperInfo"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_endpoint_info);
-
-    let nw_join = warp::post()
-        .and(warp::path("NetworkDriver.Join"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_network_join);
-
-    let nw_leave = warp::post()
-        .and(warp::path("NetworkDriver.Leave"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_network_leave);
-
-    let dsc_new = warp::post()
-        .and(warp::path("NetworkDriver.DiscoverNew"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_discover_new);
-
-    let dsc_del = warp::post()
-        .and(warp::path("NetworkDriver.DiscoverDelete"))
-        .and(warp::path::end())
-        .and(process_body())
-        .and(filter.clone())
-        .and_then(api_discover_delete);
-
-    let routes = payload
-        .or(get_cap)
-        .or(nw_create)
-        .or(nw_del)
-        .or(endp_create)
-        .or(endp_del)
-        .or(endp_info)
-        .or(nw_join)
-        .or(nw_leave)
-        .or(dsc_new)
-        .or(dsc_del);
+    let routes = api::routes(mgr, scope);
 
     let incoming =
         UnixListenerStream::new(UnixListener::bind("/run/docker/plugins/rustyvxcan.sock").unwrap());
@@ -504,3 +127,15 @@ async fn main() {
     // Then, uncomment the following line (and remove the UnixListener above)
     // warp::serve(routes).run(([127,0,0,1],7373)).await;
 }
+
+/// Bind the admin socket, replacing one left behind by an earlier run.
+fn admin_listener(path: &Path) -> std::io::Result<UnixListener> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
@@ -24,194 +24,774 @@
  * SOFTWARE.
  */
 
-use crate::endpoint::Endpoint;
-use crate::network::{JoinResponse, Network};
+use crate::backend::userspace::UserspaceGateway;
+use crate::backend::{Backend, GatewayBackend, GatewayMode, RuleStats, Topology};
+use crate::error::Error;
+use crate::fault::Faults;
+use crate::frame::Frame;
+use crate::inject::{InjectConfig, InjectStatus};
+use crate::kernel;
+use crate::metrics::{LinkSample, Metrics};
+use crate::monitor::{self, FrameFilter, FrameRecord};
+use crate::network::{JoinResponse, Network, NetworkHandle, NetworkInfo};
+use crate::options::{EndpointOptions, NetworkOptions};
+use crate::recorder::{RecordConfig, RecordStatus};
+use crate::replay::{ReplayConfig, ReplayStatus};
+use crate::stats::{EndpointStats, NetworkStats};
 use bollard::network::ListNetworksOptions;
+use bollard::system::EventsOptions;
 use bollard::Docker;
 use parking_lot::RwLock;
-use std::collections::HashMap;
-use std::fmt::Error;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::StreamExt;
+use tracing::{debug, error, info, warn};
 
+/// Name under which Docker knows the plugin's network driver.
+pub const DRIVER_NAME: &str = "rustyvxcan";
+
+/// Networks using one host CAN device.
+struct DeviceUsers {
+    /// Whether every network on the device was created with `vxcan.shared=true`.
+    shared: bool,
+    /// Whether the plugin created the device and must remove it again.
+    created: bool,
+    networks: Vec<String>,
+}
+
+/// A network served by the plugin.
+struct NetworkEntry {
+    handle: NetworkHandle,
+    /// Options the network was created with, which a repeated create has to
+    /// match.
+    options: NetworkOptions,
+}
+
+/// Tracks the Docker networks served by the plugin.
+///
+/// The network list lock is only ever held to look up or modify the map;
+/// endpoint operations are handed to the task owning each network, so
+/// requests for different networks proceed in parallel. Creating and deleting
+/// networks is serialized through the device list, which is held while host
+/// devices are set up and torn down.
 #[derive(Clone)]
 pub struct NetworkManager {
-    network_list: Arc<RwLock<HashMap<String, Network>>>,
+    network_list: Arc<RwLock<HashMap<String, NetworkEntry>>>,
+    device_list: Arc<Mutex<HashMap<String, DeviceUsers>>>,
+    backend: Backend,
+    /// Gateway of the networks forwarding frames in userspace.
+    userspace: Arc<dyn GatewayBackend>,
+    metrics: Arc<Metrics>,
+    /// Other Docker nodes, as reported by `DiscoverNew` and
+    /// `DiscoverDelete`.
+    nodes: Arc<RwLock<BTreeSet<IpAddr>>>,
+}
+
+impl Default for NetworkManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetworkManager {
+    /// Create a manager driving the kernel through `ip` and `cangw`.
     pub fn new() -> Self {
+        Self::with_backend(Backend::cli())
+    }
+
+    /// Create a manager whose networks go through `backend` for all link,
+    /// gateway and frame operations.
+    pub fn with_backend(backend: Backend) -> Self {
+        let metrics = Arc::new(Metrics::new());
         NetworkManager {
             network_list: Arc::new(RwLock::new(HashMap::new())),
+            device_list: Arc::new(Mutex::new(HashMap::new())),
+            userspace: Arc::new(UserspaceGateway::new(backend.frames.clone())),
+            backend: backend.timed(metrics.clone()),
+            metrics,
+            nodes: Arc::new(RwLock::new(BTreeSet::new())),
         }
     }
 
-    pub async fn network_load(&self) {
-        let connection = Docker::connect_with_unix_defaults().unwrap();
+    /// Fail with an explanation unless the kernel and host tools support
+    /// everything the networks need. Without can-gw, networks forward their
+    /// frames in userspace, which needs raw CAN sockets instead.
+    pub async fn kernel_support(&self) -> Result<(), Error> {
+        self.backend.links.supported().await?;
+        self.gateway_resolve(GatewayMode::Auto).await.map(|_| ())
+    }
+
+    /// The gateway networks created with `mode` use: `auto` picks the
+    /// kernel if it supports can-gw. Fails if the gateway is not supported.
+    pub async fn gateway_resolve(&self, mode: GatewayMode) -> Result<GatewayMode, Error> {
+        match mode {
+            GatewayMode::Kernel => self.backend.gateway.supported().await.map(|_| mode),
+            GatewayMode::Userspace => self.userspace.supported().await.map(|_| mode),
+            GatewayMode::Auto => match self.backend.gateway.supported().await {
+                Ok(()) => Ok(GatewayMode::Kernel),
+                Err(e) => match self.userspace.supported().await {
+                    Ok(()) => Ok(GatewayMode::Userspace),
+                    Err(u) => Err(Error::Unsupported(format!(
+                        "{e}, and frames cannot be forwarded in userspace either: {u}"
+                    ))),
+                },
+            },
+        }
+    }
+
+    /// Counters of the rules of the gateways `networks` use.
+    async fn rule_stats(&self, networks: &[NetworkInfo]) -> Result<Vec<RuleStats>, Error> {
+        let mut rules = self.userspace.rule_stats().await?;
+        // Reading can-gw rules fails on kernels without it
+        if networks.iter().any(|n| n.gateway == GatewayMode::Kernel) {
+            rules.extend(self.backend.gateway.rule_stats().await?);
+        }
+        Ok(rules)
+    }
+
+    /// Inspect every network; networks deleted meanwhile are left out.
+    async fn network_inspect_all(&self) -> Vec<NetworkInfo> {
+        let mut infos = Vec::new();
+        for nid in self.networks() {
+            if let Ok(info) = self.network_inspect(nid).await {
+                infos.push(info);
+            }
+        }
+        infos
+    }
+
+    /// Compare the interfaces and forwarding rules of every network with the
+    /// kernel, returning a description of each difference.
+    pub async fn kernel_verify(&self) -> Result<Vec<String>, Error> {
+        let infos = self.network_inspect_all().await;
+        let installed: HashSet<(String, String)> = self
+            .rule_stats(&infos)
+            .await?
+            .into_iter()
+            .map(|r| (r.src, r.dst))
+            .collect();
+
+        let mut problems = Vec::new();
+        for info in infos {
+            if !self.backend.links.link_exists(&info.interface).await? {
+                problems.push(format!(
+                    "network {}: interface {} is missing",
+                    info.id, info.interface
+                ));
+            }
+            let mut devices = HashSet::new();
+            for ep in &info.endpoints {
+                if !self.backend.links.link_exists(&ep.device).await? {
+                    problems.push(format!(
+                        "network {}: interface {} is missing",
+                        info.id, ep.device
+                    ));
+                }
+                devices.insert(ep.device.as_str());
+            }
+
+            let expected: HashSet<&(String, String)> = info.rules.iter().collect();
+            for (src, dst) in &info.rules {
+                if !installed.contains(&(src.clone(), dst.clone())) {
+                    problems.push(format!(
+                        "network {}: rule {src} -> {dst} is missing",
+                        info.id
+                    ));
+                }
+            }
+            // Endpoint tunnels belong to this network alone, so any other
+            // rule using one is stale (the device itself may be shared)
+            for rule @ (src, dst) in &installed {
+                if (devices.contains(src.as_str()) || devices.contains(dst.as_str()))
+                    && !expected.contains(rule)
+                {
+                    problems.push(format!(
+                        "network {}: unexpected rule {src} -> {dst}",
+                        info.id
+                    ));
+                }
+            }
+        }
+        problems.sort();
+        Ok(problems)
+    }
+
+    /// Frame counters of a network's routes, summed per endpoint and for the
+    /// whole network.
+    pub async fn network_stats(&self, nuid: String) -> Result<NetworkStats, Error> {
+        let info = self.network_inspect(nuid).await?;
+        let rules = self.rule_stats(std::slice::from_ref(&info)).await?;
+        Ok(NetworkStats::new(&info, &rules))
+    }
+
+    /// Frame counters of one endpoint's routes, all zero if the gateway's
+    /// counters cannot be read.
+    pub async fn endpoint_stats(
+        &self,
+        nuid: String,
+        epuid: String,
+    ) -> Result<EndpointStats, Error> {
+        let info = self.network_inspect(nuid).await?;
+        // Docker asks while inspecting and starting containers, which a
+        // failed read must not break
+        let rules = match self.rule_stats(std::slice::from_ref(&info)).await {
+            Ok(rules) => rules,
+            Err(e) => {
+                warn!(network = %info.id, error = %e, "unable to read gateway rule statistics");
+                Vec::new()
+            }
+        };
+        NetworkStats::new(&info, &rules)
+            .endpoints
+            .into_iter()
+            .find(|ep| ep.id.eq(&epuid))
+            .ok_or(Error::EndpointNotFound(epuid))
+    }
+
+    /// Metrics recorded for this manager's networks.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Sample the state of all networks, their interfaces and forwarding
+    /// rules, and return every metric in the Prometheus text format.
+    pub async fn metrics_export(&self) -> String {
+        let infos = self.network_inspect_all().await;
+        let rules = match self.rule_stats(&infos).await {
+            Ok(rules) => rules,
+            Err(e) => {
+                warn!(error = %e, "unable to read gateway rule statistics");
+                Vec::new()
+            }
+        };
 
+        let mut networks = Vec::new();
+        let mut links = Vec::new();
+        for info in infos {
+            let names =
+                std::iter::once(&info.interface).chain(info.endpoints.iter().map(|e| &e.device));
+            for name in names {
+                match self.backend.links.link_stats(name).await {
+                    Ok(stats) => links.push(LinkSample {
+                        network: info.id.clone(),
+                        interface: name.clone(),
+                        stats,
+                    }),
+                    Err(e) => debug!(interface = %name, error = %e, "no link statistics"),
+                }
+            }
+            networks.push(NetworkStats::new(&info, &rules));
+        }
+
+        self.metrics.export(&networks, &links).await
+    }
 
+    /// Restore the networks Docker already has for this driver, e.g. after a
+    /// restart of the plugin.
+    pub async fn network_load(&self, docker: &Docker) {
         let list_networks_filters: HashMap<&str, Vec<&str>> = HashMap::new();
         let config = ListNetworksOptions {
             filters: list_networks_filters,
         };
-        match connection.list_networks(Some(config)).await {
+        match docker.list_networks(Some(config)).await {
             Ok(networks) => {
                 for n in networks {
-                    match (n.driver, n.options, n.id) {
-                        (Some(driver), Some(options), Some(nid)) => {
-                            if driver.eq("rustyvxcan") {
-                                let mut d: String = String::from("vcan");
-                                let mut p: String = String::from("vcan");
-                                let mut c: u32 = 0u32;
-                                if options.contains_key("vxcan.dev") {
-                                    d = options["vxcan.dev"].clone();
+                    if let (Some(driver), Some(nid)) = (n.driver, n.id) {
+                        if driver.eq(DRIVER_NAME) {
+                            // Networks created without any -o have no options at all
+                            let options = n.options.unwrap_or_default();
+                            match NetworkOptions::from_map(&options) {
+                                Ok(o) => {
+                                    // Docker already accepted these networks, so a shared
+                                    // device is only worth a warning
+                                    if let Err(e) = self.network_insert(nid.clone(), o, true).await
+                                    {
+                                        error!(network = %nid, error = %e, "unable to restore network");
+                                    }
                                 }
-                                if options.contains_key("vxcan.peer") {
-                                    p = options["vxcan.peer"].clone();
+                                Err(e) => {
+                                    error!(network = %nid, error = %e, "unable to restore network")
                                 }
-                                if options.contains_key("vxcan.id") {
-                                    c = match options["vxcan.id"].trim().parse() {
-                                        Ok(i) => i,
-                                        Err(_) => 0u32,
-                                    };
-                                }
-
-                                let nw = Network::new(d, p, c);
-                                self.network_list.write().insert(nid, nw);
                             }
                         }
-                        _ => {}
                     }
                 }
             }
-            Err(e) => eprintln!(" !! Unable to get docker networks: {}", e),
+            Err(e) => error!(error = %e, "unable to get docker networks"),
         }
     }
 
-    pub fn network_create(&self, uid: String, options: String) {
-        // Print the options and extract the right values
-        // Add the network to the hashmap
-        println!(
-            " -> Adding network with id '{}' with options '{}'",
-            uid, options
-        );
+    /// Follow Docker's network events and tear down networks Docker removed
+    /// without the plugin seeing the `DeleteNetwork` call, e.g. because the
+    /// request timed out. Returns when the event stream ends.
+    pub async fn network_watch(&self, docker: &Docker) {
+        let mut filters: HashMap<&str, Vec<&str>> = HashMap::new();
+        filters.insert("type", vec!["network"]);
+        filters.insert("event", vec!["destroy"]);
+        let config = EventsOptions {
+            filters,
+            ..Default::default()
+        };
 
-        match self.options_parse(options) {
-            Ok((d, p, c)) => {
-                let nw = Network::new(d, p, c);
-                self.network_list.write().insert(uid, nw);
+        let mut events = docker.events(Some(config));
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(msg) => {
+                    let nid = match msg.actor.and_then(|a| a.id) {
+                        Some(nid) => nid,
+                        None => continue,
+                    };
+                    if self.network_list.read().contains_key(&nid) {
+                        info!(network = %nid, "docker removed network, cleaning up");
+                        if let Err(e) = self.network_delete(nid.clone()).await {
+                            error!(network = %nid, error = %e, "unable to remove network");
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!(error = %e, "docker event stream failed");
+                    break;
+                }
             }
-            Err(_) => {}
         }
     }
 
-    pub fn network_delete(&self, uid: String) {
-        let mut map = self.network_list.write();
-        if map.contains_key(&uid) {
-            println!(" -> Network {uid} exists...removing!");
-            map.remove(&uid);
-        }
+    /// Create a network from the `com.docker.network.generic` options of a
+    /// `CreateNetwork` request.
+    pub async fn network_create(
+        &self,
+        uid: String,
+        options: &serde_json::Value,
+    ) -> Result<(), Error> {
+        // Validate the options before touching any interfaces
+        info!(network = %uid, %options, "adding network");
+
+        let o = NetworkOptions::parse(options)?;
+        self.network_insert(uid, o, false).await
     }
 
-    pub fn endpoint_create(&self, nuid: String, epuid: String) {
-        // Create the endpoint
-        let ep = Endpoint::new(epuid);
+    /// Create a network from already validated options.
+    pub async fn network_add(&self, uid: String, options: NetworkOptions) -> Result<(), Error> {
+        info!(network = %uid, interface = %options.interface(), "adding network");
+        self.network_insert(uid, options, false).await
+    }
 
-        // Lock the network list
-        let mut map = self.network_list.write();
-        match map.get_mut(&nuid) {
-            Some(n) => {
-                // Add the endpoint to the network
-                n.endpoint_add(ep)
-            }
-            None => (),
+    /// Tear down a network with all of its endpoints, and its host device if
+    /// the plugin created it and no other network uses it. Unknown networks
+    /// are ignored. A network that cannot be torn down completely stays
+    /// registered, so that deleting it can be retried.
+    pub async fn network_delete(&self, uid: String) -> Result<(), Error> {
+        let mut devices = self.device_list.lock().await;
+        let nw = match self.network_list.read().get(&uid) {
+            Some(entry) => entry.handle.clone(),
+            None => return Ok(()),
         };
+        info!(network = %uid, "removing network");
+
+        nw.destroy().await?;
+        self.network_list.write().remove(&uid);
+
+        let ifc = nw.interface().to_string();
+        if let Some(users) = devices.get_mut(&ifc) {
+            users.networks.retain(|n| n.ne(&uid));
+            if let Some(next) = users.networks.first() {
+                // Someone else still uses the device: leave it in place
+                info!(device = %ifc, network = %next, "device still in use");
+                return Ok(());
+            }
+            let created = users.created;
+            devices.remove(&ifc);
+            if created {
+                info!(device = %ifc, "removing device");
+                let links = &self.backend.links;
+                let ifc = ifc.as_str();
+                kernel::retry(|| links.link_del(ifc)).await?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn endpoint_delete(&self, nuid: String, epuid: String) {
-        // Lock the network list
-        let mut map = self.network_list.write();
-        match map.get_mut(&nuid) {
-            Some(n) => {
-                // Remove the endpoint from the network
-                n.endpoint_remove(epuid)
+    /// Claim the network's host device (creating it if needed) and store the
+    /// network, unless a network with the ID and the same options exists
+    /// already. With `restore` set, sharing a device is only warned about.
+    async fn network_insert(
+        &self,
+        uid: String,
+        o: NetworkOptions,
+        restore: bool,
+    ) -> Result<(), Error> {
+        // Docker retries requests it got no answer to
+        if self.network_exists(&uid, &o)? {
+            return Ok(());
+        }
+        let ifc = o.interface();
+        // Only the plugin can emulate the timing of a bus or be a hub
+        let gateway = match (o.gateway, &o.bus, o.topology) {
+            (GatewayMode::Auto, Some(_), _) => GatewayMode::Userspace,
+            (GatewayMode::Auto, _, Topology::Hub) => GatewayMode::Userspace,
+            (mode, _, _) => mode,
+        };
+        let gateway = self.gateway_resolve(gateway).await?;
+        let topology = o.topology.resolve(gateway);
+        let mut devices = self.device_list.lock().await;
+        // Another create may have stored it while the gateway was probed
+        if self.network_exists(&uid, &o)? {
+            return Ok(());
+        }
+        match devices.get_mut(&ifc) {
+            Some(users) => {
+                if !(o.shared && users.shared) {
+                    let msg = format!(
+                        "device {} is already used by network {}; create every network sharing it with vxcan.shared=true",
+                        ifc,
+                        users.networks.join(", ")
+                    );
+                    if !restore {
+                        return Err(Error::DeviceInUse(msg));
+                    }
+                    warn!(network = %uid, "{msg}");
+                }
+                users.shared &= o.shared;
+                users.networks.push(uid.clone());
+            }
+            None => {
+                let exists = self.backend.links.link_exists(&ifc).await?;
+                if !exists {
+                    info!(device = %ifc, "creating device");
+                    self.backend.links.vcan_add(&ifc).await?;
+                }
+                devices.insert(
+                    ifc,
+                    DeviceUsers {
+                        shared: o.shared,
+                        created: !exists,
+                        networks: vec![uid.clone()],
+                    },
+                );
             }
-            None => (),
+        }
+
+        let mut backend = self.backend.clone();
+        if gateway == GatewayMode::Userspace {
+            backend.gateway = self.userspace.clone();
+        }
+        let options = o.clone();
+        let (record, tunnel) = (o.record, o.tunnel);
+        let nw = Network::new(o.device, o.peer, o.canid, gateway, topology, o.bus, backend).spawn();
+        let entry = NetworkEntry {
+            handle: nw.clone(),
+            options,
         };
+        self.network_list.write().insert(uid.clone(), entry);
+        drop(devices);
+
+        let mut started = Ok(());
+        if let Some(config) = record {
+            started = nw.record_start(config).await.map(drop);
+        }
+        if let (Ok(()), Some(mut config)) = (&started, tunnel) {
+            if config.discover {
+                config.peers = config.node_peers(&self.nodes());
+            }
+            started = nw.tunnel_start(config).await.map(drop);
+        }
+        if let Err(e) = started {
+            if restore {
+                error!(network = %uid, error = %e, "unable to start network");
+                return Ok(());
+            }
+            // Docker will not know about the network, so leave nothing behind
+            if let Err(e) = self.network_delete(uid.clone()).await {
+                warn!(network = %uid, error = %e, "unable to remove network");
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Whether network `uid` exists already, failing if it was created with
+    /// options other than `o`.
+    fn network_exists(&self, uid: &str, o: &NetworkOptions) -> Result<bool, Error> {
+        match self.network_list.read().get(uid) {
+            Some(entry) if entry.options == *o => {
+                info!(network = %uid, "network already exists");
+                Ok(true)
+            }
+            Some(_) => Err(Error::InvalidOption(format!(
+                "network {uid} already exists with other options"
+            ))),
+            None => Ok(false),
+        }
+    }
+
+    fn network_get(&self, nuid: &str) -> Option<NetworkHandle> {
+        self.network_list.read().get(nuid).map(|e| e.handle.clone())
+    }
+
+    /// Other Docker nodes discovered, sorted.
+    pub fn nodes(&self) -> Vec<IpAddr> {
+        self.nodes.read().iter().copied().collect()
+    }
+
+    /// Learn about another Docker node, which networks tunneling to the
+    /// nodes discovered start exchanging frames with.
+    pub async fn node_add(&self, addr: IpAddr) {
+        if self.nodes.write().insert(addr) {
+            info!(node = %addr, "node discovered");
+            self.nodes_update().await;
+        }
+    }
+
+    /// Forget a Docker node, e.g. one that left the cluster.
+    pub async fn node_remove(&self, addr: IpAddr) {
+        if self.nodes.write().remove(&addr) {
+            info!(node = %addr, "node gone");
+            self.nodes_update().await;
+        }
+    }
+
+    /// Hand the current nodes to every network.
+    async fn nodes_update(&self) {
+        let nodes = self.nodes();
+        let networks: Vec<(String, NetworkHandle)> = self
+            .network_list
+            .read()
+            .iter()
+            .map(|(uid, entry)| (uid.clone(), entry.handle.clone()))
+            .collect();
+        for (uid, nw) in networks {
+            if let Err(e) = nw.tunnel_nodes(nodes.clone()).await {
+                warn!(network = %uid, error = %e, "unable to update tunnel peers");
+            }
+        }
+    }
+
+    /// IDs of all networks, sorted.
+    pub fn networks(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.network_list.read().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Describe a network, its endpoints and its forwarding rules.
+    pub async fn network_inspect(&self, nuid: String) -> Result<NetworkInfo, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.inspect(nuid).await
+    }
+
+    /// Start recording the traffic on a network's host device to rotating
+    /// log files.
+    pub async fn record_start(
+        &self,
+        nuid: String,
+        config: RecordConfig,
+    ) -> Result<RecordStatus, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.record_start(config).await
+    }
+
+    /// Stop recording a network's traffic, returning the final state of the
+    /// recording if there was one.
+    pub async fn record_stop(&self, nuid: String) -> Result<Option<RecordStatus>, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.record_stop().await
+    }
+
+    /// Start replaying a candump log onto a network's host device.
+    pub async fn replay_start(
+        &self,
+        nuid: String,
+        config: ReplayConfig,
+    ) -> Result<ReplayStatus, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.replay_start(config).await
+    }
+
+    /// Stop replaying onto a network, returning the final state of the
+    /// replay if there was one.
+    pub async fn replay_stop(&self, nuid: String) -> Result<Option<ReplayStatus>, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.replay_stop().await
     }
 
-    pub fn endpoint_attach(
+    /// Start injecting error frames into a network, or into one of its
+    /// endpoints.
+    pub async fn inject_start(
+        &self,
+        nuid: String,
+        config: InjectConfig,
+    ) -> Result<InjectStatus, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.inject_start(config).await
+    }
+
+    /// Stop injecting error frames into a network, returning the final
+    /// state of the injection if there was one.
+    pub async fn inject_stop(&self, nuid: String) -> Result<Option<InjectStatus>, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.inject_stop().await
+    }
+
+    /// Send `frames` in order onto the host device of a network, from where
+    /// they reach its endpoints. Returns the number of frames sent.
+    pub async fn frames_send(&self, nuid: String, frames: Vec<Frame>) -> Result<usize, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        let socket = nw.host_socket().await?;
+        for frame in &frames {
+            socket.send(frame).await?;
+        }
+        debug!(
+            interface = nw.interface(),
+            frames = frames.len(),
+            "sent frames"
+        );
+        Ok(frames.len())
+    }
+
+    /// Receive the frames on the host device of a network that pass
+    /// `filter`, until the returned channel is dropped.
+    pub async fn frames_subscribe(
+        &self,
+        nuid: String,
+        filter: FrameFilter,
+    ) -> Result<mpsc::Receiver<FrameRecord>, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        let socket = nw.host_socket().await?;
+        Ok(monitor::subscribe(nw.interface(), socket, filter))
+    }
+
+    /// Create endpoint `epuid` on a network.
+    pub async fn endpoint_create(&self, nuid: String, epuid: String) -> Result<(), Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+
+        // Create the endpoint and add it to the network
+        nw.endpoint_create(epuid).await
+    }
+
+    /// Remove an endpoint and its tunnel. Unknown networks and endpoints are
+    /// ignored.
+    pub async fn endpoint_delete(&self, nuid: String, epuid: String) -> Result<(), Error> {
+        match self.network_get(&nuid) {
+            // Remove the endpoint from the network and tear it down
+            Some(nw) => nw.endpoint_delete(epuid).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Connect an endpoint to the rest of the network as Docker's `Join`
+    /// does: Docker moves the returned interface into the sandbox `sbox`
+    /// itself. `options` are the endpoint's options as a JSON object.
+    pub async fn endpoint_attach(
         &self,
         nuid: String,
         epuid: String,
-        _sbox: String,
+        sbox: String,
         options: String,
     ) -> Result<JoinResponse, Error> {
-        // Lock the network list
-        let mut map = self.network_list.write();
-        match map.get_mut(&nuid) {
-            Some(n) => {
-                let peer = match serde_json::from_str::<serde_json::Value>(&options) {
-                    Ok(v) => match v["vxcan.peer"].as_str() {
-                        Some(u) => u.to_string(),
-                        None => String::new(),
-                    },
-                    Err(_) => String::new(),
-                };
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
 
-                let namespace = String::new();
+        // Docker leaves the options out for most containers
+        let options = match options.trim() {
+            "" => serde_json::Value::Null,
+            o => serde_json::from_str(o).map_err(|e| {
+                Error::InvalidOption(format!("endpoint options are not valid JSON: {e}"))
+            })?,
+        };
+        let options = EndpointOptions::parse(&options)?;
 
-                // Add the endpoint to the network
-                let rsp = n.endpoint_attach(epuid, namespace, peer)?;
-                Ok(rsp)
-            }
-            None => Err(Error),
+        // Add the endpoint to the network
+        let rsp = nw.endpoint_attach(epuid, sbox, options).await?;
+        Ok(rsp)
+    }
+
+    /// Remove an endpoint's forwarding rules. Unknown networks and endpoints
+    /// are ignored.
+    pub async fn endpoint_detach(&self, nuid: String, epuid: String) -> Result<(), Error> {
+        match self.network_get(&nuid) {
+            // Detach the endpoint from the network
+            Some(nw) => nw.endpoint_detach(epuid).await,
+            None => Ok(()),
         }
     }
 
-    pub fn endpoint_detach(&self, nuid: String, epuid: String) {
-        // Lock the network list
-        let mut map = self.network_list.write();
-        match map.get_mut(&nuid) {
-            Some(n) => {
-                // Detach the endpoint from the network
-                n.endpoint_detach(epuid)
-            }
-            None => (),
-        };
+    /// Replace the faults injected into the frames to and from an endpoint,
+    /// returning the faults now in effect.
+    pub async fn endpoint_faults(
+        &self,
+        nuid: String,
+        epuid: String,
+        faults: Faults,
+    ) -> Result<Faults, Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
+        nw.endpoint_faults(epuid, faults).await
     }
 
-    fn options_parse(&self, options: String) -> Result<(String, String, u32), Error> {
-        match serde_json::from_str::<serde_json::Value>(&options) {
-            Ok(v) => {
-                let device = match v["vxcan.dev"].as_str() {
-                    Some(u) => u.to_string(),
-                    None => {
-                        println!(" !! Error parsing vxcan.dev option: {}", v["vxcan.dev"]);
-                        String::from("vcan")
-                    }
-                };
-                let peer = match v["vxcan.peer"].as_str() {
-                    Some(u) => u.to_string(),
-                    None => {
-                        println!(" !! Error parsing vxcan.peer option: {}", v["vxcan.peer"]);
-                        String::from("vcanp")
-                    }
-                };
-                let canid: u32 = match v["vxcan.id"].as_str() {
-                    Some(u) => match u.to_string().trim().parse() {
-                        Ok(i) => i,
-                        Err(_) => 0u32,
-                    },
-                    None => {
-                        println!(" !! Error parsing vxcan.dev option: {}", v["vxcan.dev"]);
-                        0u32
-                    }
-                };
+    /// Connect the network namespace mounted at `netns` (e.g.
+    /// `/proc/<pid>/ns/net` or `/run/netns/<name>`) to a network without
+    /// going through Docker: create endpoint `epuid`, connect it and move its
+    /// interface into the namespace as `ifname`. Nothing is left behind if
+    /// any step fails.
+    pub async fn namespace_attach(
+        &self,
+        nuid: String,
+        epuid: String,
+        netns: String,
+        ifname: String,
+    ) -> Result<(), Error> {
+        let nw = self
+            .network_get(&nuid)
+            .ok_or_else(|| Error::NetworkNotFound(nuid.clone()))?;
 
-                // Return the tuple of options
-                Ok((device, peer, canid))
+        nw.endpoint_create(epuid.clone()).await?;
+        let res = match nw
+            .endpoint_attach(epuid.clone(), netns.clone(), EndpointOptions::default())
+            .await
+        {
+            Ok(_) => nw.endpoint_move(epuid.clone(), netns, ifname).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            if let Err(e) = nw.endpoint_detach(epuid.clone()).await {
+                warn!(endpoint = %epuid, error = %e, "unable to detach endpoint");
+            }
+            if let Err(e) = nw.endpoint_delete(epuid.clone()).await {
+                warn!(endpoint = %epuid, error = %e, "unable to delete endpoint");
             }
-            Err(_) => Err(Error),
+            return Err(e);
         }
+        Ok(())
+    }
+
+    /// Disconnect a namespace attached with [`Self::namespace_attach`],
+    /// removing its interface.
+    pub async fn namespace_detach(&self, nuid: String, epuid: String) -> Result<(), Error> {
+        self.endpoint_detach(nuid.clone(), epuid.clone()).await?;
+        self.endpoint_delete(nuid, epuid).await
     }
 }
@@ -0,0 +1,296 @@
+/*
+ * Filename: metrics.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::LinkStats;
+use crate::error::Error;
+use crate::stats::{FrameCounters, NetworkStats};
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::time::Duration;
+
+/// Traffic counters of one interface of a network, as exported.
+pub struct LinkSample {
+    pub network: String,
+    pub interface: String,
+    pub stats: LinkStats,
+}
+
+/// Prometheus metrics of a [`NetworkManager`](crate::NetworkManager).
+///
+/// Request, error and kernel operation metrics are recorded as they happen;
+/// the state of networks, interfaces and routes is sampled by
+/// [`NetworkManager::metrics_export`](crate::NetworkManager::metrics_export)
+/// at every scrape.
+pub struct Metrics {
+    registry: Registry,
+    requests: HistogramVec,
+    errors: IntCounterVec,
+    kernel_ops: HistogramVec,
+    networks: IntGauge,
+    endpoints: IntGauge,
+    rules: IntGauge,
+    link_frames: IntCounterVec,
+    link_bytes: IntCounterVec,
+    link_errors: IntCounterVec,
+    link_dropped: IntCounterVec,
+    gateway_frames: IntCounterVec,
+    endpoint_frames: IntCounterVec,
+    bus_load: GaugeVec,
+    bus_frames: IntCounterVec,
+    /// Held while sampled metrics are replaced and encoded.
+    scrape: tokio::sync::Mutex<()>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("rustyvxcan")), None)
+            .expect("valid metric prefix");
+
+        let requests = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Time taken to answer plugin API requests, by Docker method",
+            ),
+            &["method"],
+        )
+        .expect("valid metric");
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Errors reported to Docker, by kind"),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let kernel_ops = HistogramVec::new(
+            HistogramOpts::new(
+                "kernel_operation_duration_seconds",
+                "Time taken by link and gateway operations, by operation",
+            ),
+            &["operation"],
+        )
+        .expect("valid metric");
+        let networks = IntGauge::new("networks", "Networks served").expect("valid metric");
+        let endpoints =
+            IntGauge::new("endpoints", "Endpoints of all networks").expect("valid metric");
+        let rules =
+            IntGauge::new("gateway_rules", "Forwarding rules installed").expect("valid metric");
+        let link = |name: &str, help: &str| {
+            IntCounterVec::new(
+                Opts::new(name, help),
+                &["network", "interface", "direction"],
+            )
+            .expect("valid metric")
+        };
+        let link_frames = link("link_frames_total", "Frames through a managed interface");
+        let link_bytes = link("link_bytes_total", "Bytes through a managed interface");
+        let link_errors = link("link_errors_total", "Errors on a managed interface");
+        let link_dropped = link(
+            "link_dropped_total",
+            "Frames dropped by a managed interface",
+        );
+        let gateway_frames = IntCounterVec::new(
+            Opts::new(
+                "gateway_frames_total",
+                "Frames seen by the forwarding rules of a network, by result",
+            ),
+            &["network", "src", "dst", "result"],
+        )
+        .expect("valid metric");
+        let endpoint_frames = IntCounterVec::new(
+            Opts::new(
+                "endpoint_frames_total",
+                "Frames forwarded from (sent) and to (received) an endpoint over all of its routes, by result",
+            ),
+            &["network", "endpoint", "direction", "result"],
+        )
+        .expect("valid metric");
+        let bus_load = GaugeVec::new(
+            Opts::new(
+                "bus_load_percent",
+                "Share of the last second the emulated bus of a network was busy",
+            ),
+            &["network"],
+        )
+        .expect("valid metric");
+        let bus_frames = IntCounterVec::new(
+            Opts::new(
+                "bus_frames_total",
+                "Frames sent over the emulated bus of a network",
+            ),
+            &["network"],
+        )
+        .expect("valid metric");
+
+        let metrics = Metrics {
+            registry,
+            requests,
+            errors,
+            kernel_ops,
+            networks,
+            endpoints,
+            rules,
+            link_frames,
+            link_bytes,
+            link_errors,
+            link_dropped,
+            gateway_frames,
+            endpoint_frames,
+            bus_load,
+            bus_frames,
+            scrape: tokio::sync::Mutex::new(()),
+        };
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.kernel_ops.clone()),
+            Box::new(metrics.networks.clone()),
+            Box::new(metrics.endpoints.clone()),
+            Box::new(metrics.rules.clone()),
+            Box::new(metrics.link_frames.clone()),
+            Box::new(metrics.link_bytes.clone()),
+            Box::new(metrics.link_errors.clone()),
+            Box::new(metrics.link_dropped.clone()),
+            Box::new(metrics.gateway_frames.clone()),
+            Box::new(metrics.endpoint_frames.clone()),
+            Box::new(metrics.bus_load.clone()),
+            Box::new(metrics.bus_frames.clone()),
+        ];
+        for c in collectors {
+            metrics
+                .registry
+                .register(c)
+                .expect("metric registered once");
+        }
+        metrics
+    }
+
+    /// Record a plugin API request to `method` answered after `elapsed`.
+    pub fn request_observe(&self, method: &str, elapsed: Duration) {
+        self.requests
+            .with_label_values(&[method])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Record an error reported to Docker.
+    pub fn error_inc(&self, e: &Error) {
+        self.errors.with_label_values(&[e.kind()]).inc();
+    }
+
+    /// Record a backend operation that took `elapsed`.
+    pub fn kernel_observe(&self, op: &str, elapsed: Duration) {
+        self.kernel_ops
+            .with_label_values(&[op])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Replace the sampled metrics and encode all metrics in the Prometheus
+    /// text format.
+    pub(crate) async fn export(&self, networks: &[NetworkStats], links: &[LinkSample]) -> String {
+        let _scrape = self.scrape.lock().await;
+
+        self.networks.set(networks.len() as i64);
+        self.endpoints
+            .set(networks.iter().map(|n| n.endpoints.len()).sum::<usize>() as i64);
+        self.rules
+            .set(networks.iter().map(|n| n.routes.len()).sum::<usize>() as i64);
+
+        // Kernel counters are exported as they are; resetting first drops
+        // interfaces and rules that went away since the last scrape
+        for counter in [
+            &self.link_frames,
+            &self.link_bytes,
+            &self.link_errors,
+            &self.link_dropped,
+            &self.gateway_frames,
+            &self.endpoint_frames,
+        ] {
+            counter.reset();
+        }
+        self.bus_frames.reset();
+        self.bus_load.reset();
+        for l in links {
+            let s = &l.stats;
+            for (counter, rx, tx) in [
+                (&self.link_frames, s.rx_frames, s.tx_frames),
+                (&self.link_bytes, s.rx_bytes, s.tx_bytes),
+                (&self.link_errors, s.rx_errors, s.tx_errors),
+                (&self.link_dropped, s.rx_dropped, s.tx_dropped),
+            ] {
+                counter
+                    .with_label_values(&[&l.network, &l.interface, "rx"])
+                    .inc_by(rx);
+                counter
+                    .with_label_values(&[&l.network, &l.interface, "tx"])
+                    .inc_by(tx);
+            }
+        }
+
+        for n in networks {
+            if let Some(bus) = &n.bus {
+                self.bus_load.with_label_values(&[&n.id]).set(bus.load);
+                self.bus_frames
+                    .with_label_values(&[&n.id])
+                    .inc_by(bus.frames);
+            }
+            for r in &n.routes {
+                for (result, count) in results(&r.frames) {
+                    self.gateway_frames
+                        .with_label_values(&[&n.id, &r.src, &r.dst, result])
+                        .inc_by(count);
+                }
+            }
+            for ep in &n.endpoints {
+                for (direction, frames) in [("sent", &ep.sent), ("received", &ep.received)] {
+                    for (result, count) in results(frames) {
+                        self.endpoint_frames
+                            .with_label_values(&[&n.id, &ep.id, direction, result])
+                            .inc_by(count);
+                    }
+                }
+            }
+        }
+
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::error!(error = %e, "unable to encode metrics");
+        }
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+fn results(frames: &FrameCounters) -> [(&'static str, u64); 3] {
+    [
+        ("handled", frames.handled),
+        ("dropped", frames.dropped),
+        ("deleted", frames.deleted),
+    ]
+}
@@ -0,0 +1,140 @@
+/*
+ * Filename: monitor.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Frames sent and observed on a network's host device from outside any
+//! container.
+
+use crate::backend::FrameSocket;
+use crate::error::Error;
+use crate::frame::Frame;
+use crate::replay::IdFilter;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::sync::mpsc;
+use tracing::{debug, warn, Instrument};
+
+/// Frames observed but not yet taken by a subscriber before the oldest are
+/// lost.
+const SUBSCRIBER_QUEUE: usize = 1024;
+
+/// Frames to send, in the notation of `cansend`: one, or a batch sent in
+/// order.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum FrameBatch {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl FrameBatch {
+    /// The frames of the batch, or an error naming the first invalid one.
+    pub fn parse(&self) -> Result<Vec<Frame>, Error> {
+        let frames = match self {
+            FrameBatch::One(frame) => std::slice::from_ref(frame),
+            FrameBatch::Many(frames) => frames.as_slice(),
+        };
+        frames
+            .iter()
+            .map(|f| Frame::parse_candump(f).map_err(|e| Error::InvalidOption(e.to_string())))
+            .collect()
+    }
+}
+
+/// Frames a subscriber receives: those passing any of its filters, or all
+/// of them, error frames included, without filters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameFilter(pub Vec<IdFilter>);
+
+impl FrameFilter {
+    /// Parse comma-separated `<id>:<mask>` or `<id>~<mask>` filters.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        s.split(',')
+            .filter(|f| !f.is_empty())
+            .map(IdFilter::parse)
+            .collect::<Result<_, _>>()
+            .map(FrameFilter)
+    }
+
+    pub fn matches(&self, frame: &Frame) -> bool {
+        self.0.is_empty() || self.0.iter().any(|f| f.matches(frame))
+    }
+}
+
+/// A frame observed on a network.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameRecord {
+    /// Seconds since the Unix epoch when the frame was received.
+    pub timestamp: f64,
+    /// The frame in the notation of `candump`.
+    pub frame: String,
+}
+
+/// Pass the frames `socket` receives through `filter` to the returned
+/// channel, until it is closed.
+pub fn subscribe(
+    ifc: &str,
+    socket: Arc<dyn FrameSocket>,
+    filter: FrameFilter,
+) -> mpsc::Receiver<FrameRecord> {
+    let (tx, rx) = mpsc::channel(SUBSCRIBER_QUEUE);
+    tokio::spawn(
+        forward(socket, filter, tx).instrument(tracing::info_span!("subscriber", interface = %ifc)),
+    );
+    rx
+}
+
+async fn forward(socket: Arc<dyn FrameSocket>, filter: FrameFilter, tx: mpsc::Sender<FrameRecord>) {
+    debug!("subscriber connected");
+    loop {
+        let received = tokio::select! {
+            _ = tx.closed() => break,
+            received = socket.recv() => received,
+        };
+        let (frame, time) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!(error = %e, "unable to receive frames for subscriber");
+                break;
+            }
+        };
+        if !filter.matches(&frame) {
+            continue;
+        }
+        let record = FrameRecord {
+            timestamp: time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            frame: frame.to_candump(),
+        };
+        // A subscriber too slow to keep up misses frames
+        if let Err(mpsc::error::TrySendError::Closed(_)) = tx.try_send(record) {
+            break;
+        }
+    }
+    debug!("subscriber disconnected");
+}
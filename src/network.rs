@@ -24,238 +24,945 @@
  * SOFTWARE.
  */
 
+use crate::backend::FrameSocket;
+use crate::backend::{Backend, GatewayMode, Topology};
+use crate::bus::{Bus, BusConfig, BusStatus};
 use crate::endpoint::Endpoint;
-use parking_lot::RwLock;
+use crate::error::Error;
+use crate::fault::Faults;
+use crate::inject::{InjectConfig, InjectStatus, Injection};
+use crate::kernel;
+use crate::options::EndpointOptions;
+use crate::recorder::{RecordConfig, RecordStatus, Recorder};
+use crate::replay::{Replay, ReplayConfig, ReplayStatus};
+use crate::tunnel::{Tunnel, TunnelConfig, TunnelStatus};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt::Error;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn, Instrument, Span};
 
+/// Requests that may be queued for a network before it blocks its callers.
+const REQUEST_QUEUE: usize = 32;
+
+/// Interface details handed back to Docker when an endpoint joins a sandbox.
 #[allow(non_snake_case)]
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct JoinResponse {
-    SrcName: String,
-    DstPrefix: String,
+    /// Container side of the endpoint's VXCAN tunnel, still in the host
+    /// namespace.
+    pub SrcName: String,
+    /// Prefix Docker uses to name the interface inside the container.
+    pub DstPrefix: String,
+}
+
+/// Snapshot of a network's state, as returned by
+/// [`NetworkManager::network_inspect`](crate::NetworkManager::network_inspect).
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct NetworkInfo {
+    /// ID the network was created with.
+    pub id: String,
+    /// Host CAN interface backing the network.
+    pub interface: String,
+    /// Prefix of the interface created inside each container.
+    pub peer: String,
+    /// What forwards the network's frames: `kernel` or `userspace`.
+    pub gateway: GatewayMode,
+    /// How the rules connect the endpoints: `mesh` or `hub`.
+    pub topology: Topology,
+    /// Endpoints of the network, ordered by ID.
+    pub endpoints: Vec<EndpointInfo>,
+    /// Installed forwarding rules as sorted `(src, dst)` pairs.
+    pub rules: Vec<(String, String)>,
+    /// Bitrates and load of the emulated bus, if the network emulates one.
+    pub bus: Option<BusStatus>,
+    /// The recording of the network's traffic, if one was started.
+    pub recording: Option<RecordStatus>,
+    /// The replay of recorded traffic onto the network, if one was started.
+    pub replay: Option<ReplayStatus>,
+    /// The injection of error frames into the network, if one was started.
+    pub injection: Option<InjectStatus>,
+    /// The tunnel to other hosts, if the network has one.
+    pub tunnel: Option<TunnelStatus>,
+}
+
+/// State of one endpoint within a [`NetworkInfo`].
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct EndpointInfo {
+    pub id: String,
+    /// Host side of the VXCAN tunnel.
+    pub device: String,
+    /// Container side of the VXCAN tunnel, as named when it was created.
+    pub peer: String,
+    /// Network namespace the endpoint joined, if it is attached.
+    pub namespace: Option<String>,
+    /// Faults injected into the endpoint's frames, if any.
+    pub faults: Option<Faults>,
+    /// Whether the endpoint is a tap, receiving every frame but sending none.
+    pub tap: bool,
+    /// Group of the endpoints it exchanges frames with, if any.
+    pub group: Option<String>,
+}
+
+type Reply<T> = oneshot::Sender<Result<T, Error>>;
+
+/// Operations processed in order by the task owning a [`Network`].
+enum Request {
+    EndpointCreate {
+        uid: String,
+        reply: Reply<()>,
+    },
+    EndpointDelete {
+        uid: String,
+        reply: Reply<()>,
+    },
+    EndpointAttach {
+        uid: String,
+        namespace: String,
+        options: EndpointOptions,
+        reply: Reply<JoinResponse>,
+    },
+    EndpointMove {
+        uid: String,
+        netns: String,
+        ifname: String,
+        reply: Reply<()>,
+    },
+    EndpointDetach {
+        uid: String,
+        reply: Reply<()>,
+    },
+    EndpointFaults {
+        uid: String,
+        faults: Faults,
+        reply: Reply<Faults>,
+    },
+    Inspect {
+        uid: String,
+        reply: Reply<NetworkInfo>,
+    },
+    RecordStart {
+        config: RecordConfig,
+        reply: Reply<RecordStatus>,
+    },
+    RecordStop {
+        reply: Reply<Option<RecordStatus>>,
+    },
+    ReplayStart {
+        config: ReplayConfig,
+        reply: Reply<ReplayStatus>,
+    },
+    ReplayStop {
+        reply: Reply<Option<ReplayStatus>>,
+    },
+    InjectStart {
+        config: InjectConfig,
+        reply: Reply<InjectStatus>,
+    },
+    InjectStop {
+        reply: Reply<Option<InjectStatus>>,
+    },
+    TunnelStart {
+        config: TunnelConfig,
+        reply: Reply<TunnelStatus>,
+    },
+    TunnelNodes {
+        nodes: Vec<IpAddr>,
+        reply: Reply<()>,
+    },
+    HostSocket {
+        reply: Reply<Arc<dyn FrameSocket>>,
+    },
+    Destroy {
+        reply: Reply<()>,
+    },
+}
+
+/// Handle to a network running in its own task.
+///
+/// Every operation on the network is queued and handled one at a time, so
+/// rules for a network are always installed and removed in request order,
+/// while separate networks run independently of each other.
+#[derive(Clone)]
+pub struct NetworkHandle {
+    ifc: String,
+    tx: mpsc::Sender<(Span, Request)>,
+}
+
+impl NetworkHandle {
+    /// Name of the host CAN interface backing the network.
+    pub fn interface(&self) -> &str {
+        &self.ifc
+    }
+
+    /// Create the VXCAN tunnel for endpoint `uid`.
+    pub async fn endpoint_create(&self, uid: String) -> Result<(), Error> {
+        self.call(|reply| Request::EndpointCreate { uid, reply })
+            .await
+    }
+
+    /// Remove endpoint `uid` and its tunnel; unknown endpoints are ignored.
+    pub async fn endpoint_delete(&self, uid: String) -> Result<(), Error> {
+        self.call(|reply| Request::EndpointDelete { uid, reply })
+            .await
+    }
+
+    /// Install the forwarding rules for endpoint `uid`, joining `namespace`,
+    /// with the peer prefix and faults of `options`.
+    pub async fn endpoint_attach(
+        &self,
+        uid: String,
+        namespace: String,
+        options: EndpointOptions,
+    ) -> Result<JoinResponse, Error> {
+        self.call(|reply| Request::EndpointAttach {
+            uid,
+            namespace,
+            options,
+            reply,
+        })
+        .await
+    }
+
+    /// Move the container side of endpoint `uid` into the network namespace
+    /// at `netns`, naming it `ifname` there.
+    pub async fn endpoint_move(
+        &self,
+        uid: String,
+        netns: String,
+        ifname: String,
+    ) -> Result<(), Error> {
+        self.call(|reply| Request::EndpointMove {
+            uid,
+            netns,
+            ifname,
+            reply,
+        })
+        .await
+    }
+
+    /// Remove the forwarding rules of endpoint `uid`.
+    pub async fn endpoint_detach(&self, uid: String) -> Result<(), Error> {
+        self.call(|reply| Request::EndpointDetach { uid, reply })
+            .await
+    }
+
+    /// Replace the faults injected into the frames of endpoint `uid`.
+    pub async fn endpoint_faults(&self, uid: String, faults: Faults) -> Result<Faults, Error> {
+        self.call(|reply| Request::EndpointFaults { uid, faults, reply })
+            .await
+    }
+
+    /// Describe the network, reporting it under the ID `uid`.
+    pub async fn inspect(&self, uid: String) -> Result<NetworkInfo, Error> {
+        self.call(|reply| Request::Inspect { uid, reply }).await
+    }
+
+    /// Start recording the traffic on the network's host device.
+    pub async fn record_start(&self, config: RecordConfig) -> Result<RecordStatus, Error> {
+        self.call(|reply| Request::RecordStart { config, reply })
+            .await
+    }
+
+    /// Stop recording, returning the final state of the recording if there
+    /// was one.
+    pub async fn record_stop(&self) -> Result<Option<RecordStatus>, Error> {
+        self.call(|reply| Request::RecordStop { reply }).await
+    }
+
+    /// Start replaying a log file onto the network's host device.
+    pub async fn replay_start(&self, config: ReplayConfig) -> Result<ReplayStatus, Error> {
+        self.call(|reply| Request::ReplayStart { config, reply })
+            .await
+    }
+
+    /// Stop replaying, returning the final state of the replay if there was
+    /// one.
+    pub async fn replay_stop(&self) -> Result<Option<ReplayStatus>, Error> {
+        self.call(|reply| Request::ReplayStop { reply }).await
+    }
+
+    /// Start injecting error frames into the network.
+    pub async fn inject_start(&self, config: InjectConfig) -> Result<InjectStatus, Error> {
+        self.call(|reply| Request::InjectStart { config, reply })
+            .await
+    }
+
+    /// Stop injecting error frames, returning the final state of the
+    /// injection if there was one.
+    pub async fn inject_stop(&self) -> Result<Option<InjectStatus>, Error> {
+        self.call(|reply| Request::InjectStop { reply }).await
+    }
+
+    /// Start tunneling the frames on the network's host device to other
+    /// hosts.
+    pub async fn tunnel_start(&self, config: TunnelConfig) -> Result<TunnelStatus, Error> {
+        self.call(|reply| Request::TunnelStart { config, reply })
+            .await
+    }
+
+    /// Tunnel to `nodes` if the network's tunnel goes to the nodes
+    /// discovered.
+    pub async fn tunnel_nodes(&self, nodes: Vec<IpAddr>) -> Result<(), Error> {
+        self.call(|reply| Request::TunnelNodes { nodes, reply })
+            .await
+    }
+
+    /// Open a socket on the network's host device, to exchange frames with
+    /// every endpoint.
+    pub async fn host_socket(&self) -> Result<Arc<dyn FrameSocket>, Error> {
+        self.call(|reply| Request::HostSocket { reply }).await
+    }
+
+    /// Tear the network down and stop its task.
+    pub async fn destroy(&self) -> Result<(), Error> {
+        self.call(|reply| Request::Destroy { reply }).await
+    }
+
+    async fn call<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T, Error> {
+        let (reply, rx) = oneshot::channel();
+        // The network's task handles the request within the caller's span
+        self.tx
+            .send((Span::current(), request(reply)))
+            .await
+            .map_err(|_| Error::NetworkStopped(self.ifc.clone()))?;
+        rx.await
+            .map_err(|_| Error::NetworkStopped(self.ifc.clone()))?
+    }
 }
 
+/// A CAN bus on a host device, connecting the endpoints that join it.
+///
+/// A network does nothing on its own; [`Network::spawn`] moves it into a task
+/// and returns the [`NetworkHandle`] used to operate it.
 pub struct Network {
     device: String,
     peer: String,
     canid: u32,
     ifc: String,
-    created: bool,
-    endpoint_list: Arc<RwLock<HashMap<String, Endpoint>>>,
-    rules_list: Arc<RwLock<Vec<(String, String)>>>,
+    gateway: GatewayMode,
+    topology: Topology,
+    bus: Option<Arc<Bus>>,
+    backend: Backend,
+    endpoint_list: BTreeMap<String, Endpoint>,
+    rules_list: HashSet<(String, String)>,
+    recorder: Option<Recorder>,
+    replay: Option<Replay>,
+    injection: Option<Injection>,
+    tunnel: Option<Tunnel>,
 }
 
 impl Network {
-    pub fn new(device: String, peer: String, canid: u32) -> Self {
-        let ifcs = interfaces::Interface::get_all().unwrap();
+    /// Create the network object. The host device is managed by the
+    /// `NetworkManager`, as several networks may share it. `gateway` names
+    /// what `backend.gateway` is, for inspection. A `Hub` topology, and
+    /// pacing frames like on a CAN bus at the bitrates of `bus`, need a
+    /// gateway forwarding in userspace.
+    pub fn new(
+        device: String,
+        peer: String,
+        canid: u32,
+        gateway: GatewayMode,
+        topology: Topology,
+        bus: Option<BusConfig>,
+        backend: Backend,
+    ) -> Self {
+        let ifc = format!("{device}{canid}");
+        info!(%device, %peer, canid, ?gateway, ?topology, ?bus, "creating network");
+        Network {
+            device,
+            peer,
+            canid,
+            ifc,
+            gateway,
+            topology,
+            bus: bus.map(|config| Arc::new(Bus::new(config))),
+            backend,
+            endpoint_list: BTreeMap::new(),
+            rules_list: HashSet::new(),
+            recorder: None,
+            replay: None,
+            injection: None,
+            tunnel: None,
+        }
+    }
 
-        let mut exists: bool = false;
-        let newifc = format!("{device}{canid}");
+    /// Move the network into its own task and return a handle to it.
+    pub fn spawn(self) -> NetworkHandle {
+        let (tx, rx) = mpsc::channel(REQUEST_QUEUE);
+        let handle = NetworkHandle {
+            ifc: self.ifc.clone(),
+            tx,
+        };
+        tokio::spawn(self.run(rx));
+        handle
+    }
 
-        for i in ifcs.into_iter() {
-            if i.name.eq(&newifc) {
-                exists = true;
+    async fn run(mut self, mut rx: mpsc::Receiver<(Span, Request)>) {
+        while let Some((span, request)) = rx.recv().await {
+            if !self.handle(request).instrument(span).await {
+                break;
             }
         }
+    }
 
-        if !exists {
-            println!(" -> Creating interface {newifc}...");
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("add")
-                .arg("dev")
-                .arg(&newifc)
-                .arg("type")
-                .arg("vcan")
-                .output()
-                .expect(" !! Failed to add VCAN device");
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("set")
-                .arg("up")
-                .arg(&newifc)
-                .output()
-                .expect(" !! Failed to start VCAN device");
-        }
-        println!(
-            " -> Creating network with settings: device='{}', peer='{}', id='{}' -- new device? {}",
-            device,
-            peer,
-            canid.to_string(),
-            !exists
-        );
-        Network {
-            device: device,
-            peer: peer,
-            canid: canid,
-            ifc: newifc,
-            created: !exists,
-            endpoint_list: Arc::new(RwLock::new(HashMap::new())),
-            rules_list: Arc::new(RwLock::new(Vec::new())),
+    /// Handle one request; returns whether the network is still running.
+    async fn handle(&mut self, request: Request) -> bool {
+        match request {
+            Request::EndpointCreate { uid, reply } => {
+                let _ = reply.send(self.endpoint_create(uid).await);
+            }
+            Request::EndpointDelete { uid, reply } => {
+                let _ = reply.send(self.endpoint_delete(uid).await);
+            }
+            Request::EndpointAttach {
+                uid,
+                namespace,
+                options,
+                reply,
+            } => {
+                let _ = reply.send(self.endpoint_attach(uid, namespace, options).await);
+            }
+            Request::EndpointMove {
+                uid,
+                netns,
+                ifname,
+                reply,
+            } => {
+                let _ = reply.send(self.endpoint_move(uid, netns, ifname).await);
+            }
+            Request::EndpointDetach { uid, reply } => {
+                let _ = reply.send(self.endpoint_detach(uid).await);
+            }
+            Request::EndpointFaults { uid, faults, reply } => {
+                let _ = reply.send(self.endpoint_faults(uid, faults).await);
+            }
+            Request::Inspect { uid, reply } => {
+                let _ = reply.send(Ok(self.inspect(uid)));
+            }
+            Request::RecordStart { config, reply } => {
+                let _ = reply.send(self.record_start(config).await);
+            }
+            Request::RecordStop { reply } => {
+                let _ = reply.send(Ok(self.record_stop().await));
+            }
+            Request::ReplayStart { config, reply } => {
+                let _ = reply.send(self.replay_start(config).await);
+            }
+            Request::ReplayStop { reply } => {
+                let _ = reply.send(Ok(self.replay_stop().await));
+            }
+            Request::InjectStart { config, reply } => {
+                let _ = reply.send(self.inject_start(config).await);
+            }
+            Request::InjectStop { reply } => {
+                let _ = reply.send(Ok(self.inject_stop().await));
+            }
+            Request::TunnelStart { config, reply } => {
+                let _ = reply.send(self.tunnel_start(config).await);
+            }
+            Request::TunnelNodes { nodes, reply } => {
+                self.tunnel_nodes(&nodes);
+                let _ = reply.send(Ok(()));
+            }
+            Request::HostSocket { reply } => {
+                let _ = reply.send(self.backend.frames.open(&self.ifc).await);
+            }
+            Request::Destroy { reply } => {
+                // Keep running after a failure so the teardown can be retried
+                let res = self.destroy().await;
+                let done = res.is_ok();
+                let _ = reply.send(res);
+                return !done;
+            }
         }
+        true
     }
 
-    pub fn endpoint_add(&mut self, ep: Endpoint) {
+    async fn endpoint_create(&mut self, uid: String) -> Result<(), Error> {
         // Add the endpoint to the list
-        self.endpoint_list.write().insert(ep.uid.clone(), ep);
+        let ep = Endpoint::create(uid, self.backend.links.clone()).await?;
+        self.endpoint_list.insert(ep.uid.clone(), ep);
+        Ok(())
     }
 
-    pub fn endpoint_remove(&mut self, uid: String) {
-        let mut map = self.endpoint_list.write();
-        if map.contains_key(&uid) {
-            println!(" -> Endpoint {uid} exists...removing!");
-            map.remove(&uid);
-        }
+    async fn endpoint_delete(&mut self, uid: String) -> Result<(), Error> {
+        let ep = match self.endpoint_list.get_mut(&uid) {
+            Some(ep) => ep,
+            None => return Ok(()),
+        };
+        info!(endpoint = %uid, "removing endpoint");
+        // Keep the endpoint while its tunnel exists, so it can be retried
+        ep.destroy().await?;
+        self.endpoint_list.remove(&uid);
+        Ok(())
     }
 
-    pub fn endpoint_attach(
+    async fn endpoint_attach(
         &mut self,
         epuid: String,
-        _namespace: String,
-        peer: String,
+        namespace: String,
+        options: EndpointOptions,
     ) -> Result<JoinResponse, Error> {
-        let map = self.endpoint_list.read();
-        match map.get(&epuid) {
-            Some(ep) => {
-                // Add cangw rules: self->endpoint, endpoint->self
-                self.add_cangw_rule(&self.ifc, &ep.device);
-                self.add_cangw_rule(&ep.device, &self.ifc);
-
-                for (uid, endpt) in map.iter() {
-                    if uid.ne(&epuid) {
-                        // Add cangw rules: other->endpoint, endpoint->other
-                        self.add_cangw_rule(&endpt.device, &ep.device);
-                        self.add_cangw_rule(&ep.device, &endpt.device);
+        self.faults_check(&options.faults)?;
+        let ep = match self.endpoint_list.get(&epuid) {
+            Some(ep) => ep,
+            None => return Err(Error::EndpointNotFound(epuid)),
+        };
+        // The endpoint keeps its previous options until its rules are in
+        // place, so the rules come from the new ones
+        let device = ep.device.clone();
+
+        // Add cangw rules: self->endpoint, endpoint->self
+        let mut rules = vec![(self.ifc.clone(), device.clone())];
+        if !options.tap {
+            rules.push((device.clone(), self.ifc.clone()));
+        }
+        // Nothing is forwarded from a tap or between groups other than to a
+        // tap, not even by rules other endpoints added before this one joined
+        let mut stale = Vec::new();
+        for (uid, endpt) in self.endpoint_list.iter() {
+            // Around a hub, frames between endpoints go through the device
+            if uid.ne(&epuid) && self.topology != Topology::Hub {
+                // Add cangw rules: other->endpoint, endpoint->other
+                let grouped = endpt.group == options.group;
+                let pairs = [
+                    (
+                        endpt.device.clone(),
+                        device.clone(),
+                        !endpt.tap,
+                        options.tap,
+                    ),
+                    (
+                        device.clone(),
+                        endpt.device.clone(),
+                        !options.tap,
+                        endpt.tap,
+                    ),
+                ];
+                for (src, dst, sends, taps) in pairs {
+                    if sends && (grouped || taps) {
+                        rules.push((src, dst));
+                    } else {
+                        stale.push((src, dst));
                     }
                 }
+            }
+        }
 
-                let mut peerifc = &peer;
-                if peer.is_empty() {
-                    peerifc = &self.peer;
-                }
+        let peer = &options.peer;
+        let mut peerifc = peer;
+        if peer.is_empty() {
+            peerifc = &self.peer;
+        }
+        let rsp = JoinResponse {
+            SrcName: ep.peer.clone(),
+            DstPrefix: (*peerifc).clone(),
+        };
 
-                let rsp = JoinResponse {
-                    SrcName: ep.peer.clone(),
-                    DstPrefix: (*peerifc).clone(),
-                };
-                Ok(rsp)
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        let res = self
+            .endpoint_install(&device, &options, stale, rules, &mut removed, &mut added)
+            .await;
+        if let Err(e) = res {
+            warn!(endpoint = %epuid, error = %e, "unable to join, restoring previous rules");
+            self.endpoint_restore(&epuid, removed, added).await;
+            return Err(e);
+        }
+        if let Some(ep) = self.endpoint_list.get_mut(&epuid) {
+            ep.faults = options.faults;
+            ep.tap = options.tap;
+            ep.group = options.group;
+            ep.namespace = Some(namespace).filter(|n| !n.is_empty());
+        }
+        Ok(rsp)
+    }
+
+    /// Replace the `stale` rules of an endpoint joining on `device` with
+    /// `rules` and apply its `options`, noting the rules `removed` and
+    /// `added` so far.
+    async fn endpoint_install(
+        &mut self,
+        device: &str,
+        options: &EndpointOptions,
+        stale: Vec<(String, String)>,
+        rules: Vec<(String, String)>,
+        removed: &mut Vec<(String, String)>,
+        added: &mut Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        for (src, dst) in stale {
+            let rule = (src.clone(), dst.clone());
+            if self.rules_list.contains(&rule) {
+                self.remove_cangw_rule(src, dst).await?;
+                removed.push(rule);
+            }
+        }
+        for (src, dst) in rules {
+            let rule = (src.clone(), dst.clone());
+            if !self.rules_list.contains(&rule) {
+                self.add_cangw_rule(src, dst).await?;
+                added.push(rule);
             }
-            None => Err(Error),
         }
+        let gateway = &self.backend.gateway;
+        if self.topology == Topology::Hub {
+            gateway.group_set(device, options.group.as_deref()).await?;
+            gateway.tap_set(device, options.tap).await?;
+        }
+        self.faults_apply(device, options.faults).await?;
+        if let Some(bus) = &self.bus {
+            gateway.bus_set(&self.ifc, Some(bus.clone())).await?;
+            gateway.bus_set(device, Some(bus.clone())).await?;
+        }
+        Ok(())
     }
 
-    pub fn endpoint_detach(&mut self, epuid: String) {
-        let map = self.endpoint_list.read();
-        match map.get(&epuid) {
-            Some(ep) => {
-                for (uid, endpt) in map.iter() {
-                    if uid.ne(&epuid) {
-                        // Remove cangw rules: other->endpoint, endpoint->other
-                        self.remove_cangw_rule(&endpt.device, &ep.device);
-                        self.remove_cangw_rule(&ep.device, &endpt.device);
-                    }
+    /// Put back the rules and settings endpoint `epuid` had before a join
+    /// that `removed` and `added` rules failed. Failures are only logged.
+    async fn endpoint_restore(
+        &mut self,
+        epuid: &str,
+        removed: Vec<(String, String)>,
+        added: Vec<(String, String)>,
+    ) {
+        for (src, dst) in added {
+            if let Err(e) = self.remove_cangw_rule(src.clone(), dst.clone()).await {
+                warn!(%src, %dst, error = %e, "unable to remove gateway rule");
+            }
+        }
+        for (src, dst) in removed {
+            if let Err(e) = self.add_cangw_rule(src.clone(), dst.clone()).await {
+                warn!(%src, %dst, error = %e, "unable to restore gateway rule");
+            }
+        }
+        let Some(ep) = self.endpoint_list.get(epuid) else {
+            return;
+        };
+        let (device, faults) = (ep.device.clone(), ep.faults);
+        let gateway = &self.backend.gateway;
+        if self.topology == Topology::Hub {
+            let restored = gateway
+                .group_set(&device, ep.group.as_deref())
+                .await
+                .and(gateway.tap_set(&device, ep.tap).await);
+            if let Err(e) = restored {
+                warn!(%device, error = %e, "unable to restore group");
+            }
+        }
+        if let Err(e) = self.faults_apply(&device, faults).await {
+            warn!(%device, error = %e, "unable to restore faults");
+        }
+    }
+
+    async fn endpoint_move(
+        &mut self,
+        epuid: String,
+        netns: String,
+        ifname: String,
+    ) -> Result<(), Error> {
+        let ep = match self.endpoint_list.get_mut(&epuid) {
+            Some(ep) => ep,
+            None => return Err(Error::EndpointNotFound(epuid)),
+        };
+        info!(link = %ep.peer, %netns, %ifname, "moving link into namespace");
+        self.backend
+            .links
+            .link_move(&ep.peer, &netns, &ifname)
+            .await?;
+        ep.namespace = Some(netns);
+        Ok(())
+    }
+
+    async fn endpoint_detach(&mut self, epuid: String) -> Result<(), Error> {
+        let mut rules: Vec<(String, String)> = Vec::new();
+        if let Some(ep) = self.endpoint_list.get(&epuid) {
+            for (uid, endpt) in self.endpoint_list.iter() {
+                if uid.ne(&epuid) {
+                    // Remove cangw rules: other->endpoint, endpoint->other
+                    rules.push((endpt.device.clone(), ep.device.clone()));
+                    rules.push((ep.device.clone(), endpt.device.clone()));
                 }
+            }
+
+            // Remove cangw rules: self->endpoint, endpoint->self
+            rules.push((ep.device.clone(), self.ifc.clone()));
+            rules.push((self.ifc.clone(), ep.device.clone()));
+        }
 
-                // Remove cangw rules: self->endpoint, endpoint->self
-                self.remove_cangw_rule(&ep.device, &self.ifc);
-                self.remove_cangw_rule(&self.ifc, &ep.device);
+        for (src, dst) in rules {
+            self.remove_cangw_rule(src, dst).await?;
+        }
+        if let Some(ep) = self.endpoint_list.get_mut(&epuid) {
+            ep.namespace = None;
+            if self.bus.is_some() {
+                self.backend.gateway.bus_set(&ep.device, None).await?;
+            }
+            if self.topology == Topology::Hub {
+                self.backend.gateway.group_set(&ep.device, None).await?;
+                self.backend.gateway.tap_set(&ep.device, false).await?;
             }
-            None => (),
+        }
+        Ok(())
+    }
+
+    async fn endpoint_faults(&mut self, epuid: String, faults: Faults) -> Result<Faults, Error> {
+        faults.validate()?;
+        self.faults_check(&faults)?;
+        let device = match self.endpoint_list.get(&epuid) {
+            Some(ep) => ep.device.clone(),
+            None => return Err(Error::EndpointNotFound(epuid)),
         };
+        info!(endpoint = %epuid, ?faults, "injecting faults");
+        self.faults_apply(&device, faults).await?;
+        if let Some(ep) = self.endpoint_list.get_mut(&epuid) {
+            ep.faults = faults;
+        }
+        Ok(faults)
     }
 
-    fn add_cangw_rule(&self, src: &String, dst: &String) {
-        println!(" -> Adding cangw rule for {src} to {dst}");
-
-        std::process::Command::new("cangw")
-            .arg("-A")
-            .arg("-s")
-            .arg(&src)
-            .arg("-d")
-            .arg(&dst)
-            .arg("-e")
-            .output()
-            .expect(" !! Failed to add cangw rule");
-
-        std::process::Command::new("cangw")
-            .arg("-A")
-            .arg("-s")
-            .arg(&src)
-            .arg("-d")
-            .arg(&dst)
-            .arg("-eX")
-            .output()
-            .expect(" !! Failed to add cangw extended rule");
-
-        self.rules_list.write().push((src.clone(), dst.clone()));
-    }
-
-    fn remove_cangw_rule(&self, src: &String, dst: &String) {
-        let mut rules = self.rules_list.write();
-        if rules.contains(&(src.clone(), dst.clone())) {
-            println!(" -> Removing cangw rule for {src} to {dst}");
-
-            std::process::Command::new("cangw")
-                .arg("-D")
-                .arg("-s")
-                .arg(&src)
-                .arg("-d")
-                .arg(&dst)
-                .arg("-e")
-                .output()
-                .expect(" !! Failed to remove cangw rule");
-
-            std::process::Command::new("cangw")
-                .arg("-D")
-                .arg("-s")
-                .arg(&src)
-                .arg("-d")
-                .arg(&dst)
-                .arg("-eX")
-                .output()
-                .expect(" !! Failed to remove cangw extended rule");
-
-            let index = rules
-                .iter()
-                .position(|x| *x == (src.clone(), dst.clone()))
-                .unwrap(); // safe unwrap since we already verified key exists
-            rules.remove(index);
+    /// Fail unless the network's gateway can inject `faults`.
+    fn faults_check(&self, faults: &Faults) -> Result<(), Error> {
+        if faults.is_none() || self.gateway == GatewayMode::Userspace {
+            return Ok(());
         }
+        Err(Error::Unsupported(format!(
+            "faults can only be injected into frames forwarded in userspace, create the network on {} with vxcan.gateway=userspace",
+            self.ifc
+        )))
     }
-}
 
-impl Drop for Network {
-    fn drop(&mut self) {
-        if self.created {
-            let ifc = format!("{}{}", self.device, self.canid);
-
-            // Actually delete the network interface
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("set")
-                .arg("down")
-                .arg(&ifc)
-                .output()
-                .expect(" !! Failed to stop VCAN device");
-            std::process::Command::new("ip")
-                .arg("link")
-                .arg("del")
-                .arg("dev")
-                .arg(&ifc)
-                .arg("type")
-                .arg("vcan")
-                .output()
-                .expect(" !! Failed to remove VCAN device");
-
-            println!(
-                " -> Dropping network object: device={}, peer={}, id={}",
-                self.device,
-                self.peer,
-                self.canid.to_string()
-            );
+    /// Update the faults of every rule to or from `device`, whose endpoint
+    /// gets `faults`. A frame going from one endpoint to another suffers the
+    /// faults of both.
+    async fn faults_apply(&self, device: &str, faults: Faults) -> Result<(), Error> {
+        if self.gateway != GatewayMode::Userspace {
+            return Ok(());
+        }
+        let faults_of = |ifc: &str| {
+            if ifc == device {
+                return faults;
+            }
+            self.endpoint_list
+                .values()
+                .find(|ep| ep.device == ifc)
+                .map(|ep| ep.faults)
+                .unwrap_or_default()
+        };
+        for (src, dst) in self.rules_list.iter() {
+            if src == device || dst == device {
+                let both = faults_of(src).then(&faults_of(dst));
+                self.backend.gateway.rule_faults(src, dst, &both).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn inspect(&self, uid: String) -> NetworkInfo {
+        let mut rules: Vec<(String, String)> = self.rules_list.iter().cloned().collect();
+        rules.sort();
+        NetworkInfo {
+            id: uid,
+            interface: self.ifc.clone(),
+            peer: self.peer.clone(),
+            gateway: self.gateway,
+            topology: self.topology,
+            endpoints: self
+                .endpoint_list
+                .values()
+                .map(|ep| EndpointInfo {
+                    id: ep.uid.clone(),
+                    device: ep.device.clone(),
+                    peer: ep.peer.clone(),
+                    namespace: ep.namespace.clone(),
+                    faults: Some(ep.faults).filter(|f| !f.is_none()),
+                    tap: ep.tap,
+                    group: ep.group.clone(),
+                })
+                .collect(),
+            rules,
+            bus: self.bus.as_ref().map(|b| b.status()),
+            recording: self.recorder.as_ref().map(|r| r.status()),
+            replay: self.replay.as_ref().map(|r| r.status()),
+            injection: self.injection.as_ref().map(|i| i.status()),
+            tunnel: self.tunnel.as_ref().map(|t| t.status()),
+        }
+    }
+
+    async fn record_start(&mut self, config: RecordConfig) -> Result<RecordStatus, Error> {
+        // A recording that failed may be replaced
+        if let Some(rec) = self.recorder.as_ref().filter(|r| r.running()) {
+            return Err(Error::Busy(format!(
+                "network on {} is already recording to {}",
+                self.ifc,
+                rec.status().config.path.display()
+            )));
+        }
+        let socket = self.backend.frames.open(&self.ifc).await?;
+        let rec = Recorder::start(&self.ifc, socket, config).await?;
+        let status = rec.status();
+        self.recorder = Some(rec);
+        Ok(status)
+    }
+
+    async fn record_stop(&mut self) -> Option<RecordStatus> {
+        match self.recorder.take() {
+            Some(rec) => Some(rec.stop().await),
+            None => None,
+        }
+    }
+
+    async fn tunnel_start(&mut self, config: TunnelConfig) -> Result<TunnelStatus, Error> {
+        // A tunnel that failed may be replaced
+        if let Some(tunnel) = self.tunnel.as_ref().filter(|t| t.running()) {
+            return Err(Error::Busy(format!(
+                "network on {} already tunnels frames from {}",
+                self.ifc,
+                tunnel.status().local
+            )));
+        }
+        let socket = self.backend.frames.open(&self.ifc).await?;
+        let tunnel = Tunnel::start(&self.ifc, socket, config).await?;
+        let status = tunnel.status();
+        self.tunnel = Some(tunnel);
+        Ok(status)
+    }
+
+    fn tunnel_nodes(&self, nodes: &[IpAddr]) {
+        if let Some(tunnel) = &self.tunnel {
+            let config = tunnel.status().config;
+            if config.discover {
+                tunnel.peers_set(config.node_peers(nodes));
+            }
+        }
+    }
+
+    async fn tunnel_stop(&mut self) {
+        if let Some(tunnel) = self.tunnel.take() {
+            tunnel.stop().await;
+        }
+    }
+
+    async fn replay_start(&mut self, config: ReplayConfig) -> Result<ReplayStatus, Error> {
+        // A finished replay may be replaced
+        if let Some(replay) = self.replay.as_ref().filter(|r| r.running()) {
+            return Err(Error::Busy(format!(
+                "network on {} is already replaying {}",
+                self.ifc,
+                replay.status().config.file.display()
+            )));
+        }
+        let socket = self.backend.frames.open(&self.ifc).await?;
+        let replay = Replay::start(&self.ifc, socket, config).await?;
+        let status = replay.status();
+        self.replay = Some(replay);
+        Ok(status)
+    }
+
+    async fn replay_stop(&mut self) -> Option<ReplayStatus> {
+        match self.replay.take() {
+            Some(replay) => Some(replay.stop().await),
+            None => None,
+        }
+    }
+
+    async fn inject_start(&mut self, config: InjectConfig) -> Result<InjectStatus, Error> {
+        // A finished injection may be replaced
+        if self.injection.as_ref().is_some_and(|i| i.running()) {
+            return Err(Error::Busy(format!(
+                "network on {} is already injecting error frames",
+                self.ifc
+            )));
+        }
+        let devices = match &config.endpoint {
+            Some(uid) => match self.endpoint_list.get(uid) {
+                Some(ep) => vec![ep.device.clone()],
+                None => return Err(Error::EndpointNotFound(uid.clone())),
+            },
+            None => std::iter::once(self.ifc.clone())
+                .chain(self.endpoint_list.values().map(|ep| ep.device.clone()))
+                .collect(),
+        };
+        let mut sockets = Vec::new();
+        for device in devices {
+            let socket = self.backend.frames.open(&device).await?;
+            sockets.push((device, socket));
+        }
+        let injection = Injection::start(sockets, config)?;
+        let status = injection.status();
+        self.injection = Some(injection);
+        Ok(status)
+    }
+
+    async fn inject_stop(&mut self) -> Option<InjectStatus> {
+        match self.injection.take() {
+            Some(injection) => Some(injection.stop().await),
+            None => None,
+        }
+    }
+
+    /// Tear down the network: stop tunneling, injecting, replaying and
+    /// recording, then detach and destroy any endpoints Docker did not
+    /// delete.
+    async fn destroy(&mut self) -> Result<(), Error> {
+        self.tunnel_stop().await;
+        self.inject_stop().await;
+        self.replay_stop().await;
+        self.record_stop().await;
+        // Tear down everything that can be, reporting the first failure
+        let mut res = Ok(());
+        let uids: Vec<String> = self.endpoint_list.keys().cloned().collect();
+        for uid in uids {
+            let detached = self.endpoint_detach(uid.clone()).await;
+            let deleted = self.endpoint_delete(uid.clone()).await;
+            if let Err(e) = detached.and(deleted) {
+                warn!(endpoint = %uid, error = %e, "unable to remove endpoint");
+                res = res.and(Err(e));
+            }
+        }
+        if self.bus.is_some() {
+            res = res.and(self.backend.gateway.bus_set(&self.ifc, None).await);
+        }
+        res?;
+
+        info!(
+            device = %self.device,
+            peer = %self.peer,
+            canid = self.canid,
+            "destroyed network"
+        );
+        Ok(())
+    }
+
+    async fn add_cangw_rule(&mut self, src: String, dst: String) -> Result<(), Error> {
+        if self.rules_list.contains(&(src.clone(), dst.clone())) {
+            return Ok(());
+        }
+        debug!(%src, %dst, "adding gateway rule");
+
+        let gateway = &self.backend.gateway;
+        if self.topology == Topology::Hub && dst == self.ifc {
+            gateway.hub_rule_add(&src, &dst).await?;
+        } else {
+            gateway.rule_add(&src, &dst).await?;
+        }
+
+        self.rules_list.insert((src, dst));
+        Ok(())
+    }
+
+    async fn remove_cangw_rule(&mut self, src: String, dst: String) -> Result<(), Error> {
+        let rule = (src, dst);
+        if self.rules_list.contains(&rule) {
+            let (src, dst) = &rule;
+            debug!(%src, %dst, "removing gateway rule");
+
+            let gateway = &self.backend.gateway;
+            kernel::retry(|| gateway.rule_del(src, dst)).await?;
+
+            self.rules_list.remove(&rule);
         }
+        Ok(())
     }
 }
@@ -0,0 +1,705 @@
+/*
+ * Filename: options.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::{GatewayMode, Topology};
+use crate::bus::BusConfig;
+use crate::error::Error;
+use crate::fault::{self, Faults};
+use crate::recorder::{RecordConfig, RecordFormat};
+use crate::tunnel::{self, TunnelConfig};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Longest interface name the kernel accepts (IFNAMSIZ minus the NUL).
+const IFNAME_MAX: usize = 15;
+
+/// Docker appends an index to the peer prefix; leave room for three digits.
+const PEER_PREFIX_MAX: usize = IFNAME_MAX - 3;
+
+/// Largest value accepted for `vxcan.id`.
+const CANID_MAX: u64 = 9999;
+
+/// Largest log file size in MiB accepted for `vxcan.record-size`.
+const RECORD_SIZE_MAX: u64 = 4096;
+
+/// Largest number of log files accepted for `vxcan.record-files`.
+const RECORD_FILES_MAX: u64 = 1000;
+
+/// Longest group name accepted for `vxcan.group`.
+const GROUP_MAX: usize = 64;
+
+/// Smallest bitrate in bit/s accepted for the emulated bus.
+const BITRATE_MIN: u64 = 1000;
+
+/// Largest bitrate in bit/s accepted for `vxcan.emulate-bitrate`.
+const BITRATE_MAX: u64 = 1_000_000;
+
+/// Largest bitrate in bit/s accepted for `vxcan.emulate-data-bitrate`.
+const DATA_BITRATE_MAX: u64 = 12_000_000;
+
+enum Kind {
+    /// Interface name, name prefix or other name of at most `max_len`
+    /// characters.
+    Name { max_len: usize },
+    /// Unsigned integer in the inclusive range `min..=max`.
+    Integer { min: u64, max: u64 },
+    /// `true` or `false`.
+    Bool,
+    /// Absolute file system path.
+    Path,
+    /// One of a fixed set of words.
+    Choice(&'static [&'static str]),
+    /// Decimal number between 0 and 100.
+    Percent,
+    /// IP address and port, like `192.0.2.1:20000` or `[2001:db8::1]:20000`.
+    Address,
+    /// Comma-separated list of IP addresses and ports.
+    Addresses,
+}
+
+struct OptionSpec {
+    key: &'static str,
+    kind: Kind,
+    /// Set when this key is a deprecated spelling of another option.
+    replaced_by: Option<&'static str>,
+    /// Options this one is meaningless without.
+    requires: &'static [&'static str],
+}
+
+/// Two options that may not be given together. Each is either a key, which
+/// matches any value, or `key=value`, which matches that value only.
+struct Conflict {
+    options: [&'static str; 2],
+    /// Why the combination is refused.
+    reason: &'static str,
+}
+
+const NETWORK_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        key: "vxcan.dev",
+        kind: Kind::Name {
+            max_len: IFNAME_MAX - 1,
+        },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.peer",
+        kind: Kind::Name {
+            max_len: PEER_PREFIX_MAX,
+        },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.id",
+        kind: Kind::Integer {
+            min: 0,
+            max: CANID_MAX,
+        },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.shared",
+        kind: Kind::Bool,
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.gateway",
+        kind: Kind::Choice(&["kernel", "userspace", "auto"]),
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.topology",
+        kind: Kind::Choice(&["mesh", "hub", "auto"]),
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.device",
+        kind: Kind::Name {
+            max_len: IFNAME_MAX - 1,
+        },
+        replaced_by: Some("vxcan.dev"),
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.canid",
+        kind: Kind::Integer {
+            min: 0,
+            max: CANID_MAX,
+        },
+        replaced_by: Some("vxcan.id"),
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.record",
+        kind: Kind::Path,
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.record-format",
+        kind: Kind::Choice(&["candump", "asc"]),
+        replaced_by: None,
+        requires: &["vxcan.record"],
+    },
+    OptionSpec {
+        key: "vxcan.record-size",
+        kind: Kind::Integer {
+            min: 1,
+            max: RECORD_SIZE_MAX,
+        },
+        replaced_by: None,
+        requires: &["vxcan.record"],
+    },
+    OptionSpec {
+        key: "vxcan.record-files",
+        kind: Kind::Integer {
+            min: 1,
+            max: RECORD_FILES_MAX,
+        },
+        replaced_by: None,
+        requires: &["vxcan.record"],
+    },
+    OptionSpec {
+        key: "vxcan.emulate-bitrate",
+        kind: Kind::Integer {
+            min: BITRATE_MIN,
+            max: BITRATE_MAX,
+        },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.emulate-data-bitrate",
+        kind: Kind::Integer {
+            min: BITRATE_MIN,
+            max: DATA_BITRATE_MAX,
+        },
+        replaced_by: None,
+        requires: &["vxcan.emulate-bitrate"],
+    },
+    OptionSpec {
+        key: "vxcan.tunnel",
+        kind: Kind::Address,
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.tunnel-peers",
+        kind: Kind::Addresses,
+        replaced_by: None,
+        requires: &["vxcan.tunnel"],
+    },
+    OptionSpec {
+        key: "vxcan.tunnel-batch",
+        kind: Kind::Integer {
+            min: 0,
+            max: tunnel::BATCH_MAX,
+        },
+        replaced_by: None,
+        requires: &["vxcan.tunnel"],
+    },
+];
+
+const NETWORK_CONFLICTS: &[Conflict] = &[
+    Conflict {
+        options: ["vxcan.device", "vxcan.dev"],
+        reason: "vxcan.device is the old spelling of vxcan.dev",
+    },
+    Conflict {
+        options: ["vxcan.canid", "vxcan.id"],
+        reason: "vxcan.canid is the old spelling of vxcan.id",
+    },
+    Conflict {
+        options: ["vxcan.topology=hub", "vxcan.gateway=kernel"],
+        reason: "can-gw cannot keep a frame from returning to its sender",
+    },
+    Conflict {
+        options: ["vxcan.emulate-bitrate", "vxcan.gateway=kernel"],
+        reason: "only frames forwarded by the plugin can be paced",
+    },
+];
+
+const ENDPOINT_OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        key: "vxcan.peer",
+        kind: Kind::Name {
+            max_len: PEER_PREFIX_MAX,
+        },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.tap",
+        kind: Kind::Bool,
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.group",
+        kind: Kind::Name { max_len: GROUP_MAX },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.drop",
+        kind: Kind::Percent,
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.delay",
+        kind: Kind::Integer {
+            min: 0,
+            max: fault::DELAY_MAX,
+        },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.jitter",
+        kind: Kind::Integer {
+            min: 0,
+            max: fault::DELAY_MAX,
+        },
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.corrupt",
+        kind: Kind::Percent,
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.duplicate",
+        kind: Kind::Percent,
+        replaced_by: None,
+        requires: &[],
+    },
+    OptionSpec {
+        key: "vxcan.reorder",
+        kind: Kind::Percent,
+        replaced_by: None,
+        requires: &[],
+    },
+];
+
+/// Validated options of a `docker network create --driver rustyvxcan` call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkOptions {
+    /// Prefix of the host CAN device (`vxcan.dev`).
+    pub device: String,
+    /// Prefix of the interface created inside each container (`vxcan.peer`).
+    pub peer: String,
+    /// Numerical identifier appended to `device` (`vxcan.id`).
+    pub canid: u32,
+    /// Whether other networks may use the same host device (`vxcan.shared`).
+    pub shared: bool,
+    /// What forwards frames between the device and the endpoints
+    /// (`vxcan.gateway`).
+    pub gateway: GatewayMode,
+    /// How the rules connect the device and the endpoints
+    /// (`vxcan.topology`).
+    pub topology: Topology,
+    /// Where and how to record the network's traffic from the start
+    /// (`vxcan.record` and the `vxcan.record-*` options).
+    pub record: Option<RecordConfig>,
+    /// Bitrates of the CAN bus whose timing the network emulates
+    /// (`vxcan.emulate-bitrate` and `vxcan.emulate-data-bitrate`).
+    pub bus: Option<BusConfig>,
+    /// Where to tunnel the network's frames to other hosts (`vxcan.tunnel`,
+    /// `vxcan.tunnel-peers` and `vxcan.tunnel-batch`). Without
+    /// `vxcan.tunnel-peers`, to the nodes Docker reports.
+    pub tunnel: Option<TunnelConfig>,
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        NetworkOptions {
+            device: String::from("vcan"),
+            peer: String::from("vcanp"),
+            canid: 0,
+            shared: false,
+            gateway: GatewayMode::Auto,
+            topology: Topology::Auto,
+            record: None,
+            bus: None,
+            tunnel: None,
+        }
+    }
+}
+
+impl NetworkOptions {
+    /// Parse the `com.docker.network.generic` object sent by Docker.
+    ///
+    /// A missing (`null`) object yields the defaults. Unknown keys, values of
+    /// the wrong type or range and conflicting options are rejected;
+    /// deprecated keys are accepted with a warning.
+    pub fn parse(options: &serde_json::Value) -> Result<Self, Error> {
+        let values = parse_with(NETWORK_OPTIONS, NETWORK_CONFLICTS, options)?;
+
+        let mut opts = NetworkOptions::default();
+        if let Some(d) = values.get("vxcan.dev") {
+            opts.device = d.clone();
+        }
+        if let Some(p) = values.get("vxcan.peer") {
+            opts.peer = p.clone();
+        }
+        if let Some(c) = values.get("vxcan.id") {
+            // Range was checked against the schema already
+            opts.canid = c.parse().map_err(|_| invalid("vxcan.id", c, "integer"))?;
+        }
+        if let Some(b) = values.get("vxcan.shared") {
+            opts.shared = b == "true";
+        }
+        if let Some(g) = values.get("vxcan.gateway") {
+            opts.gateway = GatewayMode::parse(g)?;
+        }
+        if let Some(t) = values.get("vxcan.topology") {
+            opts.topology = Topology::parse(t)?;
+        }
+        if let Some(path) = values.get("vxcan.record") {
+            let mut record = RecordConfig::new(path.into());
+            if let Some(f) = values.get("vxcan.record-format") {
+                record.format = RecordFormat::parse(f)?;
+            }
+            if let Some(s) = values.get("vxcan.record-size") {
+                let mib: u64 = s
+                    .parse()
+                    .map_err(|_| invalid("vxcan.record-size", s, "integer"))?;
+                record.max_size = mib << 20;
+            }
+            if let Some(n) = values.get("vxcan.record-files") {
+                record.max_files = n
+                    .parse()
+                    .map_err(|_| invalid("vxcan.record-files", n, "integer"))?;
+            }
+            opts.record = Some(record);
+        }
+        if let Some(b) = values.get("vxcan.emulate-bitrate") {
+            let bitrate = b
+                .parse()
+                .map_err(|_| invalid("vxcan.emulate-bitrate", b, "integer"))?;
+            let mut bus = BusConfig::new(bitrate);
+            if let Some(d) = values.get("vxcan.emulate-data-bitrate") {
+                bus.data_bitrate = d
+                    .parse()
+                    .map_err(|_| invalid("vxcan.emulate-data-bitrate", d, "integer"))?;
+                if bus.data_bitrate < bus.bitrate {
+                    return Err(Error::InvalidOption(String::from(
+                        "vxcan.emulate-data-bitrate must not be below vxcan.emulate-bitrate",
+                    )));
+                }
+            }
+            opts.bus = Some(bus);
+        }
+        if let Some(listen) = values.get("vxcan.tunnel") {
+            // Addresses were checked against the schema already
+            let listen = listen
+                .parse()
+                .map_err(|_| invalid("vxcan.tunnel", listen, "IP address and port"))?;
+            // Without peers of its own, the tunnel goes to the nodes discovered
+            let mut tunnel = match values.get("vxcan.tunnel-peers") {
+                Some(p) => TunnelConfig::new(
+                    listen,
+                    addresses(p)
+                        .map_err(|p| invalid("vxcan.tunnel-peers", p, "IP address and port"))?,
+                ),
+                None => TunnelConfig::discovered(listen),
+            };
+            if let Some(b) = values.get("vxcan.tunnel-batch") {
+                tunnel.batch = b
+                    .parse()
+                    .map_err(|_| invalid("vxcan.tunnel-batch", b, "integer"))?;
+            }
+            opts.tunnel = Some(tunnel);
+        }
+
+        let ifc = opts.interface();
+        if ifc.len() > IFNAME_MAX {
+            return Err(Error::InvalidOption(format!(
+                "interface name '{ifc}' built from vxcan.dev and vxcan.id is longer than {IFNAME_MAX} characters"
+            )));
+        }
+        Ok(opts)
+    }
+
+    /// Parse the options Docker reports for an existing network.
+    pub fn from_map(options: &HashMap<String, String>) -> Result<Self, Error> {
+        let v = serde_json::to_value(options)
+            .map_err(|e| Error::InvalidOption(format!("unable to read options: {e}")))?;
+        Self::parse(&v)
+    }
+
+    /// Name of the host CAN interface backing the network.
+    pub fn interface(&self) -> String {
+        format!("{}{}", self.device, self.canid)
+    }
+}
+
+/// Validated options of an endpoint, given to `docker network connect` with
+/// `--driver-opt` or in a Compose file's `driver_opts`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EndpointOptions {
+    /// Prefix of the interface inside the container, overriding the
+    /// network's (`vxcan.peer`). Empty to use the network's.
+    pub peer: String,
+    /// Faults injected into the endpoint's frames (`vxcan.drop`,
+    /// `vxcan.delay`, `vxcan.jitter`, `vxcan.corrupt`, `vxcan.duplicate` and
+    /// `vxcan.reorder`).
+    pub faults: Faults,
+    /// Whether the endpoint only observes the network's traffic and never
+    /// transmits (`vxcan.tap`).
+    pub tap: bool,
+    /// Group of endpoints the endpoint exchanges frames with (`vxcan.group`).
+    /// Endpoints without a group form one of their own.
+    pub group: Option<String>,
+}
+
+impl EndpointOptions {
+    /// Parse the options Docker sends with `NetworkDriver.Join`. Docker mixes
+    /// its own options in, so only the `vxcan.*` keys are looked at.
+    pub fn parse(options: &serde_json::Value) -> Result<Self, Error> {
+        let own: serde_json::Map<String, serde_json::Value> = match options {
+            serde_json::Value::Object(m) => m
+                .iter()
+                .filter(|(k, _)| k.starts_with("vxcan."))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            serde_json::Value::Null => serde_json::Map::new(),
+            v => {
+                return Err(Error::InvalidOption(format!(
+                    "expected a set of key/value options, got '{v}'"
+                )))
+            }
+        };
+        let values = parse_with(ENDPOINT_OPTIONS, &[], &serde_json::Value::Object(own))?;
+
+        let mut opts = EndpointOptions::default();
+        if let Some(p) = values.get("vxcan.peer") {
+            opts.peer = p.clone();
+        }
+        if let Some(t) = values.get("vxcan.tap") {
+            opts.tap = t == "true";
+        }
+        opts.group = values.get("vxcan.group").cloned();
+        // Ranges were checked against the schema already
+        let number = |key: &str| -> Result<f64, Error> {
+            values.get(key).map_or(Ok(0.0), |v| {
+                v.parse().map_err(|_| invalid(key, v, "number"))
+            })
+        };
+        opts.faults = Faults {
+            drop: number("vxcan.drop")?,
+            delay: number("vxcan.delay")? as u64,
+            jitter: number("vxcan.jitter")? as u64,
+            corrupt: number("vxcan.corrupt")?,
+            duplicate: number("vxcan.duplicate")?,
+            reorder: number("vxcan.reorder")?,
+        };
+        Ok(opts)
+    }
+}
+
+fn parse_with(
+    schema: &[OptionSpec],
+    conflicts: &[Conflict],
+    options: &serde_json::Value,
+) -> Result<HashMap<&'static str, String>, Error> {
+    let map = match options {
+        serde_json::Value::Null => return Ok(HashMap::new()),
+        serde_json::Value::Object(m) => m,
+        v => {
+            return Err(Error::InvalidOption(format!(
+                "expected a set of key/value options, got '{v}'"
+            )))
+        }
+    };
+
+    let mut values: HashMap<&'static str, String> = HashMap::new();
+    for (key, value) in map.iter() {
+        let spec = match schema.iter().find(|s| s.key == key) {
+            Some(s) => s,
+            None => {
+                let known: Vec<&str> = schema
+                    .iter()
+                    .filter(|s| s.replaced_by.is_none())
+                    .map(|s| s.key)
+                    .collect();
+                return Err(Error::InvalidOption(format!(
+                    "unknown option '{key}' (known options: {})",
+                    known.join(", ")
+                )));
+            }
+        };
+
+        let value = scalar(key, value)?;
+        validate(spec, &value)?;
+
+        for other in spec.requires {
+            if !map.contains_key(*other) {
+                return Err(Error::InvalidOption(format!(
+                    "option '{key}' requires '{other}'"
+                )));
+            }
+        }
+
+        let canonical = match spec.replaced_by {
+            Some(new) => {
+                tracing::warn!("option '{key}' is deprecated, use '{new}' instead");
+                new
+            }
+            None => spec.key,
+        };
+        values.insert(canonical, value);
+    }
+
+    // Every given value is a valid scalar by now
+    let given = |option: &str| match option.split_once('=') {
+        Some((key, want)) => map
+            .get(key)
+            .is_some_and(|v| scalar(key, v).is_ok_and(|v| v == want)),
+        None => map.contains_key(option),
+    };
+    for conflict in conflicts {
+        let [first, second] = conflict.options;
+        if given(first) && given(second) {
+            return Err(Error::InvalidOption(format!(
+                "options '{first}' and '{second}' are mutually exclusive: {}",
+                conflict.reason
+            )));
+        }
+    }
+
+    Ok(values)
+}
+
+/// Turn a JSON option value into the string it is validated as.
+fn scalar(key: &str, value: &serde_json::Value) -> Result<String, Error> {
+    match value {
+        serde_json::Value::String(s) => Ok(s.trim().to_string()),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        serde_json::Value::Bool(b) => Ok(b.to_string()),
+        v => Err(invalid(key, &v.to_string(), "scalar value")),
+    }
+}
+
+fn validate(spec: &OptionSpec, value: &str) -> Result<(), Error> {
+    match spec.kind {
+        Kind::Name { max_len } => {
+            if value.is_empty() || value.len() > max_len {
+                return Err(invalid(
+                    spec.key,
+                    value,
+                    &format!("name of 1 to {max_len} characters"),
+                ));
+            }
+            if !value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(invalid(
+                    spec.key,
+                    value,
+                    "name made of letters, digits, '-' and '_'",
+                ));
+            }
+        }
+        Kind::Integer { min, max } => match value.parse::<u64>() {
+            Ok(i) if i >= min && i <= max => {}
+            _ => {
+                return Err(invalid(
+                    spec.key,
+                    value,
+                    &format!("integer between {min} and {max}"),
+                ))
+            }
+        },
+        Kind::Bool => {
+            if value != "true" && value != "false" {
+                return Err(invalid(spec.key, value, "boolean ('true' or 'false')"));
+            }
+        }
+        Kind::Path => {
+            if !value.starts_with('/') {
+                return Err(invalid(spec.key, value, "absolute path"));
+            }
+        }
+        Kind::Percent => match value.parse::<f64>() {
+            Ok(p) if (0.0..=100.0).contains(&p) => {}
+            _ => return Err(invalid(spec.key, value, "percentage between 0 and 100")),
+        },
+        Kind::Address => {
+            if value.parse::<SocketAddr>().is_err() {
+                return Err(invalid(
+                    spec.key,
+                    value,
+                    "IP address and port, like '192.0.2.1:20000'",
+                ));
+            }
+        }
+        Kind::Addresses => {
+            if let Err(bad) = addresses(value) {
+                return Err(invalid(
+                    spec.key,
+                    bad,
+                    "list of IP addresses and ports, like '192.0.2.1:20000,192.0.2.2:20000'",
+                ));
+            }
+        }
+        Kind::Choice(words) => {
+            if !words.contains(&value) {
+                return Err(invalid(
+                    spec.key,
+                    value,
+                    &format!("one of '{}'", words.join("', '")),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a comma-separated list of addresses and ports, or return the
+/// first entry that is not one.
+fn addresses(value: &str) -> Result<Vec<SocketAddr>, &str> {
+    value
+        .split(',')
+        .map(|a| a.trim().parse().map_err(|_| a))
+        .collect()
+}
+
+fn invalid(key: &str, value: &str, expected: &str) -> Error {
+    Error::InvalidOption(format!(
+        "'{value}' is not valid for '{key}', expected a {expected}"
+    ))
+}
@@ -0,0 +1,508 @@
+/*
+ * Filename: recorder.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Capture of a network's traffic to rotating log files.
+
+use crate::backend::FrameSocket;
+use crate::error::Error;
+use crate::frame::{self, Frame};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{error, info, Instrument};
+
+/// How often buffered lines are written out, so that files can be followed
+/// while recording.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest line written for a frame, with room for the ASC footer.
+const LINE_MAX: u64 = 320;
+
+/// Default size in bytes after which a new file is started.
+const DEFAULT_MAX_SIZE: u64 = 64 << 20;
+
+/// Default number of files kept per recording.
+const DEFAULT_MAX_FILES: usize = 10;
+
+/// Format of the log files written by a [`Recorder`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    /// `candump -l` lines, readable by `canplayer` and `log2asc`.
+    #[default]
+    Candump,
+    /// Vector ASC, as read by CANalyzer and python-can.
+    Asc,
+}
+
+impl RecordFormat {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        match s {
+            "candump" => Ok(RecordFormat::Candump),
+            "asc" => Ok(RecordFormat::Asc),
+            _ => Err(Error::InvalidOption(format!(
+                "unknown record format '{s}', expected 'candump' or 'asc'"
+            ))),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Candump => "log",
+            RecordFormat::Asc => "asc",
+        }
+    }
+}
+
+/// Where and how to record a network's traffic.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordConfig {
+    /// Directory the log files are written to; created if missing.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: RecordFormat,
+    /// Size in bytes a file may reach before the next one is started.
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+    /// Number of files of the recording to keep; the oldest are removed.
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_max_size() -> u64 {
+    DEFAULT_MAX_SIZE
+}
+
+fn default_max_files() -> usize {
+    DEFAULT_MAX_FILES
+}
+
+impl RecordConfig {
+    /// Record to `path` in candump format with the default rotation.
+    pub fn new(path: PathBuf) -> Self {
+        RecordConfig {
+            path,
+            format: RecordFormat::default(),
+            max_size: DEFAULT_MAX_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.path.is_absolute() {
+            return Err(Error::InvalidOption(format!(
+                "record path '{}' is not absolute",
+                self.path.display()
+            )));
+        }
+        if self.max_size == 0 || self.max_files == 0 {
+            return Err(Error::InvalidOption(String::from(
+                "record max_size and max_files must be at least 1",
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Progress of a recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecordStatus {
+    #[serde(flatten)]
+    pub config: RecordConfig,
+    /// File currently written to.
+    pub file: PathBuf,
+    /// Frames recorded so far.
+    pub frames: u64,
+    /// Why the recording stopped on its own, if it did.
+    pub error: Option<String>,
+}
+
+/// Records every frame on an interface until stopped.
+pub struct Recorder {
+    status: Arc<Mutex<RecordStatus>>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Recorder {
+    /// Create the first log file and record the frames `socket` receives on
+    /// `ifc` into it in a task of its own.
+    pub async fn start(
+        ifc: &str,
+        socket: Arc<dyn FrameSocket>,
+        config: RecordConfig,
+    ) -> Result<Self, Error> {
+        config.validate()?;
+        let log = LogWriter::create(ifc, &config).await?;
+        info!(interface = %ifc, file = %log.path.display(), "recording traffic");
+
+        let status = Arc::new(Mutex::new(RecordStatus {
+            config,
+            file: log.path.clone(),
+            frames: 0,
+            error: None,
+        }));
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(
+            record(socket, log, status.clone(), stopped)
+                .instrument(tracing::info_span!("recorder", interface = %ifc)),
+        );
+        Ok(Recorder { status, stop, task })
+    }
+
+    /// Whether the recording is still going on.
+    pub fn running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn status(&self) -> RecordStatus {
+        self.status.lock().clone()
+    }
+
+    /// Stop recording and close the current file.
+    pub async fn stop(self) -> RecordStatus {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+        let status = self.status.lock().clone();
+        info!(frames = status.frames, "stopped recording");
+        status
+    }
+}
+
+async fn record(
+    socket: Arc<dyn FrameSocket>,
+    mut log: LogWriter,
+    status: Arc<Mutex<RecordStatus>>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    let res = loop {
+        tokio::select! {
+            // Also ends the recording if the recorder was dropped
+            _ = &mut stopped => break Ok(()),
+            _ = flush.tick() => {
+                if let Err(e) = log.flush().await {
+                    break Err(e);
+                }
+            }
+            received = socket.recv() => {
+                let res = match received {
+                    Ok((frame, time)) => log.write(time, &frame).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    break Err(e);
+                }
+                let mut status = status.lock();
+                status.frames += 1;
+                status.file.clone_from(&log.path);
+            }
+        }
+    };
+
+    if let Err(e) = res.and(log.close().await) {
+        error!(error = %e, "recording failed");
+        status.lock().error = Some(e.to_string());
+    }
+}
+
+/// Writes frames to a series of files, starting the next one before a
+/// file would outgrow the configured size.
+struct LogWriter {
+    ifc: String,
+    config: RecordConfig,
+    file: BufWriter<fs::File>,
+    path: PathBuf,
+    /// Bytes written to the current file.
+    size: u64,
+    /// Frames written to the current file.
+    frames: u64,
+    /// When the current file was started.
+    start: SystemTime,
+    /// Files of this recording, oldest first.
+    files: VecDeque<PathBuf>,
+    /// Number the next file is tried under.
+    count: u32,
+}
+
+impl LogWriter {
+    async fn create(ifc: &str, config: &RecordConfig) -> Result<Self, Error> {
+        fs::create_dir_all(&config.path)
+            .await
+            .map_err(|e| io_error("create", &config.path, e))?;
+        let start = SystemTime::now();
+        let mut count = 0;
+        let (path, file) = Self::open(ifc, config, start, &mut count).await?;
+        let mut log = LogWriter {
+            ifc: ifc.to_string(),
+            config: config.clone(),
+            file: BufWriter::new(file),
+            path,
+            size: 0,
+            frames: 0,
+            start,
+            files: VecDeque::new(),
+            count,
+        };
+        log.files.push_back(log.path.clone());
+        log.header().await?;
+        Ok(log)
+    }
+
+    /// `<dir>/<ifc>-<UTC date>-<time>-<count>.<ext>`, so that names sort by
+    /// age.
+    fn file_name(ifc: &str, config: &RecordConfig, time: SystemTime, count: u32) -> PathBuf {
+        let t = Civil::new(time);
+        config.path.join(format!(
+            "{ifc}-{:04}{:02}{:02}-{:02}{:02}{:02}-{count:03}.{}",
+            t.year,
+            t.month,
+            t.day,
+            t.hour,
+            t.minute,
+            t.second,
+            config.format.extension()
+        ))
+    }
+
+    /// Create the file numbered `count` or, if a previous recording left it
+    /// there, the next free number, and count it.
+    async fn open(
+        ifc: &str,
+        config: &RecordConfig,
+        time: SystemTime,
+        count: &mut u32,
+    ) -> Result<(PathBuf, fs::File), Error> {
+        loop {
+            let path = Self::file_name(ifc, config, time, *count);
+            *count += 1;
+            let created = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await;
+            match created {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(io_error("create", &path, e)),
+            }
+        }
+    }
+
+    async fn write(&mut self, time: SystemTime, frame: &Frame) -> Result<(), Error> {
+        // Every file holds at least one frame, however small the limit
+        if self.frames > 0 && self.size + LINE_MAX > self.config.max_size {
+            self.rotate().await?;
+        }
+        let line = match self.config.format {
+            RecordFormat::Candump => frame::candump_line(time, &self.ifc, frame),
+            RecordFormat::Asc => {
+                let offset = time.duration_since(self.start).unwrap_or_default();
+                asc_line(offset, frame)
+            }
+        };
+        self.append(&line).await?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    async fn append(&mut self, line: &str) -> Result<(), Error> {
+        let line = format!("{line}\n");
+        self.file
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|e| io_error("write", &self.path, e))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    async fn header(&mut self) -> Result<(), Error> {
+        if self.config.format == RecordFormat::Asc {
+            let date = Civil::new(self.start).asc();
+            for line in [
+                format!("date {date}"),
+                String::from("base hex  timestamps absolute"),
+                String::from("internal events logged"),
+                format!("Begin Triggerblock {date}"),
+                String::from("   0.000000 Start of measurement"),
+            ] {
+                self.append(&line).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.file
+            .flush()
+            .await
+            .map_err(|e| io_error("write", &self.path, e))
+    }
+
+    async fn close(&mut self) -> Result<(), Error> {
+        if self.config.format == RecordFormat::Asc {
+            self.append("End TriggerBlock").await?;
+        }
+        self.flush().await
+    }
+
+    /// Close the current file, start the next one and remove the oldest
+    /// files beyond the configured number.
+    async fn rotate(&mut self) -> Result<(), Error> {
+        self.close().await?;
+
+        self.start = SystemTime::now();
+        let (path, file) = Self::open(&self.ifc, &self.config, self.start, &mut self.count).await?;
+        self.path = path;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.frames = 0;
+        self.files.push_back(self.path.clone());
+        self.header().await?;
+
+        while self.files.len() > self.config.max_files {
+            if let Some(old) = self.files.pop_front() {
+                fs::remove_file(&old)
+                    .await
+                    .map_err(|e| io_error("remove", &old, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One message line of a Vector ASC file, on channel 1 and timed relative
+/// to the start of the file.
+fn asc_line(offset: Duration, frame: &Frame) -> String {
+    let time = format!("{:>11.6}", offset.as_secs_f64());
+    let id = match frame.is_extended() {
+        true => format!("{:X}x", frame.raw_id()),
+        false => format!("{:X}", frame.raw_id()),
+    };
+    let data: Vec<String> = frame.data.iter().map(|b| format!("{b:02X}")).collect();
+    if frame.is_error() {
+        format!("{time} 1  ErrorFrame")
+    } else if frame.fd {
+        format!(
+            "{time} CANFD   1 Rx {id:>8} {:>32} {} {} {:x} {:>2} {}",
+            "",
+            frame.flags & frame::FD_BRS,
+            (frame.flags & frame::FD_ESI) >> 1,
+            frame::dlc(frame.data.len()),
+            frame.data.len(),
+            data.join(" ")
+        )
+    } else if frame.is_remote() {
+        format!("{time} 1  {id:<15} Rx   r {:x}", frame.data.len())
+    } else {
+        format!(
+            "{time} 1  {id:<15} Rx   d {:x} {}",
+            frame.data.len(),
+            data.join(" ")
+        )
+        .trim_end()
+        .to_string()
+    }
+}
+
+/// A point in time as a UTC calendar date and time of day.
+struct Civil {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    millis: u32,
+    /// Day of the week, 0 being Sunday.
+    weekday: usize,
+}
+
+impl Civil {
+    fn new(time: SystemTime) -> Self {
+        let t = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let days = (t.as_secs() / 86400) as i64;
+        let secs = (t.as_secs() % 86400) as u32;
+
+        // Days to civil date, after Howard Hinnant's algorithm
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Civil {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            minute: secs / 60 % 60,
+            second: secs % 60,
+            millis: t.subsec_millis(),
+            // 1970-01-01 was a Thursday
+            weekday: ((days + 4) % 7) as usize,
+        }
+    }
+
+    /// The date as ASC headers write it, e.g. `Tue Oct 18 05:15:15.000 pm 2022`.
+    fn asc(&self) -> String {
+        const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let hour12 = match self.hour % 12 {
+            0 => 12,
+            h => h,
+        };
+        format!(
+            "{} {} {:02} {:02}:{:02}:{:02}.{:03} {} {}",
+            DAYS[self.weekday],
+            MONTHS[self.month as usize - 1],
+            self.day,
+            hour12,
+            self.minute,
+            self.second,
+            self.millis,
+            if self.hour < 12 { "am" } else { "pm" },
+            self.year
+        )
+    }
+}
+
+fn io_error(op: &str, path: &Path, e: std::io::Error) -> Error {
+    Error::Io(format!("unable to {op} {}: {e}", path.display()))
+}
@@ -0,0 +1,402 @@
+/*
+ * Filename: replay.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Replay of recorded traffic onto a network.
+
+use crate::backend::FrameSocket;
+use crate::error::Error;
+use crate::frame::{self, Frame, EFF_MASK, SFF_MASK};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, Instrument};
+
+/// Frames sent at full speed before other tasks get a turn.
+const BURST: u64 = 64;
+
+/// Slowest replay speed accepted, other than 0 for as fast as possible.
+const SPEED_MIN: f64 = 0.001;
+
+/// Longest a replay waits for its next frame, about a year.
+const WAIT_MAX: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Wait before sending again when the device's queue is full.
+const BACKOFF: Duration = Duration::from_millis(1);
+
+/// Identifier filter in candump notation: `<id>:<mask>` passes frames whose
+/// identifier matches `id` in the bits set in `mask`, `<id>~<mask>` passes
+/// all others. Three identifier digits select 11-bit frames, eight 29-bit
+/// frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IdFilter {
+    pub id: u32,
+    pub mask: u32,
+    pub extended: bool,
+    pub invert: bool,
+}
+
+impl IdFilter {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let bad = || {
+            Error::InvalidOption(format!(
+                "invalid filter '{s}', expected <id>:<mask> or <id>~<mask> in hex"
+            ))
+        };
+        let (invert, (id, mask)) = match (s.split_once(':'), s.split_once('~')) {
+            (Some(parts), None) => (false, parts),
+            (None, Some(parts)) => (true, parts),
+            _ => return Err(bad()),
+        };
+        let extended = match id.len() {
+            3 => false,
+            8 => true,
+            _ => return Err(bad()),
+        };
+        let limit = if extended { EFF_MASK } else { SFF_MASK };
+        let id = u32::from_str_radix(id, 16).map_err(|_| bad())?;
+        let mask = u32::from_str_radix(mask, 16).map_err(|_| bad())?;
+        if id > limit || mask > limit {
+            return Err(bad());
+        }
+        Ok(IdFilter {
+            id,
+            mask,
+            extended,
+            invert,
+        })
+    }
+
+    /// Whether the filter passes `frame`. Error frames never pass.
+    pub fn matches(&self, frame: &Frame) -> bool {
+        if frame.is_error() {
+            return false;
+        }
+        let hit = frame.is_extended() == self.extended
+            && frame.raw_id() & self.mask == self.id & self.mask;
+        hit != self.invert
+    }
+}
+
+impl fmt::Display for IdFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sep = if self.invert { '~' } else { ':' };
+        match self.extended {
+            true => write!(f, "{:08X}{sep}{:08X}", self.id, self.mask),
+            false => write!(f, "{:03X}{sep}{:03X}", self.id, self.mask),
+        }
+    }
+}
+
+impl TryFrom<String> for IdFilter {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Error> {
+        IdFilter::parse(&s)
+    }
+}
+
+impl From<IdFilter> for String {
+    fn from(f: IdFilter) -> String {
+        f.to_string()
+    }
+}
+
+/// What to replay onto a network, and how.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayConfig {
+    /// Log file in the `candump -l` format.
+    pub file: PathBuf,
+    /// Factor applied to the original pace of the frames, e.g. 2 for twice
+    /// as fast, and at least 0.001. 0 sends the frames as fast as possible.
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    /// Times to replay the file; 0 replays it until stopped.
+    #[serde(default = "default_loops")]
+    pub loops: u32,
+    /// Only replay frames passing one of these filters; all frames if
+    /// empty.
+    #[serde(default)]
+    pub filters: Vec<IdFilter>,
+    /// Only replay frames logged on this interface; frames of all
+    /// interfaces if unset.
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+fn default_loops() -> u32 {
+    1
+}
+
+impl ReplayConfig {
+    /// Replay `file` once at its original pace.
+    pub fn new(file: PathBuf) -> Self {
+        ReplayConfig {
+            file,
+            speed: default_speed(),
+            loops: default_loops(),
+            filters: Vec::new(),
+            interface: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.file.is_absolute() {
+            return Err(Error::InvalidOption(format!(
+                "replay file '{}' is not absolute",
+                self.file.display()
+            )));
+        }
+        if !self.speed.is_finite() || (self.speed != 0.0 && self.speed < SPEED_MIN) {
+            return Err(Error::InvalidOption(format!(
+                "replay speed {} is not 0 or a number of at least {SPEED_MIN}",
+                self.speed
+            )));
+        }
+        Ok(())
+    }
+
+    fn passes(&self, ifc: &str, frame: &Frame) -> bool {
+        self.interface.as_deref().is_none_or(|i| i == ifc)
+            && (self.filters.is_empty() || self.filters.iter().any(|f| f.matches(frame)))
+    }
+}
+
+/// Progress of a replay.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplayStatus {
+    #[serde(flatten)]
+    pub config: ReplayConfig,
+    /// Whether frames are still being sent.
+    pub running: bool,
+    /// Pass through the file currently replayed, starting at 1.
+    #[serde(rename = "loop")]
+    pub current_loop: u32,
+    /// Frames sent so far.
+    pub frames: u64,
+    /// Frames left out by the filters so far.
+    pub skipped: u64,
+    /// Why the replay stopped early, if it did.
+    pub error: Option<String>,
+}
+
+/// Sends the frames of a log file onto an interface until done or stopped.
+pub struct Replay {
+    status: Arc<Mutex<ReplayStatus>>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Replay {
+    /// Check that the log file can be read and replay it through `socket`
+    /// in a task of its own.
+    pub async fn start(
+        ifc: &str,
+        socket: Arc<dyn FrameSocket>,
+        config: ReplayConfig,
+    ) -> Result<Self, Error> {
+        config.validate()?;
+        let file = open(&config).await?;
+        info!(interface = %ifc, file = %config.file.display(), "replaying traffic");
+
+        let status = Arc::new(Mutex::new(ReplayStatus {
+            config,
+            running: true,
+            current_loop: 1,
+            frames: 0,
+            skipped: 0,
+            error: None,
+        }));
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(
+            run(socket, file, status.clone(), stopped)
+                .instrument(tracing::info_span!("replay", interface = %ifc)),
+        );
+        Ok(Replay { status, stop, task })
+    }
+
+    /// Whether frames are still being sent.
+    pub fn running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn status(&self) -> ReplayStatus {
+        self.status.lock().clone()
+    }
+
+    /// Stop sending frames.
+    pub async fn stop(self) -> ReplayStatus {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+        let status = self.status.lock().clone();
+        info!(frames = status.frames, "stopped replay");
+        status
+    }
+}
+
+type Lines = tokio::io::Lines<BufReader<fs::File>>;
+
+async fn open(config: &ReplayConfig) -> Result<Lines, Error> {
+    let file = fs::File::open(&config.file)
+        .await
+        .map_err(|e| Error::Io(format!("unable to open {}: {e}", config.file.display())))?;
+    Ok(BufReader::new(file).lines())
+}
+
+async fn run(
+    socket: Arc<dyn FrameSocket>,
+    file: Lines,
+    status: Arc<Mutex<ReplayStatus>>,
+    stopped: oneshot::Receiver<()>,
+) {
+    // The replay is over however the task ends, even by panicking
+    let _finished = Finished(&status);
+    let res = tokio::select! {
+        // Also ends the replay if it was dropped
+        _ = stopped => Ok(()),
+        res = replay(socket.as_ref(), file, &status) => res,
+    };
+    if let Err(e) = res {
+        let mut status = status.lock();
+        error!(error = %e, "replay failed");
+        status.error = Some(e.to_string());
+    }
+}
+
+/// Marks a replay as no longer running when dropped.
+struct Finished<'a>(&'a Mutex<ReplayStatus>);
+
+impl Drop for Finished<'_> {
+    fn drop(&mut self) {
+        let mut status = self.0.lock();
+        status.running = false;
+        if std::thread::panicking() {
+            status.error = Some(String::from("replay stopped unexpectedly"));
+        }
+    }
+}
+
+/// Replay the file as many times as configured.
+async fn replay(
+    socket: &dyn FrameSocket,
+    mut file: Lines,
+    status: &Mutex<ReplayStatus>,
+) -> Result<(), Error> {
+    let config = status.lock().config.clone();
+    let mut pass = 1;
+    loop {
+        // Passes without frames would loop forever without waiting
+        if replay_once(socket, file, &config, status).await? == 0 {
+            return Err(Error::InvalidOption(format!(
+                "{}: no frames to replay",
+                config.file.display()
+            )));
+        }
+        if config.loops != 0 && pass >= config.loops {
+            return Ok(());
+        }
+        pass += 1;
+        status.lock().current_loop = pass;
+        file = open(&config).await?;
+    }
+}
+
+/// Send the frames of one pass through the file, paced like the original
+/// from the first frame replayed on, and return how many were sent.
+async fn replay_once(
+    socket: &dyn FrameSocket,
+    mut file: Lines,
+    config: &ReplayConfig,
+    status: &Mutex<ReplayStatus>,
+) -> Result<u64, Error> {
+    let start = Instant::now();
+    let mut first = None;
+    let mut number = 0;
+    let mut sent = 0u64;
+    while let Some(line) = file
+        .next_line()
+        .await
+        .map_err(|e| Error::Io(format!("unable to read {}: {e}", config.file.display())))?
+    {
+        number += 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (time, ifc, frame) = frame::parse_candump_line(line).map_err(|e| {
+            Error::InvalidOption(format!("{}:{number}: {e}", config.file.display()))
+        })?;
+        if !config.passes(&ifc, &frame) {
+            status.lock().skipped += 1;
+            continue;
+        }
+
+        let first = *first.get_or_insert(time);
+        if config.speed > 0.0 {
+            let offset = time.saturating_sub(first).as_secs_f64() / config.speed;
+            let offset = Duration::try_from_secs_f64(offset)
+                .ok()
+                .filter(|offset| *offset <= WAIT_MAX)
+                .ok_or_else(|| {
+                    Error::InvalidOption(format!(
+                        "{}:{number}: frame is more than a year after the first at speed {}",
+                        config.file.display(),
+                        config.speed
+                    ))
+                })?;
+            tokio::time::sleep_until(start + offset).await;
+        } else if sent % BURST == BURST - 1 {
+            tokio::task::yield_now().await;
+        }
+        send(socket, &frame).await?;
+        sent += 1;
+        status.lock().frames += 1;
+    }
+    Ok(sent)
+}
+
+async fn send(socket: &dyn FrameSocket, frame: &Frame) -> Result<(), Error> {
+    loop {
+        match socket.send(frame).await {
+            Err(Error::Kernel {
+                transient: true, ..
+            }) => tokio::time::sleep(BACKOFF).await,
+            res => return res,
+        }
+    }
+}
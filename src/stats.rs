@@ -0,0 +1,166 @@
+/*
+ * Filename: stats.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use crate::backend::RuleStats;
+use crate::bus::BusStatus;
+use crate::network::NetworkInfo;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ops::AddAssign;
+
+/// Frame counters kept by can-gw for a route, or summed over several.
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+pub struct FrameCounters {
+    /// Frames forwarded.
+    pub handled: u64,
+    /// Frames that could not be forwarded, e.g. because the destination
+    /// queue was full.
+    pub dropped: u64,
+    /// Frames discarded by a frame modification check.
+    pub deleted: u64,
+}
+
+impl AddAssign for FrameCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.handled += other.handled;
+        self.dropped += other.dropped;
+        self.deleted += other.deleted;
+    }
+}
+
+/// Counters of the route from `src` to `dst`, classic and FD frames
+/// together.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RouteStats {
+    pub src: String,
+    pub dst: String,
+    #[serde(flatten)]
+    pub frames: FrameCounters,
+}
+
+/// Traffic of one endpoint.
+///
+/// A frame is counted once for every route it takes, so a frame sent to a
+/// network with three other endpoints counts four times in `sent`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct EndpointStats {
+    pub id: String,
+    pub device: String,
+    /// Frames from the endpoint, over all of its outgoing routes.
+    pub sent: FrameCounters,
+    /// Frames to the endpoint, over all of its incoming routes.
+    pub received: FrameCounters,
+    /// Routes from and to the endpoint.
+    pub routes: Vec<RouteStats>,
+}
+
+/// Traffic of one network.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct NetworkStats {
+    pub id: String,
+    pub interface: String,
+    /// Sum over all routes of the network.
+    pub frames: FrameCounters,
+    /// Load of the emulated bus, if the network emulates one.
+    pub bus: Option<BusStatus>,
+    pub endpoints: Vec<EndpointStats>,
+    pub routes: Vec<RouteStats>,
+}
+
+/// Sum the counters of the kernel rules by route.
+pub fn routes(rules: &[RuleStats]) -> HashMap<(String, String), FrameCounters> {
+    let mut routes: HashMap<(String, String), FrameCounters> = HashMap::new();
+    for r in rules {
+        *routes.entry((r.src.clone(), r.dst.clone())).or_default() += FrameCounters {
+            handled: r.handled,
+            dropped: r.dropped,
+            deleted: r.deleted,
+        };
+    }
+    routes
+}
+
+impl NetworkStats {
+    /// Statistics of the network described by `info`, from the counters of
+    /// the rules installed in the kernel. Routes of the network missing from
+    /// `rules` count as zero.
+    pub fn new(info: &NetworkInfo, rules: &[RuleStats]) -> Self {
+        let counters = routes(rules);
+        let routes: Vec<RouteStats> = info
+            .rules
+            .iter()
+            .map(|(src, dst)| RouteStats {
+                src: src.clone(),
+                dst: dst.clone(),
+                frames: counters
+                    .get(&(src.clone(), dst.clone()))
+                    .copied()
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        let mut frames = FrameCounters::default();
+        for r in &routes {
+            frames += r.frames;
+        }
+
+        let endpoints = info
+            .endpoints
+            .iter()
+            .map(|ep| {
+                let mut sent = FrameCounters::default();
+                let mut received = FrameCounters::default();
+                let mut own = Vec::new();
+                for r in &routes {
+                    if r.src.eq(&ep.device) {
+                        sent += r.frames;
+                    } else if r.dst.eq(&ep.device) {
+                        received += r.frames;
+                    } else {
+                        continue;
+                    }
+                    own.push(r.clone());
+                }
+                EndpointStats {
+                    id: ep.id.clone(),
+                    device: ep.device.clone(),
+                    sent,
+                    received,
+                    routes: own,
+                }
+            })
+            .collect();
+
+        NetworkStats {
+            id: info.id.clone(),
+            interface: info.interface.clone(),
+            frames,
+            bus: info.bus,
+            endpoints,
+            routes,
+        }
+    }
+}
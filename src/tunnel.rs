@@ -0,0 +1,441 @@
+/*
+ * Filename: tunnel.rs
+ * Created Date: Tuesday, October 18th 2022, 5:15:15 pm
+ * Author: Jonathan Haws
+ *
+ * Copyright (c) 2022 WiTricity
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+//! Tunnel carrying a network's frames to peer hosts over UDP, in the wire
+//! format of [cannelloni](https://github.com/mguentner/cannelloni).
+//!
+//! A packet starts with a 5-byte header: the format version (2), an op code
+//! (0 for data), a sequence number incremented with every packet and the
+//! big-endian number of frames that follow. Each frame is its big-endian
+//! identifier with flags, its length (with bit 7 set for CAN FD), the flags
+//! of a CAN FD frame and, unless it is a remote request, its data.
+
+use crate::backend::FrameSocket;
+use crate::error::Error;
+use crate::frame::{Frame, FrameError, CAN_MAX_LEN, RTR_FLAG};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, error, info, Instrument};
+
+/// Version of the cannelloni format spoken.
+const VERSION: u8 = 2;
+
+/// Op code of a packet carrying frames.
+const OP_DATA: u8 = 0;
+
+const HEADER_LEN: usize = 5;
+
+/// Set in the length byte of a CAN FD frame.
+const FD_FRAME: u8 = 0x80;
+
+/// Largest packet sent, so that it fits an Ethernet frame unfragmented.
+pub const PACKET_MAX: usize = 1472;
+
+/// Longest time in milliseconds frames may be held back to be batched.
+pub const BATCH_MAX: u64 = 1000;
+
+/// Encode `frames` into one packet with sequence number `seq`.
+pub fn encode(seq: u8, frames: &[Frame]) -> Vec<u8> {
+    let mut packet = vec![VERSION, OP_DATA, seq];
+    packet.extend((frames.len() as u16).to_be_bytes());
+    for frame in frames {
+        packet.extend(frame.id.to_be_bytes());
+        let len = frame.data.len() as u8;
+        if frame.fd {
+            packet.extend([len | FD_FRAME, frame.flags]);
+        } else {
+            packet.push(len);
+        }
+        if !frame.is_remote() {
+            packet.extend(&frame.data);
+        }
+    }
+    packet
+}
+
+/// Size `frame` takes up in a packet.
+fn encoded_len(frame: &Frame) -> usize {
+    let data = if frame.is_remote() {
+        0
+    } else {
+        frame.data.len()
+    };
+    4 + 1 + usize::from(frame.fd) + data
+}
+
+/// Decode a packet into its sequence number and frames.
+pub fn decode(packet: &[u8]) -> Result<(u8, Vec<Frame>), FrameError> {
+    let bad = |why: &str| FrameError(format!("invalid tunnel packet: {why}"));
+
+    if packet.len() < HEADER_LEN {
+        return Err(bad("too short"));
+    }
+    if packet[0] != VERSION {
+        return Err(bad(&format!("unsupported version {}", packet[0])));
+    }
+    if packet[1] != OP_DATA {
+        return Err(bad(&format!("unsupported op code {}", packet[1])));
+    }
+    let seq = packet[2];
+    let count = u16::from_be_bytes([packet[3], packet[4]]);
+
+    let mut rest = &packet[HEADER_LEN..];
+    let mut take = |n: usize| -> Result<&[u8], FrameError> {
+        if rest.len() < n {
+            return Err(bad("truncated frame"));
+        }
+        let (head, tail) = rest.split_at(n);
+        rest = tail;
+        Ok(head)
+    };
+    let mut frames = Vec::with_capacity(count.into());
+    for _ in 0..count {
+        let id = u32::from_be_bytes(take(4)?.try_into().unwrap_or_default());
+        let len = take(1)?[0];
+        let fd = len & FD_FRAME != 0;
+        let flags = if fd { take(1)?[0] } else { 0 };
+        let len = usize::from(len & !FD_FRAME);
+        let data = if id & RTR_FLAG != 0 {
+            vec![0; len.min(CAN_MAX_LEN)]
+        } else {
+            take(len)?.to_vec()
+        };
+        let frame = Frame {
+            id,
+            data,
+            fd,
+            flags,
+        };
+        frame.validate().map_err(|e| bad(&e.0))?;
+        frames.push(frame);
+    }
+    if !rest.is_empty() {
+        return Err(bad("data after the last frame"));
+    }
+    Ok((seq, frames))
+}
+
+/// Where a network's frames are tunneled to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// Address and UDP port receiving the frames of the peers.
+    pub listen: SocketAddr,
+    /// Addresses and UDP ports of the peers every frame is sent to.
+    pub peers: Vec<SocketAddr>,
+    /// Whether the peers are the other Docker nodes discovered, each at
+    /// the port of `listen`, rather than fixed.
+    #[serde(default)]
+    pub discover: bool,
+    /// Milliseconds to collect frames for before sending them in one
+    /// packet; 0 sends each frame right away.
+    #[serde(default)]
+    pub batch: u64,
+}
+
+impl TunnelConfig {
+    /// Send each frame right away from `listen` to `peers`.
+    pub fn new(listen: SocketAddr, peers: Vec<SocketAddr>) -> Self {
+        TunnelConfig {
+            listen,
+            peers,
+            discover: false,
+            batch: 0,
+        }
+    }
+
+    /// Send each frame right away from `listen` to the nodes discovered.
+    pub fn discovered(listen: SocketAddr) -> Self {
+        TunnelConfig {
+            discover: true,
+            ..Self::new(listen, Vec::new())
+        }
+    }
+
+    /// The peers of a tunnel to `nodes`, each at the port of `listen`.
+    pub fn node_peers(&self, nodes: &[IpAddr]) -> Vec<SocketAddr> {
+        nodes
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.listen.port()))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.peers.is_empty() && !self.discover {
+            return Err(Error::InvalidOption(String::from(
+                "a tunnel needs at least one peer",
+            )));
+        }
+        if self.batch > BATCH_MAX {
+            return Err(Error::InvalidOption(format!(
+                "frames may be batched for at most {BATCH_MAX} ms"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Traffic through a tunnel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TunnelStatus {
+    #[serde(flatten)]
+    pub config: TunnelConfig,
+    /// Address the tunnel receives on, with the port chosen by the system
+    /// if `listen` had port 0.
+    pub local: SocketAddr,
+    /// Whether frames are still being tunneled.
+    pub running: bool,
+    /// Packets sent, counted once however many peers they went to.
+    pub packets_sent: u64,
+    pub frames_sent: u64,
+    pub packets_received: u64,
+    pub frames_received: u64,
+    /// Packets the peers sent that never arrived, going by the gaps in
+    /// their sequence numbers.
+    pub packets_lost: u64,
+    /// Packets that arrived after a later one.
+    pub packets_reordered: u64,
+    /// Packets that could not be decoded or came from unknown senders.
+    pub packets_invalid: u64,
+    /// Why the tunnel stopped, if it did.
+    pub error: Option<String>,
+}
+
+/// Exchanges the frames on an interface with peer hosts until stopped.
+pub struct Tunnel {
+    status: Arc<Mutex<TunnelStatus>>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Tunnel {
+    /// Bind the tunnel's UDP socket and start exchanging the frames of
+    /// `socket`, opened on `ifc`, with the peers of `config`.
+    pub async fn start(
+        ifc: &str,
+        socket: Arc<dyn FrameSocket>,
+        config: TunnelConfig,
+    ) -> Result<Self, Error> {
+        config.validate()?;
+        let udp = UdpSocket::bind(config.listen)
+            .await
+            .map_err(|e| Error::Io(format!("unable to listen on {}: {e}", config.listen)))?;
+        let local = udp
+            .local_addr()
+            .map_err(|e| Error::Io(format!("unable to listen on {}: {e}", config.listen)))?;
+        info!(interface = %ifc, %local, peers = ?config.peers, "tunneling frames");
+
+        let status = Arc::new(Mutex::new(TunnelStatus {
+            config,
+            local,
+            running: true,
+            packets_sent: 0,
+            frames_sent: 0,
+            packets_received: 0,
+            frames_received: 0,
+            packets_lost: 0,
+            packets_reordered: 0,
+            packets_invalid: 0,
+            error: None,
+        }));
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(
+            run(socket, udp, status.clone(), stopped)
+                .instrument(tracing::info_span!("tunnel", interface = %ifc)),
+        );
+        Ok(Tunnel { status, stop, task })
+    }
+
+    /// Whether frames are still being tunneled.
+    pub fn running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    pub fn status(&self) -> TunnelStatus {
+        self.status.lock().clone()
+    }
+
+    /// Replace the peers frames are exchanged with.
+    pub fn peers_set(&self, peers: Vec<SocketAddr>) {
+        info!(?peers, "tunnel peers changed");
+        self.status.lock().config.peers = peers;
+    }
+
+    /// Stop tunneling frames.
+    pub async fn stop(self) -> TunnelStatus {
+        let _ = self.stop.send(());
+        let _ = self.task.await;
+        let status = self.status.lock().clone();
+        info!(
+            sent = status.frames_sent,
+            received = status.frames_received,
+            "stopped tunneling frames"
+        );
+        status
+    }
+}
+
+async fn run(
+    socket: Arc<dyn FrameSocket>,
+    udp: UdpSocket,
+    status: Arc<Mutex<TunnelStatus>>,
+    stopped: oneshot::Receiver<()>,
+) {
+    let res = tokio::select! {
+        // Also ends the tunnel if it was dropped
+        _ = stopped => Ok(()),
+        res = tunnel(socket, udp, &status) => res,
+    };
+    let mut status = status.lock();
+    status.running = false;
+    if let Err(e) = res {
+        error!(error = %e, "tunnel failed");
+        status.error = Some(e.to_string());
+    }
+}
+
+/// Frames waiting to be sent to the peers.
+struct Batch {
+    frames: Vec<Frame>,
+    len: usize,
+    /// When the frames must be sent at the latest.
+    deadline: Option<Instant>,
+    seq: u8,
+}
+
+impl Batch {
+    async fn flush(&mut self, udp: &UdpSocket, status: &Mutex<TunnelStatus>) {
+        self.deadline = None;
+        if self.frames.is_empty() {
+            return;
+        }
+        let packet = encode(self.seq, &self.frames);
+        self.seq = self.seq.wrapping_add(1);
+        // Peers may change while the tunnel runs
+        let peers = status.lock().config.peers.clone();
+        for peer in &peers {
+            // A peer that is down must not hold up the others
+            if let Err(e) = udp.send_to(&packet, peer).await {
+                debug!(%peer, error = %e, "unable to send packet");
+            }
+        }
+        let mut status = status.lock();
+        status.packets_sent += 1;
+        status.frames_sent += self.frames.len() as u64;
+        self.frames.clear();
+        self.len = HEADER_LEN;
+    }
+}
+
+async fn tunnel(
+    socket: Arc<dyn FrameSocket>,
+    udp: UdpSocket,
+    status: &Mutex<TunnelStatus>,
+) -> Result<(), Error> {
+    let batch = status.lock().config.batch;
+    let hold = Duration::from_millis(batch);
+    let mut pending = Batch {
+        frames: Vec::new(),
+        len: HEADER_LEN,
+        deadline: None,
+        seq: 0,
+    };
+    // Next sequence number expected from each peer
+    let mut expected: HashMap<SocketAddr, u8> = HashMap::new();
+    let mut buf = vec![0; u16::MAX.into()];
+
+    loop {
+        let deadline = pending.deadline;
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                pending.flush(&udp, status).await;
+            }
+            received = socket.recv() => {
+                let (frame, _) = received?;
+                // Error frames describe the local controller only
+                if frame.is_error() {
+                    continue;
+                }
+                if pending.len + encoded_len(&frame) > PACKET_MAX {
+                    pending.flush(&udp, status).await;
+                }
+                pending.len += encoded_len(&frame);
+                pending.frames.push(frame);
+                if hold.is_zero() {
+                    pending.flush(&udp, status).await;
+                } else if pending.deadline.is_none() {
+                    pending.deadline = Some(Instant::now() + hold);
+                }
+            }
+            received = udp.recv_from(&mut buf) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!(error = %e, "unable to receive packet");
+                        continue;
+                    }
+                };
+                if !status.lock().config.peers.contains(&from) {
+                    debug!(%from, "ignoring packet from unknown sender");
+                    status.lock().packets_invalid += 1;
+                    continue;
+                }
+                let (seq, frames) = match decode(&buf[..len]) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        debug!(%from, error = %e, "ignoring packet");
+                        status.lock().packets_invalid += 1;
+                        continue;
+                    }
+                };
+                {
+                    let mut status = status.lock();
+                    status.packets_received += 1;
+                    status.frames_received += frames.len() as u64;
+                    // Sequence numbers wrap, so only a short gap is a loss
+                    match expected.get(&from).map(|next| seq.wrapping_sub(*next)) {
+                        Some(gap) if gap >= 128 => status.packets_reordered += 1,
+                        gap => {
+                            status.packets_lost += u64::from(gap.unwrap_or(0));
+                            expected.insert(from, seq.wrapping_add(1));
+                        }
+                    }
+                }
+                for frame in &frames {
+                    socket.send(frame).await?;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,521 @@
+//! Tests for the admin interface.
+
+mod common;
+
+use common::{request, DockerStub, Server};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{GatewayBackend, LinkBackend, LinkStats, RuleStats};
+use rustycan4docker::logging::LogFilter;
+use rustycan4docker::recorder::RecordConfig;
+use rustycan4docker::{admin, api, Backend, NetworkManager, NetworkOptions};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+const EP1: &str = "0a1b2c3d4e5f60718293a4b5c6d7e8f9";
+const EP2: &str = "f9e8d7c6b5a4039281706f5e4d3c2b1a";
+
+struct Admin {
+    mgr: NetworkManager,
+    fake: Arc<FakeBackend>,
+    admin: Server,
+    /// Directory the admin routes have to be able to write to.
+    storage: PathBuf,
+    _dir: TempDir,
+    _docker: Option<DockerStub>,
+}
+
+impl Admin {
+    fn start() -> Self {
+        Self::start_with(None)
+    }
+
+    fn start_with(docker: Option<DockerStub>) -> Self {
+        Self::start_logging(docker, None)
+    }
+
+    fn start_logging(docker: Option<DockerStub>, log: Option<LogFilter>) -> Self {
+        let fake = Arc::new(FakeBackend::new());
+        let mgr =
+            NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+        let client = docker.as_ref().map(|d| d.docker());
+        let dir = tempfile::tempdir().unwrap();
+        let storage = dir.path().join("admin");
+        std::fs::create_dir(&storage).unwrap();
+        let admin = Server::start(admin::routes(
+            mgr.clone(),
+            client,
+            vec![storage.clone()],
+            log,
+        ));
+        Admin {
+            mgr,
+            fake,
+            admin,
+            storage,
+            _dir: dir,
+            _docker: docker,
+        }
+    }
+
+    async fn health(&self) -> (u16, Value) {
+        let (status, body) = self.get("/health").await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    async fn ready(&self) -> (u16, Value) {
+        let (status, body) = self.get("/ready").await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    async fn attach(&self, ep: &str) {
+        self.mgr
+            .namespace_attach(
+                NID.into(),
+                ep.into(),
+                format!("/run/netns/{ep}"),
+                "can0".into(),
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn get(&self, path: &str) -> (u16, String) {
+        let (status, body) = request(&self.admin.sock, "GET", path, String::new()).await;
+        (status.as_u16(), body)
+    }
+
+    /// Lines of the `/metrics` output for `name`, without comments.
+    async fn metric(&self, name: &str) -> Vec<String> {
+        let (status, body) = self.get("/metrics").await;
+        assert_eq!(status, 200);
+        body.lines()
+            .filter(|l| l.starts_with(name) && l[name.len()..].starts_with(['{', ' ']))
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn topology_metrics() {
+    let a = Admin::start();
+    assert_eq!(
+        a.metric("rustyvxcan_networks").await,
+        ["rustyvxcan_networks 0"]
+    );
+
+    a.mgr
+        .network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    for ep in [EP1, EP2] {
+        a.mgr
+            .namespace_attach(
+                NID.into(),
+                ep.into(),
+                format!("/run/netns/{ep}"),
+                "can0".into(),
+            )
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        a.metric("rustyvxcan_networks").await,
+        ["rustyvxcan_networks 1"]
+    );
+    assert_eq!(
+        a.metric("rustyvxcan_endpoints").await,
+        ["rustyvxcan_endpoints 2"]
+    );
+    assert_eq!(
+        a.metric("rustyvxcan_gateway_rules").await,
+        ["rustyvxcan_gateway_rules 6"]
+    );
+
+    a.mgr
+        .namespace_detach(NID.into(), EP2.into())
+        .await
+        .unwrap();
+    assert_eq!(
+        a.metric("rustyvxcan_endpoints").await,
+        ["rustyvxcan_endpoints 1"]
+    );
+    assert_eq!(
+        a.metric("rustyvxcan_gateway_rules").await,
+        ["rustyvxcan_gateway_rules 2"]
+    );
+
+    // Each link and gateway operation was timed
+    let ops = a
+        .metric("rustyvxcan_kernel_operation_duration_seconds_count")
+        .await;
+    assert!(ops
+        .iter()
+        .any(|l| l.contains(r#"operation="vxcan_add"} 2"#)));
+    assert!(ops.iter().any(|l| l.contains(r#"operation="rule_add"} 6"#)));
+}
+
+#[tokio::test]
+async fn traffic_counters() {
+    let a = Admin::start();
+    a.mgr
+        .network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    a.mgr
+        .namespace_attach(NID.into(), EP1.into(), "/run/netns/a".into(), "can0".into())
+        .await
+        .unwrap();
+
+    a.fake.set_link_stats(
+        "vcan0",
+        LinkStats {
+            rx_frames: 7,
+            tx_frames: 5,
+            rx_bytes: 56,
+            tx_dropped: 1,
+            ..Default::default()
+        },
+    );
+    a.fake.set_rule_stats(RuleStats {
+        src: "vcan0".into(),
+        dst: "vxcan0a1b2c3d".into(),
+        fd: false,
+        handled: 9,
+        dropped: 2,
+        deleted: 0,
+    });
+
+    let frames = a.metric("rustyvxcan_link_frames_total").await;
+    assert!(frames.contains(&format!(
+        r#"rustyvxcan_link_frames_total{{direction="rx",interface="vcan0",network="{NID}"}} 7"#
+    )));
+    assert!(frames.contains(&format!(
+        r#"rustyvxcan_link_frames_total{{direction="tx",interface="vcan0",network="{NID}"}} 5"#
+    )));
+    assert!(frames.contains(&format!(
+        r#"rustyvxcan_link_frames_total{{direction="rx",interface="vxcan0a1b2c3d",network="{NID}"}} 0"#
+    )));
+    let dropped = a.metric("rustyvxcan_link_dropped_total").await;
+    assert!(dropped.contains(&format!(
+        r#"rustyvxcan_link_dropped_total{{direction="tx",interface="vcan0",network="{NID}"}} 1"#
+    )));
+
+    let gw = a.metric("rustyvxcan_gateway_frames_total").await;
+    assert!(gw.contains(&format!(
+        r#"rustyvxcan_gateway_frames_total{{dst="vxcan0a1b2c3d",network="{NID}",result="handled",src="vcan0"}} 9"#
+    )));
+    assert!(gw.contains(&format!(
+        r#"rustyvxcan_gateway_frames_total{{dst="vxcan0a1b2c3d",network="{NID}",result="dropped",src="vcan0"}} 2"#
+    )));
+    assert!(gw.contains(&format!(
+        r#"rustyvxcan_gateway_frames_total{{dst="vcan0",network="{NID}",result="handled",src="vxcan0a1b2c3d"}} 0"#
+    )));
+
+    // Counters of removed interfaces and rules go away
+    a.mgr.network_delete(NID.into()).await.unwrap();
+    assert!(a.metric("rustyvxcan_link_frames_total").await.is_empty());
+    assert!(a.metric("rustyvxcan_gateway_frames_total").await.is_empty());
+}
+
+#[tokio::test]
+async fn request_metrics() {
+    let a = Admin::start();
+    let plugin = Server::start(api::routes(a.mgr.clone(), api::Scope::Local));
+    let post = |method: &'static str, body: serde_json::Value| {
+        let sock = plugin.sock.clone();
+        async move { request(&sock, "POST", &format!("/{method}"), body.to_string()).await }
+    };
+
+    post("Plugin.Activate", json!({})).await;
+    let create = json!({
+        "NetworkID": NID,
+        "Options": { "com.docker.network.generic": { "vxcan.id": "x" } },
+    });
+    post("NetworkDriver.CreateNetwork", create).await;
+    post(
+        "NetworkDriver.CreateEndpoint",
+        json!({ "NetworkID": NID, "EndpointID": EP1 }),
+    )
+    .await;
+
+    let requests = a.metric("rustyvxcan_request_duration_seconds_count").await;
+    assert!(requests.contains(&String::from(
+        r#"rustyvxcan_request_duration_seconds_count{method="Plugin.Activate"} 1"#
+    )));
+    assert!(requests.contains(&String::from(
+        r#"rustyvxcan_request_duration_seconds_count{method="NetworkDriver.CreateNetwork"} 1"#
+    )));
+    assert_eq!(
+        a.metric("rustyvxcan_errors_total").await,
+        [
+            r#"rustyvxcan_errors_total{kind="invalid_option"} 1"#,
+            r#"rustyvxcan_errors_total{kind="network_not_found"} 1"#,
+        ]
+    );
+}
+
+#[tokio::test]
+async fn unknown_routes() {
+    let a = Admin::start();
+    assert_eq!(a.get("/nothing").await.0, 404);
+}
+
+fn check<'a>(health: &'a Value, name: &str) -> &'a Value {
+    health["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|c| c["name"] == name)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn healthy() {
+    let docker = DockerStub::start(json!([]), Vec::new()).await;
+    let a = Admin::start_with(Some(docker));
+    a.mgr
+        .network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    a.attach(EP1).await;
+    a.attach(EP2).await;
+
+    let (status, health) = a.health().await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        health,
+        json!({
+            "healthy": true,
+            "checks": [
+                { "name": "kernel", "ok": true },
+                { "name": "state", "ok": true },
+            ],
+        })
+    );
+
+    let (status, ready) = a.ready().await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        ready,
+        json!({
+            "ready": true,
+            "checks": [
+                { "name": "kernel", "ok": true },
+                { "name": "docker", "ok": true },
+                { "name": "storage", "ok": true },
+            ],
+        })
+    );
+}
+
+#[tokio::test]
+async fn unhealthy_without_docker_or_kernel_support() {
+    let a = Admin::start();
+    a.fake.fail("can-gw");
+    a.fake.fail("can-raw");
+
+    let (status, health) = a.health().await;
+    assert_eq!(status, 503);
+    assert_eq!(health["healthy"], false);
+    assert_eq!(
+        check(&health, "kernel")["problems"],
+        json!([
+            "can-gw is not supported, and frames cannot be forwarded in userspace either: can-raw is not supported"
+        ])
+    );
+    assert_eq!(check(&health, "state")["ok"], true);
+
+    // Without docker, the plugin is not ready either
+    let (status, ready) = a.ready().await;
+    assert_eq!(status, 503);
+    assert_eq!(ready["ready"], false);
+    assert_eq!(check(&ready, "kernel")["ok"], false);
+    assert_eq!(
+        check(&ready, "docker")["problems"],
+        json!(["not connected to docker"])
+    );
+
+    assert!(a.mgr.kernel_support().await.is_err());
+    a.fake.recover("can-raw");
+    assert_eq!(a.mgr.kernel_support().await, Ok(()));
+}
+
+#[tokio::test]
+async fn not_ready_without_storage() {
+    let docker = DockerStub::start(json!([]), Vec::new()).await;
+    let a = Admin::start_with(Some(docker));
+    a.mgr
+        .network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    let records = tempfile::tempdir().unwrap();
+    let config = RecordConfig::new(records.path().to_path_buf());
+    a.mgr.record_start(NID.into(), config).await.unwrap();
+    assert_eq!(a.ready().await.0, 200);
+
+    // Someone removes the directories the plugin writes to
+    std::fs::remove_dir(&a.storage).unwrap();
+    std::fs::remove_dir_all(records.path()).unwrap();
+    let (status, ready) = a.ready().await;
+    assert_eq!(status, 503);
+    let problems = check(&ready, "storage")["problems"].as_array().unwrap();
+    assert_eq!(problems.len(), 2, "{ready}");
+    assert!(problems[0]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("{} is not writable", a.storage.display())));
+    assert!(problems[1]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("{} is not writable", records.path().display())));
+
+    // Liveness does not depend on it
+    assert_eq!(a.health().await.0, 200);
+}
+
+#[tokio::test]
+async fn kernel_state_drift() {
+    let a = Admin::start();
+    a.mgr
+        .network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    a.attach(EP1).await;
+    a.attach(EP2).await;
+    assert_eq!(a.mgr.kernel_verify().await, Ok(Vec::new()));
+
+    // Someone else meddles with the kernel behind the plugin's back
+    a.fake.rule_del("vcan0", "vxcan0a1b2c3d").await.unwrap();
+    a.fake.link_del("vxcanf9e8d7c6").await.unwrap();
+    a.fake.link_insert("vcan9");
+    a.fake.rule_add("vcan9", "vxcan0a1b2c3d").await.unwrap();
+
+    let problems = vec![
+        format!("network {NID}: interface vxcanf9e8d7c6 is missing"),
+        format!("network {NID}: rule vcan0 -> vxcan0a1b2c3d is missing"),
+        format!("network {NID}: rule vcan0 -> vxcanf9e8d7c6 is missing"),
+        format!("network {NID}: rule vxcan0a1b2c3d -> vxcanf9e8d7c6 is missing"),
+        format!("network {NID}: rule vxcanf9e8d7c6 -> vcan0 is missing"),
+        format!("network {NID}: rule vxcanf9e8d7c6 -> vxcan0a1b2c3d is missing"),
+        format!("network {NID}: unexpected rule vcan9 -> vxcan0a1b2c3d"),
+    ];
+    assert_eq!(a.mgr.kernel_verify().await, Ok(problems.clone()));
+
+    let (status, health) = a.health().await;
+    assert_eq!(status, 503);
+    assert_eq!(check(&health, "state")["problems"], json!(problems));
+}
+
+#[tokio::test]
+async fn network_routes() {
+    let a = Admin::start();
+    assert_eq!(a.get("/networks").await, (200, String::from("[]")));
+
+    a.mgr
+        .network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    a.attach(EP1).await;
+    a.fake.set_rule_stats(RuleStats {
+        src: "vxcan0a1b2c3d".into(),
+        dst: "vcan0".into(),
+        fd: false,
+        handled: 5,
+        dropped: 1,
+        deleted: 0,
+    });
+
+    let (status, body) = a.get("/networks").await;
+    assert_eq!(status, 200);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), json!([NID]));
+
+    let (status, body) = a.get(&format!("/networks/{NID}")).await;
+    assert_eq!(status, 200);
+    let info: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(info["interface"], "vcan0");
+    assert_eq!(info["endpoints"][0]["id"], EP1);
+
+    let (status, body) = a.get(&format!("/networks/{NID}/stats")).await;
+    assert_eq!(status, 200);
+    let stats: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        stats["frames"],
+        json!({ "handled": 5, "dropped": 1, "deleted": 0 })
+    );
+    assert_eq!(
+        stats["routes"],
+        json!([
+            { "src": "vcan0", "dst": "vxcan0a1b2c3d", "handled": 0, "dropped": 0, "deleted": 0 },
+            { "src": "vxcan0a1b2c3d", "dst": "vcan0", "handled": 5, "dropped": 1, "deleted": 0 },
+        ])
+    );
+    assert_eq!(stats["endpoints"][0]["sent"]["handled"], 5);
+
+    let (status, body) = a
+        .get(&format!("/networks/{NID}/endpoints/{EP1}/stats"))
+        .await;
+    assert_eq!(status, 200);
+    let stats: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(stats["sent"]["dropped"], 1);
+
+    let (status, body) = a
+        .get(&format!("/networks/{NID}/endpoints/{EP2}/stats"))
+        .await;
+    assert_eq!(status, 404);
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap(),
+        json!({ "Err": format!("endpoint {EP2} not found") })
+    );
+    assert_eq!(a.get("/networks/0000/stats").await.0, 404);
+
+    // Endpoint traffic is exported as metrics too
+    let sent = a.metric("rustyvxcan_endpoint_frames_total").await;
+    assert!(sent.contains(&format!(
+        r#"rustyvxcan_endpoint_frames_total{{direction="sent",endpoint="{EP1}",network="{NID}",result="handled"}} 5"#
+    )));
+}
+
+#[tokio::test]
+async fn log_filter_changes_at_runtime() {
+    let (layer, log) = LogFilter::new("info").unwrap();
+    let _logger = tracing_subscriber::registry().with(layer).set_default();
+    let a = Admin::start_logging(None, Some(log));
+    let set = |filter: &str| {
+        let body = json!({ "filter": filter }).to_string();
+        let sock = a.admin.sock.clone();
+        async move {
+            let (status, body) = request(&sock, "PUT", "/log", body).await;
+            (
+                status.as_u16(),
+                serde_json::from_str::<Value>(&body).unwrap(),
+            )
+        }
+    };
+
+    assert_eq!(
+        a.get("/log").await,
+        (200, json!({ "filter": "info" }).to_string())
+    );
+    assert!(!tracing::enabled!(tracing::Level::DEBUG));
+
+    let (status, rsp) = set("info,rustycan4docker::api=debug").await;
+    assert_eq!(status, 200, "{rsp}");
+    assert_eq!(rsp, json!({ "filter": "rustycan4docker::api=debug,info" }));
+    assert!(tracing::enabled!(target: "rustycan4docker::api", tracing::Level::DEBUG));
+    assert!(!tracing::enabled!(tracing::Level::DEBUG));
+
+    // A bad filter leaves the one in effect
+    let (status, rsp) = set("info,=bogus=").await;
+    assert_eq!(status, 400, "{rsp}");
+    assert!(tracing::enabled!(target: "rustycan4docker::api", tracing::Level::DEBUG));
+
+    // Without a logger of its own, the plugin cannot change it
+    let other = Admin::start();
+    assert_eq!(other.get("/log").await.0, 500);
+}
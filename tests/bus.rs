@@ -0,0 +1,145 @@
+//! Tests for emulating the timing of a real CAN bus.
+
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{FrameBackend, GatewayMode};
+use rustycan4docker::bus::{self, Bus, BusConfig};
+use rustycan4docker::frame::{Frame, EFF_FLAG, FD_BRS, RTR_FLAG};
+use rustycan4docker::{Backend, NetworkManager, NetworkOptions};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+const EP1: &str = "0a1b2c3d4e5f60718293a4b5c6d7e8f9";
+
+fn frame(id: u32, len: usize) -> Frame {
+    Frame {
+        id,
+        data: vec![0x55; len],
+        fd: false,
+        flags: 0,
+    }
+}
+
+#[test]
+fn frame_timing() {
+    assert_eq!(bus::bits(&frame(0x123, 8)), (135, 0));
+    assert_eq!(bus::bits(&frame(0x123, 0)), (55, 0));
+    assert_eq!(bus::bits(&frame(0x123 | RTR_FLAG, 8)), (55, 0));
+    assert_eq!(bus::bits(&frame(0x123 | EFF_FLAG, 8)), (160, 0));
+    let fd = Frame {
+        id: 0x123,
+        data: vec![0; 64],
+        fd: true,
+        flags: FD_BRS,
+    };
+    assert_eq!(bus::bits(&fd), (34, 678));
+
+    let config = BusConfig {
+        bitrate: 500_000,
+        data_bitrate: 2_000_000,
+    };
+    assert_eq!(
+        config.duration(&frame(0x123, 8)),
+        Duration::from_micros(270)
+    );
+    assert_eq!(config.duration(&fd), Duration::from_micros(68 + 339));
+    let slow = Frame { flags: 0, ..fd };
+    assert_eq!(config.duration(&slow), Duration::from_micros(68 + 1356));
+
+    // Lower identifiers win, standard frames win over extended frames with
+    // the same base identifier, and data frames over remote requests
+    let order = [
+        frame(EFF_FLAG, 0),
+        frame(0x001, 0),
+        frame(0x100, 0),
+        frame(0x100 | RTR_FLAG, 0),
+        frame(0x100 << 18 | EFF_FLAG, 0),
+        frame(0x100 << 18 | EFF_FLAG | RTR_FLAG, 0),
+        frame(0x101, 0),
+    ];
+    for pair in order.windows(2) {
+        assert!(
+            bus::priority(&pair[0]) < bus::priority(&pair[1]),
+            "{:x} {:x}",
+            pair[0].id,
+            pair[1].id
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn arbitration_and_load() {
+    let bus = Arc::new(Bus::new(BusConfig::new(500_000)));
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let start = Instant::now();
+    let send = |id: u32| {
+        let bus = bus.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            bus.transmit(&frame(id, 8)).await;
+            tx.send(id).unwrap();
+        })
+    };
+
+    // The first frame takes the idle bus, the others wait for it and are
+    // then sent by priority
+    send(0x400);
+    tokio::task::yield_now().await;
+    for id in [0x300, 0x100, 0x200] {
+        send(id);
+    }
+    let mut sent = Vec::new();
+    for _ in 0..4 {
+        sent.push(rx.recv().await.unwrap());
+    }
+    assert_eq!(sent, [0x400, 0x100, 0x200, 0x300]);
+    // Timers wake up late, the bus keeps time by itself
+    assert!(start.elapsed() >= Duration::from_micros(4 * 270));
+
+    let status = bus.status();
+    assert_eq!(status.frames, 4);
+    assert!((status.load - 0.108).abs() < 1e-9, "{}", status.load);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(bus.status().load, 0.0);
+}
+
+#[tokio::test]
+async fn bitrate_limits_throughput() {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    let options = NetworkOptions {
+        bus: Some(BusConfig::new(125_000)),
+        ..Default::default()
+    };
+    mgr.network_add(NID.into(), options).await.unwrap();
+    mgr.endpoint_create(NID.into(), EP1.into()).await.unwrap();
+    let join = mgr
+        .endpoint_attach(NID.into(), EP1.into(), String::new(), String::new())
+        .await
+        .unwrap();
+    let host = fake.open("vcan0").await.unwrap();
+    let ep1 = fake.open(&join.SrcName).await.unwrap();
+
+    // 100 frames of 135 bits take 108 ms at 125 kbit/s
+    let start = Instant::now();
+    for i in 0..100 {
+        host.send(&frame(0x100 + i, 8)).await.unwrap();
+    }
+    for i in 0..100 {
+        assert_eq!(ep1.recv().await.unwrap().0.id, 0x100 + i);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(108));
+
+    let info = mgr.network_inspect(NID.into()).await.unwrap();
+    assert_eq!(info.gateway, GatewayMode::Userspace);
+    let bus = info.bus.unwrap();
+    assert_eq!(bus.frames, 100);
+    assert!(bus.load > 5.0, "{}", bus.load);
+
+    let metrics = mgr.metrics_export().await;
+    assert!(metrics.contains(&format!(
+        "rustyvxcan_bus_frames_total{{network=\"{NID}\"}} 100"
+    )));
+}
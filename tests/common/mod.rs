@@ -0,0 +1,137 @@
+//! Test helpers shared by the integration tests.
+
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use bollard::Docker;
+use hyper::{Body, Request, StatusCode};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::wrappers::UnixListenerStream;
+use warp::Filter;
+
+/// Routes served on a temporary unix socket.
+pub struct Server {
+    _dir: TempDir,
+    pub sock: PathBuf,
+}
+
+impl Server {
+    pub fn start<F>(routes: F) -> Self
+    where
+        F: Filter + Clone + Send + Sync + 'static,
+        F::Extract: warp::Reply,
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let sock = dir.path().join("server.sock");
+        let incoming = UnixListenerStream::new(UnixListener::bind(&sock).unwrap());
+        tokio::spawn(warp::serve(routes).run_incoming(incoming));
+        Server { _dir: dir, sock }
+    }
+}
+
+/// Send one HTTP request over the unix socket `sock`.
+pub async fn request(sock: &Path, method: &str, path: &str, body: String) -> (StatusCode, String) {
+    let stream = UnixStream::connect(sock).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+
+    let req = Request::builder()
+        .method(method)
+        .uri(path)
+        .header("Host", "localhost")
+        .header("Content-Length", body.len())
+        .body(Body::from(body))
+        .unwrap();
+    let rsp = sender.send_request(req).await.unwrap();
+    let status = rsp.status();
+    let bytes = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+    (status, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+/// Minimal stand-in for the Docker Engine API on a temporary unix socket.
+///
+/// Serves `GET /networks` from a fixed list and `GET /events` as a stream of
+/// fixed events that ends once all of them were sent; both with or without
+/// an API version prefix.
+pub struct DockerStub {
+    _dir: TempDir,
+    sock: PathBuf,
+}
+
+impl DockerStub {
+    pub async fn start(networks: Value, events: Vec<Value>) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let sock = dir.path().join("docker.sock");
+
+        let list = warp::get()
+            .and(versioned("networks"))
+            .map(move || warp::reply::json(&networks));
+        let body: String = events.iter().map(|e| format!("{e}\n")).collect();
+        let events = warp::get()
+            .and(versioned("events"))
+            .map(move || body.clone());
+        let ping = warp::get().and(versioned("_ping")).map(|| "OK");
+
+        let incoming = UnixListenerStream::new(UnixListener::bind(&sock).unwrap());
+        tokio::spawn(warp::serve(list.or(events).or(ping)).run_incoming(incoming));
+
+        DockerStub { _dir: dir, sock }
+    }
+
+    /// A client connected to the stub.
+    pub fn docker(&self) -> Docker {
+        Docker::connect_with_unix(self.sock.to_str().unwrap(), 5, bollard::API_DEFAULT_VERSION)
+            .unwrap()
+    }
+}
+
+/// Match `/<name>` and `/v<version>/<name>`, ignoring the query string.
+fn versioned(name: &'static str) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let plain = warp::path(name).and(warp::path::end());
+    let prefixed = warp::path::param::<String>()
+        .and_then(|v: String| async move {
+            match v.starts_with('v') {
+                true => Ok(()),
+                false => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one()
+        .and(warp::path(name))
+        .and(warp::path::end());
+    plain.or(prefixed).unify()
+}
+
+/// A network as listed by the Docker Engine API.
+pub fn docker_network(id: &str, name: &str, driver: &str, options: Option<Value>) -> Value {
+    let mut n = serde_json::json!({
+        "Name": name,
+        "Id": id,
+        "Scope": "local",
+        "Driver": driver,
+        "EnableIPv6": false,
+        "Internal": false,
+        "Attachable": false,
+        "Ingress": false,
+        "Containers": {},
+        "Labels": {},
+    });
+    if let Some(o) = options {
+        n["Options"] = o;
+    }
+    n
+}
+
+/// A network event as streamed by the Docker Engine API.
+pub fn docker_network_event(action: &str, id: &str, driver: &str) -> Value {
+    serde_json::json!({
+        "Type": "network",
+        "Action": action,
+        "Actor": { "ID": id, "Attributes": { "name": "net", "type": driver } },
+        "scope": "local",
+        "time": 1666110915,
+        "timeNano": 1666110915000000000i64,
+    })
+}
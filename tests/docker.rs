@@ -0,0 +1,126 @@
+//! Tests for restoring networks from, and following events of, a stubbed
+//! Docker daemon.
+
+mod common;
+
+use common::{docker_network, docker_network_event, DockerStub};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::{Backend, Error, NetworkManager};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const OURS: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+const DEFAULTS: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+const BRIDGE: &str = "3333333333333333333333333333333333333333333333333333333333333333";
+const BROKEN: &str = "4444444444444444444444444444444444444444444444444444444444444444";
+const EP: &str = "0a1b2c3d4e5f60718293a4b5c6d7e8f9";
+
+fn manager() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    (mgr, fake)
+}
+
+fn link_names(fake: &FakeBackend) -> Vec<String> {
+    fake.links().into_keys().collect()
+}
+
+#[tokio::test]
+async fn load_restores_own_networks() {
+    let networks = json!([
+        docker_network(
+            OURS,
+            "can1",
+            "rustyvxcan",
+            Some(json!({ "vxcan.dev": "can", "vxcan.id": "1" }))
+        ),
+        docker_network(DEFAULTS, "plain", "rustyvxcan", None),
+        docker_network(
+            BRIDGE,
+            "bridge",
+            "bridge",
+            Some(json!({ "vxcan.dev": "br" }))
+        ),
+        docker_network(
+            BROKEN,
+            "broken",
+            "rustyvxcan",
+            Some(json!({ "vxcan.id": "x" }))
+        ),
+    ]);
+    let docker = DockerStub::start(networks, Vec::new()).await;
+    let (mgr, fake) = manager();
+
+    mgr.network_load(&docker.docker()).await;
+    assert_eq!(link_names(&fake), ["can1", "vcan0"]);
+
+    assert_eq!(mgr.endpoint_create(OURS.into(), EP.into()).await, Ok(()));
+    for nid in [BRIDGE, BROKEN] {
+        assert_eq!(
+            mgr.endpoint_create(nid.into(), EP.into()).await,
+            Err(Error::NetworkNotFound(nid.into()))
+        );
+    }
+}
+
+#[tokio::test]
+async fn load_keeps_networks_sharing_a_device() {
+    let networks = json!([
+        docker_network(OURS, "a", "rustyvxcan", Some(json!({ "vxcan.id": "0" }))),
+        docker_network(DEFAULTS, "b", "rustyvxcan", None),
+    ]);
+    let docker = DockerStub::start(networks, Vec::new()).await;
+    let (mgr, fake) = manager();
+
+    mgr.network_load(&docker.docker()).await;
+    assert_eq!(link_names(&fake), ["vcan0"]);
+
+    // The device stays until both networks are gone
+    assert_eq!(mgr.network_delete(OURS.into()).await, Ok(()));
+    assert_eq!(link_names(&fake), ["vcan0"]);
+    assert_eq!(mgr.network_delete(DEFAULTS.into()).await, Ok(()));
+    assert!(link_names(&fake).is_empty());
+}
+
+#[tokio::test]
+async fn load_without_docker() {
+    let dir = tempfile::tempdir().unwrap();
+    let sock = dir.path().join("missing.sock");
+    let docker =
+        bollard::Docker::connect_with_unix(sock.to_str().unwrap(), 5, bollard::API_DEFAULT_VERSION)
+            .unwrap();
+    let (mgr, fake) = manager();
+
+    mgr.network_load(&docker).await;
+    assert!(link_names(&fake).is_empty());
+}
+
+#[tokio::test]
+async fn watch_removes_destroyed_networks() {
+    let networks = json!([
+        docker_network(
+            OURS,
+            "can1",
+            "rustyvxcan",
+            Some(json!({ "vxcan.dev": "can", "vxcan.id": "1" }))
+        ),
+        docker_network(DEFAULTS, "plain", "rustyvxcan", None),
+    ]);
+    let events: Vec<Value> = vec![
+        docker_network_event("destroy", BRIDGE, "bridge"),
+        docker_network_event("destroy", OURS, "rustyvxcan"),
+    ];
+    let docker = DockerStub::start(networks, events).await;
+    let (mgr, fake) = manager();
+
+    let docker = docker.docker();
+    mgr.network_load(&docker).await;
+    mgr.network_create(BROKEN.into(), &json!({ "vxcan.id": "7" }))
+        .await
+        .unwrap();
+    assert_eq!(link_names(&fake), ["can1", "vcan0", "vcan7"]);
+
+    // Returns once the stub has sent all its events
+    mgr.network_watch(&docker).await;
+    assert_eq!(link_names(&fake), ["vcan0", "vcan7"]);
+}
@@ -0,0 +1,234 @@
+//! Injection of error frames into a network, on the fake backend.
+
+mod common;
+
+use common::{request, Server};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{FrameBackend, FrameSocket};
+use rustycan4docker::frame::{Frame, ERR_FLAG};
+use rustycan4docker::inject::{ErrorClass, ErrorState, ErrorStep, InjectConfig};
+use rustycan4docker::{admin, Backend, NetworkManager, NetworkOptions};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+
+async fn network() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    mgr.network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    (mgr, fake)
+}
+
+/// Join endpoint `ep` and open a socket on its container side.
+async fn join(mgr: &NetworkManager, fake: &FakeBackend, ep: &str) -> Arc<dyn FrameSocket> {
+    mgr.endpoint_create(NID.into(), ep.into()).await.unwrap();
+    let rsp = mgr
+        .endpoint_attach(NID.into(), ep.into(), String::new(), String::from("{}"))
+        .await
+        .unwrap();
+    fake.open(&rsp.SrcName).await.unwrap()
+}
+
+/// The frames `socket` receives until it stays quiet.
+async fn received(socket: &Arc<dyn FrameSocket>) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    while let Ok(res) = tokio::time::timeout(Duration::from_millis(100), socket.recv()).await {
+        frames.push(res.unwrap().0);
+    }
+    frames
+}
+
+async fn finished(mgr: &NetworkManager) -> Value {
+    for _ in 0..500 {
+        let info = mgr.network_inspect(NID.into()).await.unwrap();
+        let status = info.injection.unwrap();
+        if !status.running {
+            return serde_json::to_value(status).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("injection did not finish");
+}
+
+#[test]
+fn error_frames() {
+    let frame = ErrorStep::state(ErrorState::ErrorPassive).frame();
+    assert!(frame.is_error());
+    assert_eq!(frame.id, ERR_FLAG | 0x204);
+    assert_eq!(frame.data, [0, 0x30, 0, 0, 0, 0, 128, 128]);
+    assert_eq!(frame.to_candump(), "20000204#0030000000008080");
+
+    let frame = ErrorStep::state(ErrorState::BusOff).frame();
+    assert_eq!(frame.id, ERR_FLAG | 0x040);
+    assert_eq!(frame.data, [0; 8]);
+
+    // Classes add up, data replaces the state's
+    let step = ErrorStep {
+        class: vec![ErrorClass::Ack, ErrorClass::Protocol],
+        data: Some(vec![0, 0, 0x08]),
+        ..ErrorStep::state(ErrorState::ErrorWarning)
+    };
+    let frame = step.frame();
+    assert_eq!(frame.id, ERR_FLAG | 0x22c);
+    assert_eq!(frame.data, [0, 0, 0x08, 0, 0, 0, 0, 0]);
+
+    let bad = [
+        ErrorStep {
+            state: None,
+            ..ErrorStep::state(ErrorState::BusOff)
+        },
+        ErrorStep {
+            data: Some(vec![0; 9]),
+            ..ErrorStep::state(ErrorState::BusOff)
+        },
+    ];
+    for step in bad {
+        assert!(step.validate().is_err(), "{step:?}");
+    }
+    assert!(InjectConfig::new(Vec::new()).validate().is_err());
+    let forever = InjectConfig {
+        loops: 0,
+        ..InjectConfig::new(vec![ErrorStep::state(ErrorState::BusOff)])
+    };
+    assert!(forever.validate().is_err());
+}
+
+#[tokio::test]
+async fn network_or_one_endpoint() {
+    let (mgr, fake) = network().await;
+    let host = fake.open("vcan0").await.unwrap();
+    let a = join(&mgr, &fake, "a").await;
+    let b = join(&mgr, &fake, "b").await;
+
+    let config = InjectConfig::new(vec![ErrorStep::state(ErrorState::BusOff)]);
+    let status = mgr.inject_start(NID.into(), config).await.unwrap();
+    assert_eq!(status.interfaces.len(), 3);
+    let bus_off = ErrorStep::state(ErrorState::BusOff).frame();
+    // Once each, not forwarded between the interfaces
+    for socket in [&host, &a, &b] {
+        assert_eq!(received(socket).await, std::slice::from_ref(&bus_off));
+    }
+    assert_eq!(finished(&mgr).await["frames"], 1);
+
+    let config = InjectConfig {
+        endpoint: Some("b".into()),
+        ..InjectConfig::new(vec![ErrorStep::state(ErrorState::ErrorPassive)])
+    };
+    mgr.inject_start(NID.into(), config).await.unwrap();
+    assert_eq!(received(&b).await.len(), 1);
+    assert!(received(&a).await.is_empty());
+    assert!(received(&host).await.is_empty());
+
+    let config = InjectConfig {
+        endpoint: Some("c".into()),
+        ..InjectConfig::new(vec![ErrorStep::state(ErrorState::BusOff)])
+    };
+    assert!(mgr.inject_start(NID.into(), config).await.is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn scripted_sequence() {
+    let (mgr, fake) = network().await;
+    let host = fake.open("vcan0").await.unwrap();
+
+    // Warning, passive twice 50 ms apart, off, restarted after 100 ms
+    let config = InjectConfig::new(vec![
+        ErrorStep::state(ErrorState::ErrorWarning),
+        ErrorStep {
+            delay: 50,
+            repeat: 2,
+            ..ErrorStep::state(ErrorState::ErrorPassive)
+        },
+        ErrorStep::state(ErrorState::BusOff),
+        ErrorStep {
+            delay: 100,
+            ..ErrorStep::state(ErrorState::Restarted)
+        },
+    ]);
+    let start = Instant::now();
+    mgr.inject_start(NID.into(), config).await.unwrap();
+
+    let mut frames = Vec::new();
+    for _ in 0..5 {
+        let (frame, _) = host.recv().await.unwrap();
+        frames.push((frame.id & !ERR_FLAG, (Instant::now() - start).as_millis()));
+    }
+    assert_eq!(
+        frames,
+        [
+            (0x204, 0),
+            (0x204, 50),
+            (0x204, 100),
+            (0x040, 100),
+            (0x104, 200)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn injection_through_admin_api() {
+    let (mgr, fake) = network().await;
+    let server = Server::start(admin::routes(mgr.clone(), None, Vec::new(), None));
+    let call = |method: &'static str, body: Value| {
+        let sock = server.sock.clone();
+        async move {
+            let body = if body.is_null() {
+                String::new()
+            } else {
+                body.to_string()
+            };
+            let path = format!("/networks/{NID}/errors");
+            let (status, body) = request(&sock, method, &path, body).await;
+            (
+                status.as_u16(),
+                serde_json::from_str::<Value>(&body).unwrap(),
+            )
+        }
+    };
+    let host = fake.open("vcan0").await.unwrap();
+
+    for bad in [
+        json!({ "sequence": [] }),
+        json!({ "sequence": [{ "state": "melted" }] }),
+        json!({ "sequence": [{ "delay": 10 }] }),
+        json!({ "sequence": [{ "class": ["ack"], "data": [0, 0, 0, 0, 0, 0, 0, 0, 0] }] }),
+        json!({ "sequence": [{ "state": "bus-off" }], "loops": 0 }),
+    ] {
+        let (status, rsp) = call("POST", bad).await;
+        assert_eq!(status, 400, "{rsp}");
+    }
+    let (status, _) = call(
+        "POST",
+        json!({ "endpoint": "missing", "sequence": [{ "state": "bus-off" }] }),
+    )
+    .await;
+    assert_eq!(status, 404);
+
+    // Repeated until stopped
+    let (status, rsp) = call(
+        "POST",
+        json!({ "sequence": [{ "state": "error-warning", "delay": 10 }], "loops": 0 }),
+    )
+    .await;
+    assert_eq!(status, 200, "{rsp}");
+    assert_eq!(rsp["interfaces"], json!(["vcan0"]));
+    let (status, _) = call("POST", json!({ "sequence": [{ "state": "bus-off" }] })).await;
+    assert_eq!(status, 409);
+    for _ in 0..3 {
+        let (frame, _) = host.recv().await.unwrap();
+        assert_eq!(frame.to_candump(), "20000204#000C000000006060");
+    }
+    let (status, rsp) = call("DELETE", Value::Null).await;
+    assert_eq!(status, 200);
+    assert_eq!(rsp["running"], false);
+    assert!(rsp["frames"].as_u64().unwrap() >= 3, "{rsp}");
+    let (_, rsp) = call("GET", Value::Null).await;
+    assert_eq!(rsp, Value::Null);
+    let (_, rsp) = call("DELETE", Value::Null).await;
+    assert_eq!(rsp, Value::Null);
+}
@@ -0,0 +1,271 @@
+//! Tests for injecting faults into the frames of an endpoint.
+
+mod common;
+
+use common::{request, Server};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{FrameBackend, FrameSocket, GatewayMode};
+use rustycan4docker::fault::Faults;
+use rustycan4docker::frame::{Frame, RTR_FLAG};
+use rustycan4docker::{admin, Backend, Error, NetworkManager, NetworkOptions};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+const EP1: &str = "0a1b2c3d4e5f60718293a4b5c6d7e8f9";
+const EP2: &str = "f9e8d7c6b5a4039281706f5e4d3c2b1a";
+
+fn manager() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    (mgr, fake)
+}
+
+async fn network(mgr: &NetworkManager, gateway: GatewayMode) {
+    let options = NetworkOptions {
+        gateway,
+        ..Default::default()
+    };
+    mgr.network_add(NID.into(), options).await.unwrap();
+}
+
+fn frame(id: u32) -> Frame {
+    Frame {
+        id,
+        data: vec![id as u8; 8],
+        fd: false,
+        flags: 0,
+    }
+}
+
+/// Join endpoint `ep` with the Join options `options` and open a socket on
+/// its container side.
+async fn join(
+    mgr: &NetworkManager,
+    fake: &FakeBackend,
+    ep: &str,
+    options: Value,
+) -> Result<Arc<dyn FrameSocket>, Error> {
+    mgr.endpoint_create(NID.into(), ep.into()).await.unwrap();
+    let rsp = mgr
+        .endpoint_attach(NID.into(), ep.into(), String::new(), options.to_string())
+        .await?;
+    Ok(fake.open(&rsp.SrcName).await.unwrap())
+}
+
+/// The frames `socket` receives until it stays quiet.
+async fn received(socket: &Arc<dyn FrameSocket>) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    while let Ok(res) = tokio::time::timeout(Duration::from_millis(100), socket.recv()).await {
+        frames.push(res.unwrap().0);
+    }
+    frames
+}
+
+fn ids(frames: &[Frame]) -> Vec<u32> {
+    frames.iter().map(|f| f.id).collect()
+}
+
+#[test]
+fn fault_combination() {
+    let faults = Faults {
+        drop: 10.0,
+        delay: 5,
+        ..Default::default()
+    };
+    assert!(Faults::default().is_none());
+    assert_eq!(faults.then(&Faults::default()), faults);
+    let both = faults.then(&faults);
+    assert!((both.drop - 19.0).abs() < 1e-9);
+    assert_eq!(both.delay, 10);
+
+    assert!(faults.validate().is_ok());
+    for bad in [
+        Faults {
+            drop: 100.5,
+            ..Default::default()
+        },
+        Faults {
+            reorder: -1.0,
+            ..Default::default()
+        },
+        Faults {
+            jitter: 60_001,
+            ..Default::default()
+        },
+    ] {
+        assert!(matches!(bad.validate(), Err(Error::InvalidOption(_))));
+    }
+
+    // Corruption flips exactly one bit, and leaves frames without payload
+    let mut rng = rand::thread_rng();
+    let corrupt = Faults {
+        corrupt: 100.0,
+        ..Default::default()
+    };
+    let mut f = frame(0x123);
+    corrupt.apply(&mut f, &mut rng);
+    let flipped: u32 = f
+        .data
+        .iter()
+        .zip(&frame(0x123).data)
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum();
+    assert_eq!(flipped, 1);
+    let mut rtr = Frame {
+        id: 0x123 | RTR_FLAG,
+        data: Vec::new(),
+        fd: false,
+        flags: 0,
+    };
+    corrupt.apply(&mut rtr, &mut rng);
+    assert!(rtr.data.is_empty());
+}
+
+#[tokio::test]
+async fn faults_from_join_options() {
+    let (mgr, fake) = manager();
+    network(&mgr, GatewayMode::Userspace).await;
+    let host = fake.open("vcan0").await.unwrap();
+
+    let err = join(&mgr, &fake, EP1, json!({ "vxcan.drop": "101" })).await;
+    assert!(matches!(err, Err(Error::InvalidOption(_))));
+    mgr.endpoint_delete(NID.into(), EP1.into()).await.unwrap();
+
+    // Docker's own options are passed along and ignored
+    let options = json!({
+        "com.docker.network.endpoint.exposedports": [],
+        "vxcan.drop": "100",
+    });
+    let ep1 = join(&mgr, &fake, EP1, options).await.unwrap();
+    let ep2 = join(&mgr, &fake, EP2, json!({ "vxcan.duplicate": "100" }))
+        .await
+        .unwrap();
+
+    // Nothing reaches or leaves the first endpoint, the second gets
+    // everything twice
+    host.send(&frame(0x100)).await.unwrap();
+    ep1.send(&frame(0x101)).await.unwrap();
+    assert!(received(&ep1).await.is_empty());
+    assert_eq!(ids(&received(&ep2).await), [0x100, 0x100]);
+    assert!(received(&host).await.is_empty());
+    ep2.send(&frame(0x102)).await.unwrap();
+    assert_eq!(ids(&received(&host).await), [0x102, 0x102]);
+
+    let info = mgr.network_inspect(NID.into()).await.unwrap();
+    assert_eq!(info.endpoints[0].faults.unwrap().drop, 100.0);
+    assert_eq!(info.endpoints[1].faults.unwrap().duplicate, 100.0);
+
+    // Lost frames count as dropped, on the one route into the hub and, for
+    // both copies of the second endpoint's frame, on the route out of it
+    let stats = mgr.endpoint_stats(NID.into(), EP1.into()).await.unwrap();
+    assert_eq!(stats.sent.dropped, 1);
+    assert_eq!(stats.received.dropped, 3);
+
+    // Clearing the faults takes effect at once
+    mgr.endpoint_faults(NID.into(), EP1.into(), Faults::default())
+        .await
+        .unwrap();
+    host.send(&frame(0x100)).await.unwrap();
+    assert_eq!(ids(&received(&ep1).await), [0x100]);
+    assert_eq!(ids(&received(&ep2).await), [0x100, 0x100]);
+}
+
+#[tokio::test]
+async fn delay_and_reorder() {
+    let (mgr, fake) = manager();
+    network(&mgr, GatewayMode::Userspace).await;
+    let host = fake.open("vcan0").await.unwrap();
+    let ep1 = join(&mgr, &fake, EP1, json!({ "vxcan.delay": 50 }))
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    host.send(&frame(0x100)).await.unwrap();
+    ep1.recv().await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    let faults = Faults {
+        reorder: 100.0,
+        ..Default::default()
+    };
+    mgr.endpoint_faults(NID.into(), EP1.into(), faults)
+        .await
+        .unwrap();
+    for id in [0x101, 0x102, 0x103] {
+        host.send(&frame(id)).await.unwrap();
+    }
+    // The last frame has none to swap places with and is sent on its own
+    assert_eq!(ids(&received(&ep1).await), [0x102, 0x101, 0x103]);
+}
+
+#[tokio::test]
+async fn faults_need_userspace_forwarding() {
+    let (mgr, fake) = manager();
+    network(&mgr, GatewayMode::Kernel).await;
+
+    let err = join(&mgr, &fake, EP1, json!({ "vxcan.delay": "10" })).await;
+    assert!(matches!(err, Err(Error::Unsupported(_))));
+    assert!(fake.rules().is_empty());
+    mgr.endpoint_delete(NID.into(), EP1.into()).await.unwrap();
+
+    join(&mgr, &fake, EP1, json!({})).await.unwrap();
+    let faults = Faults {
+        drop: 1.0,
+        ..Default::default()
+    };
+    let res = mgr.endpoint_faults(NID.into(), EP1.into(), faults).await;
+    assert!(matches!(res, Err(Error::Unsupported(_))));
+    let res = mgr
+        .endpoint_faults(NID.into(), EP1.into(), Faults::default())
+        .await;
+    assert_eq!(res, Ok(Faults::default()));
+}
+
+#[tokio::test]
+async fn faults_through_admin_api() {
+    let (mgr, fake) = manager();
+    let server = Server::start(admin::routes(mgr.clone(), None, Vec::new(), None));
+    let call = |method: &'static str, ep: &'static str, body: Value| {
+        let sock = server.sock.clone();
+        async move {
+            let body = if body.is_null() {
+                String::new()
+            } else {
+                body.to_string()
+            };
+            let path = format!("/networks/{NID}/endpoints/{ep}/faults");
+            let (status, body) = request(&sock, method, &path, body).await;
+            (
+                status.as_u16(),
+                serde_json::from_str::<Value>(&body).unwrap(),
+            )
+        }
+    };
+
+    network(&mgr, GatewayMode::Userspace).await;
+    let (status, _) = call("GET", EP1, Value::Null).await;
+    assert_eq!(status, 404);
+
+    let host = fake.open("vcan0").await.unwrap();
+    let ep1 = join(&mgr, &fake, EP1, json!({})).await.unwrap();
+    let (status, rsp) = call("GET", EP1, Value::Null).await;
+    assert_eq!((status, rsp["drop"].as_f64()), (200, Some(0.0)));
+
+    for bad in [json!({ "drop": 200 }), json!({ "loss": 5 })] {
+        let (status, rsp) = call("PUT", EP1, bad).await;
+        assert_eq!(status, 400, "{rsp}");
+    }
+    let (status, rsp) = call("PUT", EP1, json!({ "drop": 100 })).await;
+    assert_eq!(status, 200, "{rsp}");
+    assert_eq!(rsp["delay"], 0);
+
+    host.send(&frame(0x100)).await.unwrap();
+    assert!(received(&ep1).await.is_empty());
+
+    let (status, rsp) = call("DELETE", EP1, Value::Null).await;
+    assert_eq!((status, rsp["drop"].as_f64()), (200, Some(0.0)));
+    host.send(&frame(0x100)).await.unwrap();
+    assert_eq!(ids(&received(&ep1).await), [0x100]);
+}
@@ -0,0 +1,201 @@
+//! Frames sent and received through the admin socket, on the fake backend.
+
+mod common;
+
+use common::{request, Server};
+use hyper::body::HttpBody;
+use hyper::{Body, Request, StatusCode};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{FrameBackend, FrameSocket};
+use rustycan4docker::frame::Frame;
+use rustycan4docker::monitor::{FrameBatch, FrameFilter, FrameRecord};
+use rustycan4docker::{admin, Backend, NetworkManager, NetworkOptions};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UnixStream;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+
+async fn network() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    mgr.network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    (mgr, fake)
+}
+
+/// Join endpoint `ep` and open a socket on its container side.
+async fn join(mgr: &NetworkManager, fake: &FakeBackend, ep: &str) -> Arc<dyn FrameSocket> {
+    mgr.endpoint_create(NID.into(), ep.into()).await.unwrap();
+    let rsp = mgr
+        .endpoint_attach(NID.into(), ep.into(), String::new(), String::from("{}"))
+        .await
+        .unwrap();
+    fake.open(&rsp.SrcName).await.unwrap()
+}
+
+fn frame(s: &str) -> Frame {
+    Frame::parse_candump(s).unwrap()
+}
+
+/// A streaming `GET` of `path`, read line by line.
+struct Subscription {
+    body: Body,
+    buf: String,
+}
+
+impl Subscription {
+    async fn start(sock: &Path, path: &str) -> (StatusCode, Self) {
+        let stream = UnixStream::connect(sock).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .uri(path)
+            .header("Host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let rsp = sender.send_request(req).await.unwrap();
+        let status = rsp.status();
+        let sub = Subscription {
+            body: rsp.into_body(),
+            buf: String::new(),
+        };
+        (status, sub)
+    }
+
+    /// The next line of the stream, or `None` once it stays quiet.
+    async fn next(&mut self) -> Option<Value> {
+        loop {
+            if let Some((line, rest)) = self.buf.split_once('\n') {
+                let value = serde_json::from_str(line).unwrap();
+                self.buf = rest.to_string();
+                return Some(value);
+            }
+            let chunk = tokio::time::timeout(Duration::from_millis(200), self.body.data())
+                .await
+                .ok()??
+                .unwrap();
+            self.buf.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+}
+
+#[test]
+fn batches_and_filters() {
+    let one: FrameBatch = serde_json::from_value(json!("123#0102")).unwrap();
+    assert_eq!(one.parse().unwrap(), [frame("123#0102")]);
+    let many: FrameBatch = serde_json::from_value(json!(["123#01", "18FF0010##1AA"])).unwrap();
+    assert_eq!(
+        many.parse().unwrap(),
+        [frame("123#01"), frame("18FF0010##1AA")]
+    );
+    let bad: FrameBatch = serde_json::from_value(json!(["123#01", "800#01"])).unwrap();
+    assert!(bad.parse().is_err());
+
+    let all = FrameFilter::parse("").unwrap();
+    assert!(all.matches(&frame("123#01")));
+    assert!(all.matches(&frame("20000040#0000000000000000")));
+    let some = FrameFilter::parse("120:7F0,18FF0000:1FFF0000").unwrap();
+    assert!(some.matches(&frame("123#01")));
+    assert!(some.matches(&frame("18FF0010#01")));
+    assert!(!some.matches(&frame("133#01")));
+    assert!(!some.matches(&frame("20000040#0000000000000000")));
+    assert!(FrameFilter::parse("120:7F0,nonsense").is_err());
+}
+
+#[tokio::test]
+async fn send_to_containers() {
+    let (mgr, fake) = network().await;
+    let server = Server::start(admin::routes(mgr.clone(), None, Vec::new(), None));
+    let a = join(&mgr, &fake, "a").await;
+    let b = join(&mgr, &fake, "b").await;
+    let path = format!("/networks/{NID}/frames");
+
+    let body = json!(["123#01", "456#R", "18FF0010##1AABB"]).to_string();
+    let (status, rsp) = request(&server.sock, "POST", &path, body).await;
+    assert_eq!(status, 200, "{rsp}");
+    assert_eq!(
+        serde_json::from_str::<Value>(&rsp).unwrap(),
+        json!({ "sent": 3 })
+    );
+    for socket in [&a, &b] {
+        for expected in ["123#01", "456#R", "18FF0010##1AABB"] {
+            let (received, _) = socket.recv().await.unwrap();
+            assert_eq!(received, frame(expected));
+        }
+    }
+
+    let (status, _) = request(&server.sock, "POST", &path, json!("789#02").to_string()).await;
+    assert_eq!(status, 200);
+    for socket in [&a, &b] {
+        assert_eq!(socket.recv().await.unwrap().0, frame("789#02"));
+    }
+
+    // Nothing is sent from a batch with an invalid frame
+    for bad in [json!(["123#01", "123#0"]), json!({ "frame": "123#01" })] {
+        let (status, rsp) = request(&server.sock, "POST", &path, bad.to_string()).await;
+        assert_eq!(status, 400, "{rsp}");
+    }
+    let (status, _) = request(
+        &server.sock,
+        "POST",
+        "/networks/missing/frames",
+        json!("123#01").to_string(),
+    )
+    .await;
+    assert_eq!(status, 404);
+    assert!(tokio::time::timeout(Duration::from_millis(100), b.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn subscribe_with_filters() {
+    let (mgr, fake) = network().await;
+    let server = Server::start(admin::routes(mgr.clone(), None, Vec::new(), None));
+    let a = join(&mgr, &fake, "a").await;
+    let host = fake.open("vcan0").await.unwrap();
+
+    let path = format!("/networks/{NID}/frames?filter=120:7F0,18FF0000:1FFF0000");
+    let (status, mut filtered) = Subscription::start(&server.sock, &path).await;
+    assert_eq!(status, 200);
+    let (_, mut all) = Subscription::start(&server.sock, &format!("/networks/{NID}/frames")).await;
+
+    // From a container and from the host
+    a.send(&frame("123#01")).await.unwrap();
+    a.send(&frame("133#02")).await.unwrap();
+    host.send(&frame("18FF0010#03")).await.unwrap();
+    // And from the admin socket itself
+    let (status, _) = request(
+        &server.sock,
+        "POST",
+        &format!("/networks/{NID}/frames"),
+        json!("12F#04").to_string(),
+    )
+    .await;
+    assert_eq!(status, 200);
+
+    let mut frames = Vec::new();
+    while let Some(line) = filtered.next().await {
+        let record: FrameRecord = serde_json::from_value(line).unwrap();
+        assert!(record.timestamp > 1_600_000_000.0, "{record:?}");
+        frames.push(record.frame);
+    }
+    assert_eq!(frames, ["123#01", "18FF0010#03", "12F#04"]);
+    let mut frames = Vec::new();
+    while let Some(line) = all.next().await {
+        frames.push(line["frame"].as_str().unwrap().to_string());
+    }
+    assert_eq!(frames, ["123#01", "133#02", "18FF0010#03", "12F#04"]);
+
+    for (path, code) in [
+        (format!("/networks/{NID}/frames?filter=800:7FF"), 400),
+        (String::from("/networks/missing/frames"), 404),
+    ] {
+        let (status, _) = request(&server.sock, "GET", &path, String::new()).await;
+        assert_eq!(status, code, "{path}");
+    }
+}
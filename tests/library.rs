@@ -0,0 +1,144 @@
+//! Tests for using the topology engine directly, without the Docker plugin.
+
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::{Backend, EndpointInfo, Error, NetworkManager, NetworkOptions};
+use std::sync::Arc;
+
+const BUS: &str = "bus";
+const ECU1: &str = "ecu1-5c2b3f1b";
+const ECU2: &str = "ecu2-7e9a4d6c";
+
+fn manager() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    (mgr, fake)
+}
+
+fn rule(src: &str, dst: &str) -> (String, String) {
+    (src.to_string(), dst.to_string())
+}
+
+fn moved(netns: &str, ifname: &str) -> (String, String) {
+    (netns.to_string(), ifname.to_string())
+}
+
+#[tokio::test]
+async fn attach_and_detach_namespaces() {
+    let (mgr, fake) = manager();
+    let o = NetworkOptions {
+        device: "can".into(),
+        canid: 3,
+        ..Default::default()
+    };
+    assert_eq!(mgr.network_add(BUS.into(), o).await, Ok(()));
+    assert_eq!(mgr.networks(), [BUS]);
+
+    for (ep, netns) in [(ECU1, "/run/netns/a"), (ECU2, "/run/netns/b")] {
+        let res = mgr
+            .namespace_attach(BUS.into(), ep.into(), netns.into(), "can0".into())
+            .await;
+        assert_eq!(res, Ok(()));
+    }
+
+    // Both namespaces get their interface under the same name
+    let links: Vec<String> = fake.links().into_keys().collect();
+    assert_eq!(links, ["can3", "vxcanecu1-5c2", "vxcanecu2-7e9"]);
+    let ns: Vec<(String, String)> = fake.moved_links().into_keys().collect();
+    assert_eq!(
+        ns,
+        [moved("/run/netns/a", "can0"), moved("/run/netns/b", "can0")]
+    );
+
+    let info = mgr.network_inspect(BUS.into()).await.unwrap();
+    assert_eq!(info.id, BUS);
+    assert_eq!(info.interface, "can3");
+    assert_eq!(
+        info.endpoints,
+        [
+            EndpointInfo {
+                id: ECU1.into(),
+                device: "vxcanecu1-5c2".into(),
+                peer: "vxcanecu1-5c2p".into(),
+                namespace: Some("/run/netns/a".into()),
+                faults: None,
+                tap: false,
+                group: None,
+            },
+            EndpointInfo {
+                id: ECU2.into(),
+                device: "vxcanecu2-7e9".into(),
+                peer: "vxcanecu2-7e9p".into(),
+                namespace: Some("/run/netns/b".into()),
+                faults: None,
+                tap: false,
+                group: None,
+            },
+        ]
+    );
+    assert_eq!(info.rules.len(), 6);
+    assert_eq!(info.rules, fake.rules().into_iter().collect::<Vec<_>>());
+
+    assert_eq!(mgr.namespace_detach(BUS.into(), ECU1.into()).await, Ok(()));
+    assert_eq!(
+        fake.moved_links().into_keys().collect::<Vec<_>>(),
+        [moved("/run/netns/b", "can0")]
+    );
+    let info = mgr.network_inspect(BUS.into()).await.unwrap();
+    assert_eq!(info.endpoints.len(), 1);
+    assert_eq!(
+        info.rules,
+        [rule("can3", "vxcanecu2-7e9"), rule("vxcanecu2-7e9", "can3")]
+    );
+
+    assert_eq!(mgr.network_delete(BUS.into()).await, Ok(()));
+    assert!(fake.links().is_empty());
+    assert!(fake.moved_links().is_empty());
+    assert!(mgr.networks().is_empty());
+}
+
+#[tokio::test]
+async fn failed_attach_leaves_nothing_behind() {
+    let (mgr, fake) = manager();
+    assert_eq!(
+        mgr.network_add(BUS.into(), NetworkOptions::default()).await,
+        Ok(())
+    );
+
+    fake.fail("/run/netns/gone");
+    let res = mgr
+        .namespace_attach(
+            BUS.into(),
+            ECU1.into(),
+            "/run/netns/gone".into(),
+            "can0".into(),
+        )
+        .await;
+    assert!(matches!(res, Err(Error::Kernel { .. })));
+
+    let links: Vec<String> = fake.links().into_keys().collect();
+    assert_eq!(links, ["vcan0"]);
+    assert!(fake.rules().is_empty());
+    let info = mgr.network_inspect(BUS.into()).await.unwrap();
+    assert!(info.endpoints.is_empty());
+    assert!(info.rules.is_empty());
+}
+
+#[tokio::test]
+async fn unknown_networks() {
+    let (mgr, _) = manager();
+    assert_eq!(
+        mgr.network_inspect(BUS.into()).await,
+        Err(Error::NetworkNotFound(BUS.into()))
+    );
+    assert_eq!(
+        mgr.namespace_attach(
+            BUS.into(),
+            ECU1.into(),
+            "/run/netns/a".into(),
+            "can0".into()
+        )
+        .await,
+        Err(Error::NetworkNotFound(BUS.into()))
+    );
+    assert_eq!(mgr.namespace_detach(BUS.into(), ECU1.into()).await, Ok(()));
+}
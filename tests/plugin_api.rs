@@ -0,0 +1,725 @@
+//! End-to-end tests driving the plugin API over a unix socket, as Docker
+//! does, with the kernel replaced by a [`FakeBackend`].
+
+use hyper::{Body, Request, StatusCode};
+use rustycan4docker::backend::fake::{FakeBackend, FakeLink};
+use rustycan4docker::backend::{FrameBackend, RuleStats};
+use rustycan4docker::frame::Frame;
+use rustycan4docker::{api, Backend, NetworkManager};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::wrappers::UnixListenerStream;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+const EP1: &str = "0a1b2c3d4e5f60718293a4b5c6d7e8f9";
+const EP2: &str = "f9e8d7c6b5a4039281706f5e4d3c2b1a";
+const EP3: &str = "3c4d5e6f708192a3b4c5d6e7f8091a2b";
+const TAP: &str = "7a7b7c7d7e7f70717273747576777879";
+
+struct Plugin {
+    _dir: TempDir,
+    sock: PathBuf,
+    fake: Arc<FakeBackend>,
+    mgr: NetworkManager,
+}
+
+impl Plugin {
+    async fn start() -> Self {
+        Self::start_with(FakeBackend::new()).await
+    }
+
+    async fn start_with(fake: FakeBackend) -> Self {
+        let fake = Arc::new(fake);
+        let mgr =
+            NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let sock = dir.path().join("rustyvxcan.sock");
+        let incoming = UnixListenerStream::new(UnixListener::bind(&sock).unwrap());
+        tokio::spawn(
+            warp::serve(api::routes(mgr.clone(), api::Scope::Local)).run_incoming(incoming),
+        );
+
+        Plugin {
+            _dir: dir,
+            sock,
+            fake,
+            mgr,
+        }
+    }
+
+    async fn post_raw(&self, method: &str, body: String) -> (StatusCode, Value) {
+        let stream = UnixStream::connect(&self.sock).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::post(format!("/{method}"))
+            .header("Host", "localhost")
+            .header("Content-Type", "application/vnd.docker.plugins.v1.2+json")
+            .header("Content-Length", body.len())
+            .body(Body::from(body))
+            .unwrap();
+        let rsp = sender.send_request(req).await.unwrap();
+        let status = rsp.status();
+        let bytes = hyper::body::to_bytes(rsp.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    async fn post(&self, method: &str, body: Value) -> (StatusCode, Value) {
+        self.post_raw(method, body.to_string()).await
+    }
+
+    async fn create_network(&self, nid: &str, options: Value) -> Value {
+        let body = json!({
+            "NetworkID": nid,
+            "Options": {
+                "com.docker.network.enable_ipv6": false,
+                "com.docker.network.generic": options,
+            },
+            "IPv4Data": [],
+            "IPv6Data": [],
+        });
+        self.post("NetworkDriver.CreateNetwork", body).await.1
+    }
+
+    async fn create_endpoint(&self, nid: &str, epid: &str) -> Value {
+        let body = json!({
+            "NetworkID": nid,
+            "EndpointID": epid,
+            "Interface": {},
+            "Options": {},
+        });
+        self.post("NetworkDriver.CreateEndpoint", body).await.1
+    }
+
+    async fn join(&self, nid: &str, epid: &str) -> Value {
+        self.join_with(nid, epid, json!({})).await
+    }
+
+    async fn join_with(&self, nid: &str, epid: &str, options: Value) -> Value {
+        let body = json!({
+            "NetworkID": nid,
+            "EndpointID": epid,
+            "SandboxKey": format!("/var/run/docker/netns/{}", &epid[..12]),
+            "Options": options,
+        });
+        self.post("NetworkDriver.Join", body).await.1
+    }
+
+    async fn endpoint_info(&self, nid: &str, epid: &str) -> Value {
+        let body = json!({ "NetworkID": nid, "EndpointID": epid });
+        self.post("NetworkDriver.EndpointOperInfo", body).await.1
+    }
+
+    async fn leave(&self, nid: &str, epid: &str) -> Value {
+        let body = json!({ "NetworkID": nid, "EndpointID": epid });
+        self.post("NetworkDriver.Leave", body).await.1
+    }
+
+    async fn delete_endpoint(&self, nid: &str, epid: &str) -> Value {
+        let body = json!({ "NetworkID": nid, "EndpointID": epid });
+        self.post("NetworkDriver.DeleteEndpoint", body).await.1
+    }
+
+    async fn delete_network(&self, nid: &str) -> Value {
+        let body = json!({ "NetworkID": nid });
+        self.post("NetworkDriver.DeleteNetwork", body).await.1
+    }
+
+    fn link_names(&self) -> Vec<String> {
+        self.fake.links().into_keys().collect()
+    }
+
+    fn rules(&self) -> Vec<(String, String)> {
+        self.fake.rules().into_iter().collect()
+    }
+}
+
+fn rule(src: &str, dst: &str) -> (String, String) {
+    (src.to_string(), dst.to_string())
+}
+
+fn err(rsp: &Value) -> &str {
+    rsp["Err"].as_str().unwrap_or_default()
+}
+
+#[tokio::test]
+async fn handshake() {
+    let p = Plugin::start().await;
+
+    let (status, rsp) = p.post("Plugin.Activate", Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(rsp, json!({ "Implements": ["NetworkDriver"] }));
+
+    let (status, rsp) = p.post("NetworkDriver.GetCapabilities", Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(rsp["Scope"], "local");
+    assert_eq!(rsp["ConnectivityScope"], "local");
+}
+
+#[tokio::test]
+async fn container_lifecycle() {
+    let p = Plugin::start().await;
+    let opts = json!({ "vxcan.dev": "vcan", "vxcan.id": "0", "vxcan.peer": "vxcanp" });
+
+    assert_eq!(p.create_network(NID, opts).await, json!({}));
+    assert_eq!(p.link_names(), ["vcan0"]);
+    assert_eq!(p.fake.links()["vcan0"], FakeLink::Vcan);
+
+    // Docker creates and joins the endpoint of each container in turn
+    assert_eq!(p.create_endpoint(NID, EP1).await, json!({}));
+    assert_eq!(p.link_names(), ["vcan0", "vxcan0a1b2c3d", "vxcan0a1b2c3dp"]);
+    assert!(p.rules().is_empty());
+
+    let rsp = p.join(NID, EP1).await;
+    assert_eq!(
+        rsp,
+        json!({ "InterfaceName": { "SrcName": "vxcan0a1b2c3dp", "DstPrefix": "vxcanp" } })
+    );
+    assert_eq!(
+        p.rules(),
+        [
+            rule("vcan0", "vxcan0a1b2c3d"),
+            rule("vxcan0a1b2c3d", "vcan0")
+        ]
+    );
+
+    assert_eq!(p.create_endpoint(NID, EP2).await, json!({}));
+    assert_eq!(
+        p.link_names(),
+        [
+            "vcan0",
+            "vxcan0a1b2c3d",
+            "vxcan0a1b2c3dp",
+            "vxcanf9e8d7c6",
+            "vxcanf9e8d7c6p"
+        ]
+    );
+    p.join(NID, EP2).await;
+    assert_eq!(
+        p.rules(),
+        [
+            rule("vcan0", "vxcan0a1b2c3d"),
+            rule("vcan0", "vxcanf9e8d7c6"),
+            rule("vxcan0a1b2c3d", "vcan0"),
+            rule("vxcan0a1b2c3d", "vxcanf9e8d7c6"),
+            rule("vxcanf9e8d7c6", "vcan0"),
+            rule("vxcanf9e8d7c6", "vxcan0a1b2c3d"),
+        ]
+    );
+
+    assert_eq!(p.leave(NID, EP1).await, json!({}));
+    assert_eq!(
+        p.rules(),
+        [
+            rule("vcan0", "vxcanf9e8d7c6"),
+            rule("vxcanf9e8d7c6", "vcan0")
+        ]
+    );
+    p.leave(NID, EP2).await;
+    assert!(p.rules().is_empty());
+
+    assert_eq!(p.delete_endpoint(NID, EP1).await, json!({}));
+    assert_eq!(p.delete_endpoint(NID, EP2).await, json!({}));
+    assert_eq!(p.link_names(), ["vcan0"]);
+
+    assert_eq!(p.delete_network(NID).await, json!({}));
+    assert!(p.link_names().is_empty());
+}
+
+fn rule_stats(src: &str, dst: &str, fd: bool, handled: u64, dropped: u64) -> RuleStats {
+    RuleStats {
+        src: src.into(),
+        dst: dst.into(),
+        fd,
+        handled,
+        dropped,
+        deleted: 0,
+    }
+}
+
+#[tokio::test]
+async fn endpoint_traffic() {
+    let p = Plugin::start().await;
+    p.create_network(NID, Value::Null).await;
+    for ep in [EP1, EP2] {
+        p.create_endpoint(NID, ep).await;
+        p.join(NID, ep).await;
+    }
+
+    p.fake
+        .set_rule_stats(rule_stats("vxcan0a1b2c3d", "vcan0", false, 10, 1));
+    p.fake
+        .set_rule_stats(rule_stats("vxcan0a1b2c3d", "vxcanf9e8d7c6", false, 10, 0));
+    p.fake
+        .set_rule_stats(rule_stats("vcan0", "vxcan0a1b2c3d", false, 3, 0));
+    p.fake
+        .set_rule_stats(rule_stats("vxcanf9e8d7c6", "vxcan0a1b2c3d", false, 4, 2));
+
+    let info = p.endpoint_info(NID, EP1).await;
+    let stats = &info["Value"];
+    assert_eq!(stats["id"], EP1);
+    assert_eq!(stats["device"], "vxcan0a1b2c3d");
+    assert_eq!(
+        stats["sent"],
+        json!({ "handled": 20, "dropped": 1, "deleted": 0 })
+    );
+    assert_eq!(
+        stats["received"],
+        json!({ "handled": 7, "dropped": 2, "deleted": 0 })
+    );
+    assert_eq!(stats["routes"].as_array().unwrap().len(), 4);
+
+    let info = p.endpoint_info(NID, EP2).await;
+    assert_eq!(
+        info["Value"]["sent"],
+        json!({ "handled": 4, "dropped": 2, "deleted": 0 })
+    );
+
+    // Unreadable counters read as zero rather than failing the request
+    p.fake.fail("can-gw");
+    let info = p.endpoint_info(NID, EP1).await;
+    assert_eq!(
+        info["Value"]["sent"],
+        json!({ "handled": 0, "dropped": 0, "deleted": 0 })
+    );
+    p.fake.recover("can-gw");
+
+    assert_eq!(
+        err(&p.endpoint_info(NID, "0000").await),
+        "endpoint 0000 not found"
+    );
+    assert_eq!(
+        err(&p.endpoint_info("0000", EP1).await),
+        "network 0000 not found"
+    );
+}
+
+#[tokio::test]
+async fn tap_endpoints() {
+    let p = Plugin::start().await;
+    p.create_network(NID, Value::Null).await;
+    // Created before anything joins, so that EP1 meshes with the tap at first
+    for ep in [EP1, TAP, EP2] {
+        p.create_endpoint(NID, ep).await;
+    }
+    p.join(NID, EP1).await;
+    let rsp = p.join_with(NID, TAP, json!({ "vxcan.tap": "true" })).await;
+    assert_eq!(rsp["InterfaceName"]["SrcName"], "vxcan7a7b7c7dp");
+    p.join(NID, EP2).await;
+
+    let (ep1, tap, ep2) = ("vxcan0a1b2c3d", "vxcan7a7b7c7d", "vxcanf9e8d7c6");
+    let mut expected = vec![
+        rule("vcan0", ep1),
+        rule(ep1, "vcan0"),
+        rule("vcan0", tap),
+        rule("vcan0", ep2),
+        rule(ep2, "vcan0"),
+        rule(ep1, ep2),
+        rule(ep2, ep1),
+        rule(ep1, tap),
+        rule(ep2, tap),
+    ];
+    expected.sort();
+    assert_eq!(p.rules(), expected);
+
+    let info = p.mgr.network_inspect(NID.into()).await.unwrap();
+    let taps: Vec<bool> = info.endpoints.iter().map(|ep| ep.tap).collect();
+    assert_eq!(taps, [false, true, false]);
+
+    // The tap sees traffic between the others, and nothing it sends leaves
+    let s1 = p.fake.open(&format!("{ep1}p")).await.unwrap();
+    let st = p.fake.open(&format!("{tap}p")).await.unwrap();
+    let s2 = p.fake.open(&format!("{ep2}p")).await.unwrap();
+    let host = p.fake.open("vcan0").await.unwrap();
+    let frame = Frame::parse_candump("123#01").unwrap();
+    s1.send(&frame).await.unwrap();
+    assert_eq!(st.recv().await.unwrap().0, frame);
+    assert_eq!(s2.recv().await.unwrap().0, frame);
+    assert_eq!(host.recv().await.unwrap().0, frame);
+    st.send(&Frame::parse_candump("666#00").unwrap())
+        .await
+        .unwrap();
+    for socket in [&s1, &s2, &host] {
+        let quiet = tokio::time::timeout(Duration::from_millis(100), socket.recv()).await;
+        assert!(quiet.is_err());
+    }
+
+    // Only the tap's rules go when it leaves
+    p.leave(NID, TAP).await;
+    expected.retain(|(src, dst)| src != tap && dst != tap);
+    assert_eq!(p.rules(), expected);
+}
+
+#[tokio::test]
+async fn taps_see_every_group() {
+    let p = Plugin::start().await;
+    p.create_network(NID, Value::Null).await;
+    for ep in [EP1, TAP, EP2] {
+        p.create_endpoint(NID, ep).await;
+    }
+    p.join_with(NID, EP1, json!({ "vxcan.group": "ecu-a" }))
+        .await;
+    let options = json!({ "vxcan.group": "ecu-a", "vxcan.tap": "true" });
+    p.join_with(NID, TAP, options).await;
+    p.join_with(NID, EP2, json!({ "vxcan.group": "ecu-b" }))
+        .await;
+
+    let (ep1, tap, ep2) = ("vxcan0a1b2c3d", "vxcan7a7b7c7d", "vxcanf9e8d7c6");
+    let mut expected = vec![
+        rule("vcan0", ep1),
+        rule(ep1, "vcan0"),
+        rule("vcan0", tap),
+        rule("vcan0", ep2),
+        rule(ep2, "vcan0"),
+        rule(ep1, tap),
+        rule(ep2, tap),
+    ];
+    expected.sort();
+    assert_eq!(p.rules(), expected);
+
+    // The tap sees both groups, which do not see each other
+    let s1 = p.fake.open(&format!("{ep1}p")).await.unwrap();
+    let st = p.fake.open(&format!("{tap}p")).await.unwrap();
+    let s2 = p.fake.open(&format!("{ep2}p")).await.unwrap();
+    let frame = Frame::parse_candump("123#01").unwrap();
+    s1.send(&frame).await.unwrap();
+    s2.send(&frame).await.unwrap();
+    assert_eq!(st.recv().await.unwrap().0, frame);
+    assert_eq!(st.recv().await.unwrap().0, frame);
+    for socket in [&s1, &s2] {
+        let quiet = tokio::time::timeout(Duration::from_millis(100), socket.recv()).await;
+        assert!(quiet.is_err());
+    }
+}
+
+#[tokio::test]
+async fn endpoint_groups() {
+    let p = Plugin::start().await;
+    p.create_network(NID, Value::Null).await;
+    for ep in [EP1, EP2, EP3] {
+        p.create_endpoint(NID, ep).await;
+    }
+    for options in [
+        json!({ "vxcan.group": "ecu a" }),
+        json!("{\"vxcan.group\": \"ecu-a"),
+        json!(["vxcan.group", "ecu-a"]),
+    ] {
+        let rsp = p.join_with(NID, EP1, options).await;
+        assert!(err(&rsp).starts_with("invalid option"), "{rsp}");
+    }
+
+    // A join that fails leaves the endpoint as it was
+    p.fake.fail("vxcan0a1b2c3d");
+    let options = json!({ "vxcan.group": "ecu-a", "vxcan.tap": "true" });
+    let rsp = p.join_with(NID, EP1, options).await;
+    assert!(err(&rsp).contains("injected failure"), "{rsp}");
+    p.fake.recover("vxcan0a1b2c3d");
+    let info = p.mgr.network_inspect(NID.into()).await.unwrap();
+    assert_eq!(
+        (info.endpoints[0].tap, &info.endpoints[0].group),
+        (false, &None)
+    );
+
+    // The endpoint without a group joins first and meshes with the others,
+    // until they join groups of their own
+    let (ep1, ep2, ep3) = ("vxcan0a1b2c3d", "vxcanf9e8d7c6", "vxcan3c4d5e6f");
+    p.join(NID, EP3).await;
+    p.join_with(NID, EP1, json!({ "vxcan.group": "ecu-a" }))
+        .await;
+    p.join_with(NID, EP2, json!({ "vxcan.group": "ecu-a" }))
+        .await;
+    let mut expected = vec![
+        rule("vcan0", ep1),
+        rule(ep1, "vcan0"),
+        rule("vcan0", ep2),
+        rule(ep2, "vcan0"),
+        rule("vcan0", ep3),
+        rule(ep3, "vcan0"),
+        rule(ep1, ep2),
+        rule(ep2, ep1),
+    ];
+    expected.sort();
+    assert_eq!(p.rules(), expected);
+
+    // A rejoin that fails halfway leaves the rules as they were
+    p.fake.fail(ep3);
+    let rsp = p.join(NID, EP1).await;
+    assert!(err(&rsp).contains("injected failure"), "{rsp}");
+    p.fake.recover(ep3);
+    assert_eq!(p.rules(), expected);
+
+    let info = p.mgr.network_inspect(NID.into()).await.unwrap();
+    let groups: Vec<Option<&str>> = info
+        .endpoints
+        .iter()
+        .map(|ep| ep.group.as_deref())
+        .collect();
+    assert_eq!(groups, [Some("ecu-a"), None, Some("ecu-a")]);
+
+    // Every group still reaches the host device, and the host all of them
+    let s1 = p.fake.open(&format!("{ep1}p")).await.unwrap();
+    let s2 = p.fake.open(&format!("{ep2}p")).await.unwrap();
+    let s3 = p.fake.open(&format!("{ep3}p")).await.unwrap();
+    let host = p.fake.open("vcan0").await.unwrap();
+    let frame = Frame::parse_candump("123#01").unwrap();
+    s1.send(&frame).await.unwrap();
+    assert_eq!(s2.recv().await.unwrap().0, frame);
+    assert_eq!(host.recv().await.unwrap().0, frame);
+    s3.send(&frame).await.unwrap();
+    assert_eq!(host.recv().await.unwrap().0, frame);
+    host.send(&frame).await.unwrap();
+    for socket in [&s1, &s2, &s3] {
+        assert_eq!(socket.recv().await.unwrap().0, frame);
+    }
+    for socket in [&s1, &s2, &s3] {
+        let quiet = tokio::time::timeout(Duration::from_millis(100), socket.recv()).await;
+        assert!(quiet.is_err());
+    }
+}
+
+#[tokio::test]
+async fn default_options() {
+    let p = Plugin::start().await;
+
+    assert_eq!(p.create_network(NID, Value::Null).await, json!({}));
+    p.create_endpoint(NID, EP1).await;
+    let rsp = p.join(NID, EP1).await;
+    assert_eq!(rsp["InterfaceName"]["DstPrefix"], "vcanp");
+    assert_eq!(p.link_names()[0], "vcan0");
+}
+
+#[tokio::test]
+async fn invalid_options_are_rejected() {
+    let p = Plugin::start().await;
+
+    for opts in [
+        json!({ "vxcan.bogus": "1" }),
+        json!({ "vxcan.id": "x" }),
+        json!({ "vxcan.id": "10000" }),
+        json!({ "vxcan.dev": "has space" }),
+        json!({ "vxcan.dev": "averylongname", "vxcan.id": "420" }),
+        json!({ "vxcan.shared": "yes" }),
+        json!({ "vxcan.gateway": "bridge" }),
+        json!({ "vxcan.topology": "star" }),
+        json!({ "vxcan.topology": "hub", "vxcan.gateway": "kernel" }),
+        json!({ "vxcan.emulate-bitrate": "500000", "vxcan.gateway": "kernel" }),
+        json!({ "vxcan.emulate-bitrate": "2000000" }),
+        json!({ "vxcan.emulate-data-bitrate": "2000000" }),
+        json!({ "vxcan.emulate-bitrate": "500000", "vxcan.emulate-data-bitrate": "250000" }),
+        json!({ "vxcan.tunnel-peers": "192.0.2.2:20000" }),
+        json!({ "vxcan.tunnel": "board1:20000", "vxcan.tunnel-peers": "192.0.2.2:20000" }),
+        json!({ "vxcan.tunnel": "0.0.0.0:20000", "vxcan.tunnel-peers": "192.0.2.2" }),
+        json!({ "vxcan.tunnel": "0.0.0.0:20000", "vxcan.tunnel-peers": "192.0.2.2:20000", "vxcan.tunnel-batch": "5000" }),
+        json!({ "vxcan.record": "relative/dir" }),
+        json!({ "vxcan.record": "/tmp/can", "vxcan.record-format": "blf" }),
+        json!({ "vxcan.record-size": "1" }),
+    ] {
+        let rsp = p.create_network(NID, opts.clone()).await;
+        assert!(err(&rsp).starts_with("invalid option"), "{opts}: {rsp}");
+    }
+    assert!(p.link_names().is_empty());
+
+    // The network was never stored, so endpoints cannot be created on it
+    let rsp = p.create_endpoint(NID, EP1).await;
+    assert_eq!(err(&rsp), format!("network {NID} not found"));
+    assert!(p.link_names().is_empty());
+}
+
+#[tokio::test]
+async fn deprecated_options_are_accepted() {
+    let p = Plugin::start().await;
+
+    let opts = json!({ "vxcan.device": "can", "vxcan.canid": "3" });
+    assert_eq!(p.create_network(NID, opts).await, json!({}));
+    assert_eq!(p.link_names(), ["can3"]);
+}
+
+#[tokio::test]
+async fn conflicting_options_are_rejected() {
+    let p = Plugin::start().await;
+
+    for (opts, pair) in [
+        (
+            json!({ "vxcan.dev": "can", "vxcan.device": "can" }),
+            "'vxcan.device' and 'vxcan.dev'",
+        ),
+        (
+            json!({ "vxcan.id": "3", "vxcan.canid": "3" }),
+            "'vxcan.canid' and 'vxcan.id'",
+        ),
+        (
+            json!({ "vxcan.topology": "hub", "vxcan.gateway": "kernel" }),
+            "'vxcan.topology=hub' and 'vxcan.gateway=kernel'",
+        ),
+        (
+            json!({ "vxcan.emulate-bitrate": 500000, "vxcan.gateway": "kernel" }),
+            "'vxcan.emulate-bitrate' and 'vxcan.gateway=kernel'",
+        ),
+    ] {
+        let rsp = p.create_network(NID, opts.clone()).await;
+        assert!(err(&rsp).contains(pair), "{opts}: {rsp}");
+        assert!(err(&rsp).contains("mutually exclusive"), "{opts}: {rsp}");
+    }
+    assert!(p.link_names().is_empty());
+
+    // Only the listed values conflict
+    let opts = json!({ "vxcan.topology": "mesh", "vxcan.gateway": "kernel" });
+    assert_eq!(p.create_network(NID, opts).await, json!({}));
+}
+
+#[tokio::test]
+async fn shared_devices() {
+    let p = Plugin::start().await;
+    let other = "aaaabbbbccccddddeeeeffff00001111";
+
+    p.create_network(NID, json!({ "vxcan.id": "1" })).await;
+    let rsp = p.create_network(other, json!({ "vxcan.id": "1" })).await;
+    assert!(err(&rsp).contains("already used by network"), "{rsp}");
+
+    // Both sides have to agree to share the device
+    let rsp = p
+        .create_network(other, json!({ "vxcan.id": "1", "vxcan.shared": "true" }))
+        .await;
+    assert!(err(&rsp).contains("already used by network"), "{rsp}");
+
+    p.delete_network(NID).await;
+    let shared = json!({ "vxcan.id": "1", "vxcan.shared": "true", "vxcan.gateway": "kernel" });
+    assert_eq!(p.create_network(NID, shared.clone()).await, json!({}));
+    assert_eq!(p.create_network(other, shared.clone()).await, json!({}));
+    // A repeated create is answered without counting another user or
+    // probing the gateway again, but only if it asks for the same network
+    p.fake.fail("can-gw");
+    assert_eq!(p.create_network(other, shared).await, json!({}));
+    p.fake.recover("can-gw");
+    let rsp = p.create_network(other, json!({ "vxcan.id": "1" })).await;
+    assert!(
+        err(&rsp).contains("already exists with other options"),
+        "{rsp}"
+    );
+
+    // The device survives until its last user goes away
+    p.delete_network(NID).await;
+    assert_eq!(p.link_names(), ["vcan1"]);
+    p.delete_network(other).await;
+    assert!(p.link_names().is_empty());
+}
+
+#[tokio::test]
+async fn existing_devices_are_kept() {
+    let fake = FakeBackend::new();
+    fake.link_insert("can0");
+    let p = Plugin::start_with(fake).await;
+
+    p.create_network(NID, json!({ "vxcan.dev": "can" })).await;
+    p.create_endpoint(NID, EP1).await;
+    p.join(NID, EP1).await;
+    assert_eq!(p.rules().len(), 2);
+
+    p.leave(NID, EP1).await;
+    p.delete_endpoint(NID, EP1).await;
+    p.delete_network(NID).await;
+    assert_eq!(p.link_names(), ["can0"]);
+    assert_eq!(p.fake.links()["can0"], FakeLink::Existing);
+}
+
+#[tokio::test]
+async fn out_of_order_requests() {
+    let p = Plugin::start().await;
+
+    // Nothing exists yet
+    let rsp = p.join(NID, EP1).await;
+    assert_eq!(err(&rsp), format!("network {NID} not found"));
+    assert_eq!(p.leave(NID, EP1).await, json!({}));
+    assert_eq!(p.delete_endpoint(NID, EP1).await, json!({}));
+    assert_eq!(p.delete_network(NID).await, json!({}));
+
+    // Join before the endpoint was created
+    p.create_network(NID, Value::Null).await;
+    let rsp = p.join(NID, EP1).await;
+    assert_eq!(err(&rsp), format!("endpoint {EP1} not found"));
+    assert!(p.rules().is_empty());
+
+    // Joining twice does not duplicate rules, leaving twice is harmless
+    p.create_endpoint(NID, EP1).await;
+    p.join(NID, EP1).await;
+    p.join(NID, EP1).await;
+    assert_eq!(p.rules().len(), 2);
+    p.leave(NID, EP1).await;
+    assert_eq!(p.leave(NID, EP1).await, json!({}));
+    assert!(p.rules().is_empty());
+
+    // Deleting the network cleans up endpoints Docker forgot about
+    p.create_endpoint(NID, EP2).await;
+    p.join(NID, EP2).await;
+    assert_eq!(p.delete_network(NID).await, json!({}));
+    assert!(p.rules().is_empty());
+    assert!(p.link_names().is_empty());
+}
+
+#[tokio::test]
+async fn kernel_failures_are_reported() {
+    let p = Plugin::start().await;
+
+    p.fake.fail("vcan0");
+    let rsp = p.create_network(NID, Value::Null).await;
+    assert!(err(&rsp).contains("injected failure on vcan0"), "{rsp}");
+
+    // A failed create leaves nothing behind and can be retried
+    p.fake.recover("vcan0");
+    assert_eq!(p.create_network(NID, Value::Null).await, json!({}));
+    p.create_endpoint(NID, EP1).await;
+
+    p.fake.fail("vxcan0a1b2c3d");
+    let rsp = p.join(NID, EP1).await;
+    assert!(err(&rsp).contains("injected failure"), "{rsp}");
+    let rsp = p.delete_endpoint(NID, EP1).await;
+    assert!(err(&rsp).contains("injected failure"), "{rsp}");
+
+    p.fake.recover("vxcan0a1b2c3d");
+    assert_eq!(p.delete_network(NID).await, json!({}));
+
+    // An endpoint that cannot be removed does not keep the others
+    p.create_network(NID, Value::Null).await;
+    for ep in [EP1, EP2] {
+        p.create_endpoint(NID, ep).await;
+        p.join(NID, ep).await;
+    }
+    p.fake.fail("vxcan0a1b2c3d");
+    let rsp = p.delete_network(NID).await;
+    assert!(err(&rsp).contains("injected failure"), "{rsp}");
+    assert_eq!(p.link_names(), ["vcan0", "vxcan0a1b2c3d", "vxcan0a1b2c3dp"]);
+
+    // The network is kept until it is gone completely
+    p.fake.recover("vxcan0a1b2c3d");
+    let rsp = p.create_endpoint(NID, EP2).await;
+    assert!(!err(&rsp).contains("not found"), "{rsp}");
+    assert_eq!(p.delete_network(NID).await, json!({}));
+    assert!(p.link_names().is_empty());
+}
+
+#[tokio::test]
+async fn malformed_requests() {
+    let p = Plugin::start().await;
+
+    let (_, rsp) = p
+        .post_raw("NetworkDriver.CreateNetwork", String::from("{not json"))
+        .await;
+    assert_eq!(err(&rsp), "Unable to parse JSON payload");
+
+    let (status, rsp) = p
+        .post("NetworkDriver.CreateNetwork", json!({ "NetworkID": 7 }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(err(&rsp), "Invalid network ID");
+
+    let (status, rsp) = p
+        .post("NetworkDriver.Join", json!({ "NetworkID": NID }))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(err(&rsp), "Invalid network ID, endpoint ID, or sandbox key");
+}
@@ -0,0 +1,262 @@
+//! Tests for recording a network's traffic to log files.
+
+mod common;
+
+use common::{request, Server};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::FrameBackend;
+use rustycan4docker::frame::{self, Frame, EFF_FLAG, ERR_FLAG, FD_BRS, RTR_FLAG};
+use rustycan4docker::recorder::RecordConfig;
+use rustycan4docker::{admin, Backend, NetworkManager, NetworkOptions};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+const EP1: &str = "0a1b2c3d4e5f60718293a4b5c6d7e8f9";
+
+fn manager() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    (mgr, fake)
+}
+
+fn classic(id: u32, data: &[u8]) -> Frame {
+    Frame {
+        id,
+        data: data.to_vec(),
+        fd: false,
+        flags: 0,
+    }
+}
+
+/// Wait until the network's recording has seen `frames` frames.
+async fn recorded(mgr: &NetworkManager, frames: u64) {
+    for _ in 0..200 {
+        let info = mgr.network_inspect(NID.into()).await.unwrap();
+        if info.recording.unwrap().frames >= frames {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{frames} frames were not recorded");
+}
+
+/// Contents of the files in `dir`, ordered by name.
+fn files(dir: &Path) -> Vec<String> {
+    let mut names: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    names.sort();
+    names
+        .iter()
+        .map(|n| std::fs::read_to_string(n).unwrap())
+        .collect()
+}
+
+#[test]
+fn candump_format() {
+    let frames = [
+        (classic(0x123, &[0xde, 0xad, 0xbe, 0xef]), "123#DEADBEEF"),
+        (classic(0x12345678 | EFF_FLAG, &[]), "12345678#"),
+        (classic(0x7ff | RTR_FLAG, &[0; 8]), "7FF#R8"),
+        (
+            classic(ERR_FLAG | 0x04, &[0, 0x10, 0, 0, 0, 0, 0, 0]),
+            "20000004#0010000000000000",
+        ),
+        (
+            Frame {
+                id: 0x42,
+                data: vec![0x55; 12],
+                fd: true,
+                flags: FD_BRS,
+            },
+            "042##1555555555555555555555555",
+        ),
+    ];
+    for (frame, text) in frames {
+        assert_eq!(frame.to_candump(), text);
+        assert_eq!(Frame::parse_candump(text).unwrap(), frame);
+    }
+
+    assert_eq!(
+        Frame::parse_candump("123#11.22.33").unwrap(),
+        classic(0x123, &[0x11, 0x22, 0x33])
+    );
+    for bad in [
+        "123",
+        "1234#00",
+        "800#00",
+        "123#0",
+        "123#001122334455667788",
+        "123##0001",
+    ] {
+        assert!(Frame::parse_candump(bad).is_err(), "{bad}");
+    }
+
+    let (time, ifc, frame) =
+        frame::parse_candump_line("(1666113315.012345) vcan0 123#01 R").unwrap();
+    assert_eq!(time, Duration::new(1666113315, 12_345_000));
+    assert_eq!(ifc, "vcan0");
+    assert_eq!(frame, classic(0x123, &[1]));
+    assert!(frame::parse_candump_line("vcan0 123#01").is_err());
+}
+
+#[tokio::test]
+async fn record_from_network_option() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mgr, fake) = manager();
+    let options = NetworkOptions {
+        record: Some(RecordConfig::new(dir.path().to_path_buf())),
+        ..Default::default()
+    };
+    mgr.network_add(NID.into(), options).await.unwrap();
+    mgr.endpoint_create(NID.into(), EP1.into()).await.unwrap();
+    let join = mgr
+        .endpoint_attach(NID.into(), EP1.into(), String::new(), String::new())
+        .await
+        .unwrap();
+
+    // Frames the container sends reach vcan0 through the gateway rules
+    let container = fake.open(&join.SrcName).await.unwrap();
+    let sent = [
+        classic(0x123, &[0xde, 0xad, 0xbe, 0xef]),
+        classic(0x1abcdef0 | EFF_FLAG, &[1, 2]),
+        Frame {
+            id: 0x42,
+            data: vec![0xaa; 16],
+            fd: true,
+            flags: FD_BRS,
+        },
+    ];
+    for frame in &sent {
+        container.send(frame).await.unwrap();
+    }
+    recorded(&mgr, 3).await;
+
+    // Deleting the network ends the recording and completes the file
+    mgr.endpoint_detach(NID.into(), EP1.into()).await.unwrap();
+    mgr.network_delete(NID.into()).await.unwrap();
+
+    let logs = files(dir.path());
+    assert_eq!(logs.len(), 1);
+    let lines: Vec<_> = logs[0].lines().collect();
+    assert_eq!(lines.len(), 3);
+    for (line, frame) in lines.iter().zip(&sent) {
+        let (_, ifc, logged) = frame::parse_candump_line(line).unwrap();
+        assert_eq!(ifc, "vcan0");
+        assert_eq!(&logged, frame);
+    }
+}
+
+#[tokio::test]
+async fn restarted_recordings_keep_earlier_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mgr, fake) = manager();
+    mgr.network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    let host = fake.open("vcan0").await.unwrap();
+
+    // Within the same second, so both files start with the same name
+    for data in [1, 2] {
+        let config = RecordConfig::new(dir.path().to_path_buf());
+        mgr.record_start(NID.into(), config).await.unwrap();
+        host.send(&classic(0x123, &[data])).await.unwrap();
+        recorded(&mgr, 1).await;
+        mgr.record_stop(NID.into()).await.unwrap();
+    }
+
+    let logged: Vec<Frame> = files(dir.path())
+        .iter()
+        .map(|log| frame::parse_candump_line(log.trim()).unwrap().2)
+        .collect();
+    assert_eq!(logged.len(), 2);
+    assert!(logged.contains(&classic(0x123, &[1])));
+    assert!(logged.contains(&classic(0x123, &[2])));
+}
+
+#[tokio::test]
+async fn record_through_admin_api() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mgr, fake) = manager();
+    let server = Server::start(admin::routes(mgr.clone(), None, Vec::new(), None));
+    let call = |method: &'static str, body: Value| {
+        let sock = server.sock.clone();
+        async move {
+            let body = if body.is_null() {
+                String::new()
+            } else {
+                body.to_string()
+            };
+            let path = format!("/networks/{NID}/record");
+            let (status, body) = request(&sock, method, &path, body).await;
+            (
+                status.as_u16(),
+                serde_json::from_str::<Value>(&body).unwrap(),
+            )
+        }
+    };
+
+    let config = json!({
+        "path": dir.path(),
+        "format": "asc",
+        "max_size": 1000,
+        "max_files": 2,
+    });
+    let (status, _) = call("POST", config.clone()).await;
+    assert_eq!(status, 404);
+
+    mgr.network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    let (status, rsp) = call("GET", Value::Null).await;
+    assert_eq!((status, rsp), (200, Value::Null));
+
+    let (status, rsp) = call("POST", json!({ "path": "relative" })).await;
+    assert_eq!(status, 400, "{rsp}");
+    // Bodies are limited in size, like those of the plugin API
+    let huge = json!({ "path": dir.path(), "padding": "x".repeat(20 * 1024) });
+    let path = format!("/networks/{NID}/record");
+    let (status, _) = request(&server.sock, "POST", &path, huge.to_string()).await;
+    assert_eq!(status.as_u16(), 413);
+    let (status, rsp) = call("POST", config.clone()).await;
+    assert_eq!(status, 200, "{rsp}");
+    assert_eq!(rsp["format"], "asc");
+    assert_eq!(rsp["frames"], 0);
+    let (status, rsp) = call("POST", config).await;
+    assert_eq!(status, 409, "{rsp}");
+
+    // A host application sending on vcan0 is recorded as well
+    let host = fake.open("vcan0").await.unwrap();
+    for i in 0..20u8 {
+        host.send(&classic(0x100 + u32::from(i), &[i; 8]))
+            .await
+            .unwrap();
+    }
+    recorded(&mgr, 20).await;
+
+    let (status, rsp) = call("DELETE", Value::Null).await;
+    assert_eq!(status, 200);
+    assert_eq!(rsp["frames"], 20);
+    assert_eq!(rsp["error"], Value::Null);
+    let (_, rsp) = call("GET", Value::Null).await;
+    assert_eq!(rsp, Value::Null);
+
+    // Only the two newest files are kept, each a complete ASC file
+    let logs = files(dir.path());
+    assert_eq!(logs.len(), 2);
+    for log in &logs {
+        let lines: Vec<_> = log.lines().collect();
+        assert!(lines[0].starts_with("date "), "{log}");
+        assert_eq!(lines[1], "base hex  timestamps absolute");
+        assert_eq!(*lines.last().unwrap(), "End TriggerBlock");
+    }
+    let last = logs[1].lines().rev().nth(1).unwrap();
+    assert!(
+        last.ends_with("1  113             Rx   d 8 13 13 13 13 13 13 13 13"),
+        "{last}"
+    );
+}
@@ -0,0 +1,240 @@
+//! Tests for replaying recorded traffic onto a network.
+
+mod common;
+
+use common::{request, Server};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{FrameBackend, FrameSocket};
+use rustycan4docker::frame::Frame;
+use rustycan4docker::replay::{IdFilter, ReplayConfig};
+use rustycan4docker::{admin, Backend, NetworkManager, NetworkOptions};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+use tokio::time::Instant;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+
+/// Two vcan0 frames 100 ms apart, one can1 frame between them and one
+/// extended vcan0 frame another 100 ms later.
+const LOG: &str = "\
+(1666113315.000000) vcan0 123#01
+(1666113315.050000) can1 124#02
+(1666113315.100000) vcan0 7FF#03
+
+(1666113315.200000) vcan0 18FF0010#04
+";
+
+async fn network() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    mgr.network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    (mgr, fake)
+}
+
+fn log_file(contents: &str) -> NamedTempFile {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(contents.as_bytes()).unwrap();
+    file
+}
+
+/// The identifier and first data byte of each of the next `n` frames.
+async fn receive(socket: &Arc<dyn FrameSocket>, n: usize) -> Vec<(String, u8)> {
+    let mut frames = Vec::new();
+    for _ in 0..n {
+        let (frame, _) = tokio::time::timeout(Duration::from_secs(5), socket.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        frames.push((frame.to_candump(), frame.data[0]));
+    }
+    frames
+}
+
+async fn finished(mgr: &NetworkManager) -> Value {
+    for _ in 0..500 {
+        let info = mgr.network_inspect(NID.into()).await.unwrap();
+        let status = info.replay.unwrap();
+        if !status.running {
+            return serde_json::to_value(status).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("replay did not finish");
+}
+
+#[test]
+fn id_filters() {
+    let frame = |id| Frame {
+        id,
+        data: Vec::new(),
+        fd: false,
+        flags: 0,
+    };
+    let f = IdFilter::parse("120:7F0").unwrap();
+    assert!(f.matches(&frame(0x123)));
+    assert!(!f.matches(&frame(0x133)));
+    assert!(!f.matches(&frame(0x123 | rustycan4docker::frame::EFF_FLAG)));
+    assert_eq!(f.to_string(), "120:7F0");
+
+    let f = IdFilter::parse("18FF0000~1FFF0000").unwrap();
+    assert!(!f.matches(&frame(0x18ff0010 | rustycan4docker::frame::EFF_FLAG)));
+    assert!(f.matches(&frame(0x18fe0010 | rustycan4docker::frame::EFF_FLAG)));
+    assert!(f.matches(&frame(0x123)));
+
+    for bad in ["123", "123:", "1234:7FF", "800:7FF", "123:FFF", "12G:7FF"] {
+        assert!(IdFilter::parse(bad).is_err(), "{bad}");
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn original_and_scaled_timing() {
+    let (mgr, fake) = network().await;
+    let listener = fake.open("vcan0").await.unwrap();
+    let log = log_file(LOG);
+
+    for (speed, gap) in [(1.0, 100), (4.0, 25)] {
+        let config = ReplayConfig {
+            speed,
+            interface: Some("vcan0".into()),
+            ..ReplayConfig::new(log.path().to_path_buf())
+        };
+        mgr.replay_start(NID.into(), config).await.unwrap();
+
+        let mut times = Vec::new();
+        for _ in 0..3 {
+            listener.recv().await.unwrap();
+            times.push(Instant::now());
+        }
+        let gaps: Vec<u128> = times
+            .windows(2)
+            .map(|w| (w[1] - w[0]).as_millis())
+            .collect();
+        assert_eq!(gaps, [gap, gap]);
+
+        let status = finished(&mgr).await;
+        assert_eq!(status["frames"], 3);
+        assert_eq!(status["skipped"], 1);
+    }
+}
+
+#[tokio::test]
+async fn replay_through_admin_api() {
+    let (mgr, fake) = network().await;
+    let server = Server::start(admin::routes(mgr.clone(), None, Vec::new(), None));
+    let call = |method: &'static str, body: Value| {
+        let sock = server.sock.clone();
+        async move {
+            let body = if body.is_null() {
+                String::new()
+            } else {
+                body.to_string()
+            };
+            let path = format!("/networks/{NID}/replay");
+            let (status, body) = request(&sock, method, &path, body).await;
+            (
+                status.as_u16(),
+                serde_json::from_str::<Value>(&body).unwrap(),
+            )
+        }
+    };
+    let listener = fake.open("vcan0").await.unwrap();
+    let log = log_file(LOG);
+
+    for bad in [
+        json!({ "file": "relative.log" }),
+        json!({ "file": log.path(), "speed": -1 }),
+        json!({ "file": log.path(), "speed": 1e-300 }),
+        json!({ "file": log.path(), "filters": ["nonsense"] }),
+    ] {
+        let (status, rsp) = call("POST", bad).await;
+        assert_eq!(status, 400, "{rsp}");
+    }
+    let (status, _) = call("POST", json!({ "file": "/nonexistent.log" })).await;
+    assert_eq!(status, 500);
+
+    // As fast as possible, twice, only frames with identifiers 120-12F
+    let (status, rsp) = call(
+        "POST",
+        json!({ "file": log.path(), "speed": 0, "loops": 2, "filters": ["120:7F0"] }),
+    )
+    .await;
+    assert_eq!(status, 200, "{rsp}");
+    assert_eq!(rsp["filters"], json!(["120:7F0"]));
+    assert_eq!(
+        receive(&listener, 4).await,
+        [
+            ("123#01".to_string(), 1),
+            ("124#02".to_string(), 2),
+            ("123#01".to_string(), 1),
+            ("124#02".to_string(), 2),
+        ]
+    );
+    let status = finished(&mgr).await;
+    assert_eq!(status["loop"], 2);
+    assert_eq!(status["frames"], 4);
+    assert_eq!(status["skipped"], 4);
+    assert_eq!(status["error"], Value::Null);
+
+    // Looping until stopped
+    let (status, _) = call("POST", json!({ "file": log.path(), "loops": 0 })).await;
+    assert_eq!(status, 200);
+    let (status, _) = call("POST", json!({ "file": log.path() })).await;
+    assert_eq!(status, 409);
+    receive(&listener, 5).await;
+    let (status, rsp) = call("DELETE", Value::Null).await;
+    assert_eq!(status, 200);
+    assert_eq!(rsp["running"], false);
+    assert!(rsp["loop"].as_u64().unwrap() >= 2, "{rsp}");
+    let (_, rsp) = call("GET", Value::Null).await;
+    assert_eq!(rsp, Value::Null);
+
+    // Malformed logs stop the replay with the offending line
+    let bad = log_file("(1666113315.000000) vcan0 123#01\nnot a frame\n");
+    let (status, _) = call("POST", json!({ "file": bad.path(), "speed": 0 })).await;
+    assert_eq!(status, 200);
+    let status = finished(&mgr).await;
+    assert_eq!(status["frames"], 1);
+    let error = status["error"].as_str().unwrap();
+    assert!(error.contains(":2: "), "{error}");
+
+    // So do frames too far apart to wait for at the slowest speed
+    let far = log_file("(0.000000) vcan0 123#01\n(9000000000000000.000000) vcan0 123#02\n");
+    let (status, _) = call("POST", json!({ "file": far.path(), "speed": 0.001 })).await;
+    assert_eq!(status, 200);
+    let status = finished(&mgr).await;
+    assert_eq!(status["frames"], 1);
+    let error = status["error"].as_str().unwrap();
+    assert!(error.contains(":2: "), "{error}");
+}
+
+#[tokio::test]
+async fn nothing_to_replay() {
+    let (mgr, _fake) = network().await;
+
+    // Looping over a file without frames to send stops instead of spinning
+    let empty = log_file("# nothing recorded\n\n");
+    let filtered = log_file(LOG);
+    for config in [
+        ReplayConfig {
+            loops: 0,
+            ..ReplayConfig::new(empty.path().to_path_buf())
+        },
+        ReplayConfig {
+            loops: 0,
+            interface: Some("can9".into()),
+            ..ReplayConfig::new(filtered.path().to_path_buf())
+        },
+    ] {
+        mgr.replay_start(NID.into(), config).await.unwrap();
+        let status = finished(&mgr).await;
+        assert_eq!(status["frames"], 0);
+        assert_eq!(status["loop"], 1);
+        let error = status["error"].as_str().unwrap();
+        assert!(error.ends_with(": no frames to replay"), "{error}");
+    }
+}
@@ -0,0 +1,298 @@
+//! Tunnels between networks over UDP on loopback, on the fake backend.
+
+mod common;
+
+use common::{request, Server};
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{FrameBackend, FrameSocket};
+use rustycan4docker::frame::Frame;
+use rustycan4docker::tunnel::{self, TunnelStatus};
+use rustycan4docker::{api, Backend, NetworkManager, NetworkOptions};
+use serde_json::{json, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+
+fn frame(s: &str) -> Frame {
+    Frame::parse_candump(s).unwrap()
+}
+
+/// A loopback address with a UDP port that was free a moment ago.
+fn free_addr() -> SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+/// A plugin instance with a network tunneled from `listen` to `peers`.
+async fn host(
+    listen: SocketAddr,
+    peers: &[SocketAddr],
+    batch: u64,
+) -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    let peers: Vec<String> = peers.iter().map(|p| p.to_string()).collect();
+    let options = NetworkOptions::parse(&json!({
+        "vxcan.tunnel": listen.to_string(),
+        "vxcan.tunnel-peers": peers.join(","),
+        "vxcan.tunnel-batch": batch.to_string(),
+    }))
+    .unwrap();
+    mgr.network_add(NID.into(), options).await.unwrap();
+    (mgr, fake)
+}
+
+/// Join endpoint `ep` and open a socket on its container side.
+async fn join(mgr: &NetworkManager, fake: &FakeBackend, ep: &str) -> Arc<dyn FrameSocket> {
+    mgr.endpoint_create(NID.into(), ep.into()).await.unwrap();
+    let rsp = mgr
+        .endpoint_attach(NID.into(), ep.into(), String::new(), String::from("{}"))
+        .await
+        .unwrap();
+    fake.open(&rsp.SrcName).await.unwrap()
+}
+
+async fn recv(socket: &Arc<dyn FrameSocket>) -> Frame {
+    tokio::time::timeout(Duration::from_secs(5), socket.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .0
+}
+
+async fn status(mgr: &NetworkManager) -> TunnelStatus {
+    mgr.network_inspect(NID.into())
+        .await
+        .unwrap()
+        .tunnel
+        .unwrap()
+}
+
+#[test]
+fn wire_format() {
+    let frames = [frame("123#DEAD"), frame("18FF0010##1AABB"), frame("456#R2")];
+    let packet = tunnel::encode(7, &frames);
+    assert_eq!(
+        packet,
+        [
+            0x02, 0x00, 0x07, 0x00, 0x03, // version, data, sequence, count
+            0x00, 0x00, 0x01, 0x23, 0x02, 0xde, 0xad, // classic
+            0x98, 0xff, 0x00, 0x10, 0x82, 0x01, 0xaa, 0xbb, // CAN FD, BRS
+            0x40, 0x00, 0x04, 0x56, 0x02, // remote request, no data
+        ]
+    );
+    assert_eq!(tunnel::decode(&packet).unwrap(), (7, frames.to_vec()));
+
+    for bad in [
+        &packet[..4],
+        &packet[..packet.len() - 1],
+        &[0x01, 0x00, 0x07, 0x00, 0x00][..],
+        &[0x02, 0x01, 0x07, 0x00, 0x00][..],
+        // A classic frame of 9 bytes
+        &[
+            0x02, 0x00, 0x00, 0x00, 0x01, 0, 0, 1, 0x23, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ][..],
+    ] {
+        assert!(tunnel::decode(bad).is_err(), "{bad:?}");
+    }
+    let mut trailing = packet.clone();
+    trailing.push(0);
+    assert!(tunnel::decode(&trailing).is_err());
+}
+
+#[tokio::test]
+async fn two_hosts_on_loopback() {
+    let (addr1, addr2) = (free_addr(), free_addr());
+    let (mgr1, fake1) = host(addr1, &[addr2], 0).await;
+    let (mgr2, fake2) = host(addr2, &[addr1], 0).await;
+    let ecu1 = join(&mgr1, &fake1, "ecu1").await;
+    let ecu2 = join(&mgr2, &fake2, "ecu2").await;
+    let host2 = fake2.open("vcan0").await.unwrap();
+
+    ecu1.send(&frame("123#01")).await.unwrap();
+    assert_eq!(recv(&ecu2).await, frame("123#01"));
+    assert_eq!(recv(&host2).await, frame("123#01"));
+    ecu2.send(&frame("18FF0010##3AABB")).await.unwrap();
+    assert_eq!(recv(&ecu1).await, frame("18FF0010##3AABB"));
+
+    // Frames from a peer are not sent back to it
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (s1, s2) = (status(&mgr1).await, status(&mgr2).await);
+    assert_eq!((s1.frames_sent, s1.frames_received), (1, 1));
+    assert_eq!((s2.frames_sent, s2.frames_received), (1, 1));
+    assert_eq!(s1.local, addr1);
+    assert!(s1.running);
+
+    // The port is released with the network
+    mgr1.network_delete(NID.into()).await.unwrap();
+    let (mgr1, _) = host(addr1, &[addr2], 0).await;
+    assert!(status(&mgr1).await.running);
+}
+
+#[tokio::test]
+async fn batches_and_sequence_numbers() {
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let listen = free_addr();
+    let (mgr, fake) = host(listen, &[peer.local_addr().unwrap()], 50).await;
+    let host = fake.open("vcan0").await.unwrap();
+    let mut buf = [0; 2048];
+
+    // Frames within the batching time share a packet
+    for f in ["100#01", "101#02", "102#03"] {
+        host.send(&frame(f)).await.unwrap();
+    }
+    let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+    let (seq, frames) = tunnel::decode(&buf[..len]).unwrap();
+    assert_eq!(seq, 0);
+    assert_eq!(frames.len(), 3);
+    host.send(&frame("103#04")).await.unwrap();
+    let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+    assert_eq!(tunnel::decode(&buf[..len]).unwrap().0, 1);
+    let s = status(&mgr).await;
+    assert_eq!((s.packets_sent, s.frames_sent), (2, 4));
+
+    // Packets 1 and 2 go missing, packet 1 turns up late
+    for seq in [0, 3, 1] {
+        let packet = tunnel::encode(seq, &[frame(&format!("20{seq}#00"))]);
+        peer.send_to(&packet, listen).await.unwrap();
+    }
+    for seq in [0, 3, 1] {
+        assert_eq!(recv(&host).await, frame(&format!("20{seq}#00")));
+    }
+    // Neither garbage nor packets from strangers reach the network
+    peer.send_to(b"garbage", listen).await.unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let packet = tunnel::encode(4, &[frame("666#00")]);
+    stranger.send_to(&packet, listen).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), host.recv())
+            .await
+            .is_err()
+    );
+
+    let s = status(&mgr).await;
+    assert_eq!((s.packets_received, s.frames_received), (3, 3));
+    assert_eq!(s.packets_lost, 2);
+    assert_eq!(s.packets_reordered, 1);
+    assert_eq!(s.packets_invalid, 2);
+}
+
+/// A plugin instance reporting global scope, with a network tunneled from
+/// `listen` to the nodes discovered.
+async fn node(listen: SocketAddr) -> (NetworkManager, Arc<FakeBackend>, Server) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    let options = NetworkOptions::parse(&json!({ "vxcan.tunnel": listen.to_string() })).unwrap();
+    mgr.network_add(NID.into(), options).await.unwrap();
+    let plugin = Server::start(api::routes(mgr.clone(), api::Scope::Global));
+    (mgr, fake, plugin)
+}
+
+/// Tell `plugin` about a node, as Docker does.
+async fn discover(plugin: &Server, method: &str, address: &str, is_self: bool) -> (u16, Value) {
+    let body = json!({
+        "DiscoveryType": 1,
+        "DiscoveryData": { "Address": address, "BindAddress": address, "Self": is_self },
+    });
+    let path = format!("/NetworkDriver.{method}");
+    let (status, rsp) = request(&plugin.sock, "POST", &path, body.to_string()).await;
+    (status.as_u16(), serde_json::from_str(&rsp).unwrap())
+}
+
+async fn quiet(socket: &Arc<dyn FrameSocket>) -> bool {
+    tokio::time::timeout(Duration::from_millis(200), socket.recv())
+        .await
+        .is_err()
+}
+
+#[tokio::test]
+async fn tunnels_to_discovered_nodes() {
+    // Two nodes on loopback, on the same port
+    let port = free_addr().port();
+    let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+    let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+    let (mgr1, fake1, plugin1) = node(SocketAddr::new(ip1, port)).await;
+    let (mgr2, fake2, plugin2) = node(SocketAddr::new(ip2, port)).await;
+    let ecu1 = join(&mgr1, &fake1, "ecu1").await;
+    let ecu2 = join(&mgr2, &fake2, "ecu2").await;
+
+    let (code, rsp) = request(
+        &plugin1.sock,
+        "POST",
+        "/NetworkDriver.GetCapabilities",
+        String::new(),
+    )
+    .await;
+    assert_eq!(code, 200);
+    let rsp: Value = serde_json::from_str(&rsp).unwrap();
+    assert_eq!(rsp["ConnectivityScope"], "global");
+    assert_eq!(rsp["Scope"], "local");
+
+    ecu1.send(&frame("123#01")).await.unwrap();
+    assert!(quiet(&ecu2).await);
+
+    assert_eq!(
+        discover(&plugin1, "DiscoverNew", "127.0.0.1", true).await,
+        (200, json!({}))
+    );
+    assert_eq!(
+        discover(&plugin1, "DiscoverNew", "127.0.0.2", false).await,
+        (200, json!({}))
+    );
+    assert_eq!(
+        discover(&plugin2, "DiscoverNew", "127.0.0.1", false).await,
+        (200, json!({}))
+    );
+    assert_eq!(mgr1.nodes(), [ip2]);
+    assert_eq!(
+        status(&mgr1).await.config.peers,
+        [SocketAddr::new(ip2, port)]
+    );
+
+    ecu1.send(&frame("123#02")).await.unwrap();
+    assert_eq!(recv(&ecu2).await, frame("123#02"));
+    ecu2.send(&frame("124#03")).await.unwrap();
+    assert_eq!(recv(&ecu1).await, frame("124#03"));
+
+    // A network created later tunnels to the nodes known by then
+    let listen = SocketAddr::new(ip2, free_addr().port());
+    let options = NetworkOptions::parse(&json!({
+        "vxcan.id": "1",
+        "vxcan.tunnel": listen.to_string(),
+    }))
+    .unwrap();
+    mgr2.network_add("late".into(), options).await.unwrap();
+    let late = mgr2
+        .network_inspect("late".into())
+        .await
+        .unwrap()
+        .tunnel
+        .unwrap();
+    assert_eq!(late.config.peers, [SocketAddr::new(ip1, listen.port())]);
+
+    assert_eq!(
+        discover(&plugin1, "DiscoverDelete", "127.0.0.2", false).await,
+        (200, json!({}))
+    );
+    assert!(status(&mgr1).await.config.peers.is_empty());
+    ecu1.send(&frame("123#04")).await.unwrap();
+    assert!(quiet(&ecu2).await);
+
+    // Only node discovery matters
+    let (code, _) = discover(&plugin1, "DiscoverNew", "nonsense", false).await;
+    assert_eq!(code, 400);
+    let body = json!({ "DiscoveryType": 2, "DiscoveryData": { "Key": "secret" } });
+    let (code, rsp) = request(
+        &plugin1.sock,
+        "POST",
+        "/NetworkDriver.DiscoverNew",
+        body.to_string(),
+    )
+    .await;
+    assert_eq!((code.as_u16(), rsp.as_str()), (200, "{}"));
+    assert!(mgr1.nodes().is_empty());
+}
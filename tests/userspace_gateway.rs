@@ -0,0 +1,236 @@
+//! Tests for forwarding frames in the plugin instead of with can-gw.
+
+use rustycan4docker::backend::fake::FakeBackend;
+use rustycan4docker::backend::{FrameBackend, FrameSocket, GatewayMode, Topology};
+use rustycan4docker::frame::{Frame, ERR_FLAG};
+use rustycan4docker::{Backend, Error, NetworkManager, NetworkOptions};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+const NID: &str = "5c2b3f1b7e9a4d6c8f0e1a2b3c4d5e6f";
+const EP1: &str = "0a1b2c3d4e5f60718293a4b5c6d7e8f9";
+const EP2: &str = "f9e8d7c6b5a4039281706f5e4d3c2b1a";
+const EP3: &str = "7a7b7c7d7e7f70717273747576777879";
+const TAP: &str = "3c4d5e6f708192a3b4c5d6e7f8091a2b";
+
+fn manager() -> (NetworkManager, Arc<FakeBackend>) {
+    let fake = Arc::new(FakeBackend::new());
+    let mgr = NetworkManager::with_backend(Backend::new(fake.clone(), fake.clone(), fake.clone()));
+    (mgr, fake)
+}
+
+fn frame(id: u32) -> Frame {
+    Frame {
+        id,
+        data: vec![id as u8],
+        fd: false,
+        flags: 0,
+    }
+}
+
+/// Join endpoint `ep` and open a socket on its container side.
+async fn join(mgr: &NetworkManager, fake: &FakeBackend, ep: &str) -> Arc<dyn FrameSocket> {
+    mgr.endpoint_create(NID.into(), ep.into()).await.unwrap();
+    let rsp = mgr
+        .endpoint_attach(NID.into(), ep.into(), String::new(), String::new())
+        .await
+        .unwrap();
+    fake.open(&rsp.SrcName).await.unwrap()
+}
+
+/// Identifiers of the frames `socket` receives until it stays quiet.
+async fn received(socket: &Arc<dyn FrameSocket>) -> Vec<u32> {
+    let mut ids = Vec::new();
+    while let Ok(res) = tokio::time::timeout(Duration::from_millis(100), socket.recv()).await {
+        ids.push(res.unwrap().0.id);
+    }
+    ids
+}
+
+/// Every frame reaches every other member of the network exactly once,
+/// whichever gateway forwards it and however its rules connect them.
+async fn connected(gateway: GatewayMode, topology: Topology) -> NetworkManager {
+    let (mgr, fake) = manager();
+    let options = NetworkOptions {
+        gateway,
+        topology,
+        ..Default::default()
+    };
+    mgr.network_add(NID.into(), options).await.unwrap();
+    let host = fake.open("vcan0").await.unwrap();
+    let ep1 = join(&mgr, &fake, EP1).await;
+    let ep2 = join(&mgr, &fake, EP2).await;
+
+    ep1.send(&frame(0x101)).await.unwrap();
+    ep2.send(&frame(0x102)).await.unwrap();
+    host.send(&frame(0x100)).await.unwrap();
+    assert_eq!(received(&host).await, [0x101, 0x102]);
+    assert_eq!(received(&ep1).await, [0x102, 0x100]);
+    assert_eq!(received(&ep2).await, [0x101, 0x100]);
+
+    mgr.endpoint_detach(NID.into(), EP2.into()).await.unwrap();
+    ep1.send(&frame(0x101)).await.unwrap();
+    host.send(&frame(0x100)).await.unwrap();
+    assert_eq!(received(&host).await, [0x101]);
+    assert_eq!(received(&ep1).await, [0x100]);
+    assert!(received(&ep2).await.is_empty());
+    mgr
+}
+
+#[tokio::test]
+async fn same_forwarding_as_can_gw() {
+    let kernel = connected(GatewayMode::Kernel, Topology::Auto).await;
+    let info = kernel.network_inspect(NID.into()).await.unwrap();
+    assert_eq!(info.gateway, GatewayMode::Kernel);
+    assert_eq!(info.topology, Topology::Mesh);
+
+    let userspace = connected(GatewayMode::Userspace, Topology::Mesh).await;
+    let info = userspace.network_inspect(NID.into()).await.unwrap();
+    assert_eq!(info.gateway, GatewayMode::Userspace);
+    assert_eq!(info.rules.len(), 2);
+
+    // The plugin counts the frames of its routes like can-gw does
+    let stats = userspace.network_stats(NID.into()).await.unwrap();
+    assert_eq!(stats.frames.handled, 4);
+    let ep1 = &stats.endpoints[0];
+    assert_eq!((ep1.sent.handled, ep1.received.handled), (2, 2));
+    assert_eq!(userspace.kernel_verify().await, Ok(Vec::new()));
+
+    // Around a hub, the first endpoint's frames only take the route into it,
+    // and the second endpoint's frame reached it over the route out of it
+    let hub = connected(GatewayMode::Userspace, Topology::Auto).await;
+    let info = hub.network_inspect(NID.into()).await.unwrap();
+    assert_eq!(info.topology, Topology::Hub);
+    let stats = hub.network_stats(NID.into()).await.unwrap();
+    assert_eq!(stats.frames.handled, 5);
+    let ep1 = &stats.endpoints[0];
+    assert_eq!((ep1.sent.handled, ep1.received.handled), (2, 3));
+}
+
+#[tokio::test]
+async fn hub_without_echoes() {
+    let (mgr, fake) = manager();
+    let options = NetworkOptions {
+        topology: Topology::Hub,
+        ..Default::default()
+    };
+    mgr.network_add(NID.into(), options).await.unwrap();
+    let host = fake.open("vcan0").await.unwrap();
+    let mut eps = Vec::new();
+    for ep in [EP1, EP2, EP3] {
+        eps.push(join(&mgr, &fake, ep).await);
+    }
+
+    // A hub on a network that did not ask for a gateway forwards in
+    // userspace, with two rules per endpoint
+    let info = mgr.network_inspect(NID.into()).await.unwrap();
+    assert_eq!(info.gateway, GatewayMode::Userspace);
+    assert_eq!(info.topology, Topology::Hub);
+    assert_eq!(info.rules.len(), 6);
+    assert!(info
+        .rules
+        .iter()
+        .all(|(src, dst)| src == "vcan0" || dst == "vcan0"));
+    assert!(fake.rules().is_empty());
+
+    // Each frame reaches everyone but its sender, once
+    for (i, ep) in eps.iter().enumerate() {
+        ep.send(&frame(0x101 + i as u32)).await.unwrap();
+    }
+    host.send(&frame(0x100)).await.unwrap();
+    assert_eq!(received(&host).await, [0x101, 0x102, 0x103]);
+    assert_eq!(received(&eps[0]).await, [0x102, 0x103, 0x100]);
+    assert_eq!(received(&eps[1]).await, [0x101, 0x103, 0x100]);
+    assert_eq!(received(&eps[2]).await, [0x101, 0x102, 0x100]);
+
+    mgr.endpoint_detach(NID.into(), EP2.into()).await.unwrap();
+    eps[0].send(&frame(0x101)).await.unwrap();
+    assert_eq!(received(&host).await, [0x101]);
+    assert!(received(&eps[1]).await.is_empty());
+    assert_eq!(received(&eps[2]).await, [0x101]);
+    assert!(received(&eps[0]).await.is_empty());
+}
+
+#[tokio::test]
+async fn groups_around_a_hub() {
+    // Without can-gw, a network forwards in userspace and is a hub
+    let (mgr, fake) = manager();
+    fake.fail("can-gw");
+    mgr.network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    let host = fake.open("vcan0").await.unwrap();
+    let mut eps = Vec::new();
+    let members = [
+        (EP1, "ecu-a", false),
+        (EP2, "ecu-b", false),
+        (EP3, "ecu-a", false),
+        (TAP, "ecu-a", true),
+    ];
+    for (ep, group, tap) in members {
+        mgr.endpoint_create(NID.into(), ep.into()).await.unwrap();
+        let options = json!({ "vxcan.group": group, "vxcan.tap": tap }).to_string();
+        let rsp = mgr
+            .endpoint_attach(NID.into(), ep.into(), String::new(), options)
+            .await
+            .unwrap();
+        eps.push(fake.open(&rsp.SrcName).await.unwrap());
+    }
+
+    // The hub passes frames on within their group only, and to the tap
+    let tap = eps.pop().unwrap();
+    for (i, ep) in eps.iter().enumerate() {
+        ep.send(&frame(0x101 + i as u32)).await.unwrap();
+    }
+    host.send(&frame(0x100)).await.unwrap();
+    assert_eq!(received(&host).await, [0x101, 0x102, 0x103]);
+    assert_eq!(received(&eps[0]).await, [0x103, 0x100]);
+    assert_eq!(received(&eps[1]).await, [0x100]);
+    assert_eq!(received(&eps[2]).await, [0x101, 0x100]);
+    assert_eq!(received(&tap).await, [0x101, 0x102, 0x103, 0x100]);
+}
+
+#[tokio::test]
+async fn fallback_without_can_gw() {
+    let (mgr, fake) = manager();
+    fake.fail("can-gw");
+    assert_eq!(mgr.kernel_support().await, Ok(()));
+    assert_eq!(
+        mgr.gateway_resolve(GatewayMode::Auto).await,
+        Ok(GatewayMode::Userspace)
+    );
+
+    let options = NetworkOptions {
+        gateway: GatewayMode::Kernel,
+        ..Default::default()
+    };
+    assert!(matches!(
+        mgr.network_add(NID.into(), options).await,
+        Err(Error::Unsupported(_))
+    ));
+    assert!(fake.links().is_empty());
+
+    mgr.network_add(NID.into(), NetworkOptions::default())
+        .await
+        .unwrap();
+    let host = fake.open("vcan0").await.unwrap();
+    let ep1 = join(&mgr, &fake, EP1).await;
+    assert!(fake.rules().is_empty());
+
+    // Error frames stay where they are sent, as with can-gw
+    host.send(&frame(ERR_FLAG | 0x04)).await.unwrap();
+    host.send(&frame(0x100)).await.unwrap();
+    assert_eq!(received(&ep1).await, [0x100]);
+
+    mgr.endpoint_detach(NID.into(), EP1.into()).await.unwrap();
+    mgr.endpoint_delete(NID.into(), EP1.into()).await.unwrap();
+    mgr.network_delete(NID.into()).await.unwrap();
+    assert!(fake.links().is_empty());
+
+    fake.fail("can-raw");
+    assert!(matches!(
+        mgr.kernel_support().await,
+        Err(Error::Unsupported(_))
+    ));
+}